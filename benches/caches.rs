@@ -0,0 +1,130 @@
+use std::time::{Duration, Instant};
+
+use criterion::measurement::WallTime;
+use criterion::{criterion_group, criterion_main, BenchmarkGroup, Criterion, Throughput};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use risu::lru::ExpirationType;
+use risu::{
+    Cache, LruCache, PassthroughBuildHasher, ProbatoryCache, ShardCache, ShardedCache, SharedCache, SieveCache,
+    TinyLfuCache,
+};
+
+// Distinct keys looked up, and keys the caches hold
+const KEYS: usize = 100_000;
+const CAPACITY: usize = 1_000;
+const LOOKUPS: usize = 1_000_000;
+const THREADS: u64 = 4;
+
+// Keys following a Zipf distribution, as skewed as typical web traffic
+fn zipf_trace(exponent: f64) -> Vec<u64>
+{
+    let weights: Vec<f64> = (1..=KEYS).map(|rank| 1.0 / (rank as f64).powf(exponent)).collect();
+    let total: f64 = weights.iter().sum();
+    let mut cumulative = 0.0;
+    let cdf: Vec<f64> = weights
+        .iter()
+        .map(|weight| {
+            cumulative += weight / total;
+            cumulative
+        })
+        .collect();
+    let mut rng = StdRng::seed_from_u64(42);
+    (0..LOOKUPS)
+        .map(|_| {
+            let x: f64 = rng.gen();
+            cdf.partition_point(|&p| p < x).min(KEYS - 1) as u64
+        })
+        .collect()
+}
+
+// Looks every key up, and adds it on a miss, then returns the share of hits
+fn hit_ratio(cache: &mut impl ShardCache<u64, ()>, trace: &[u64]) -> f64
+{
+    let hits = trace
+        .iter()
+        .filter(|key| match cache.try_get(key) {
+            Some(_) => true,
+            None => {
+                cache.try_add(**key, ());
+                false
+            }
+        })
+        .count();
+    100.0 * hits as f64 / trace.len() as f64
+}
+
+fn benchmark(c: &mut Criterion)
+{
+    let trace = zipf_trace(1.0);
+    println!("Hit ratios of {} lookups over {} keys, holding {}:", LOOKUPS, KEYS, CAPACITY);
+    let mut lru = LruCache::new(CAPACITY, Duration::MAX, ExpirationType::Absolute);
+    println!("  LruCache: {:.2}%", hit_ratio(&mut lru, &trace));
+    let mut probatory = ProbatoryCache::new(CAPACITY, Duration::MAX, ExpirationType::Absolute);
+    println!("  ProbatoryCache: {:.2}%", hit_ratio(&mut probatory, &trace));
+    let mut tiny_lfu = TinyLfuCache::new(CAPACITY, 10 * CAPACITY, Duration::MAX, ExpirationType::Absolute);
+    println!("  TinyLfuCache: {:.2}%", hit_ratio(&mut tiny_lfu, &trace));
+    let mut sieve = SieveCache::new(CAPACITY, Duration::MAX);
+    println!("  SieveCache: {:.2}%", hit_ratio(&mut sieve, &trace));
+
+    let mut group = c.benchmark_group("Hit");
+    group.throughput(Throughput::Elements(1));
+
+    group.bench_function("LruCache", |b| {
+        let mut lru = LruCache::new(CAPACITY, Duration::MAX, ExpirationType::Absolute);
+        (0..CAPACITY as u64).for_each(|key| _ = lru.try_add(key, ()));
+        let mut key = 0;
+        b.iter(|| {
+            key = (key + 1) % CAPACITY as u64;
+            lru.try_get(&key)
+        });
+    });
+
+    group.bench_function("SieveCache", |b| {
+        let mut sieve = SieveCache::new(CAPACITY, Duration::MAX);
+        (0..CAPACITY as u64).for_each(|key| _ = sieve.try_add(key, ()));
+        let mut key = 0;
+        b.iter(|| {
+            key = (key + 1) % CAPACITY as u64;
+            sieve.get(&key)
+        });
+    });
+
+    group.finish();
+
+    // Hits on the same shards from several threads, where LRU lookups wait for each other
+    let mut group = c.benchmark_group(format!("Hit from {} threads", THREADS));
+    group.throughput(Throughput::Elements(THREADS));
+
+    hit_from_threads(&mut group, "LruCache", || LruCache::new(CAPACITY, Duration::MAX, ExpirationType::Absolute));
+    hit_from_threads(&mut group, "SieveCache", || SieveCache::new(CAPACITY, Duration::MAX));
+
+    group.finish();
+}
+
+// Looks keys up from several threads at once, in a cache of 4 shards made by the factory
+fn hit_from_threads<C>(group: &mut BenchmarkGroup<WallTime>, name: &str, factory: impl Fn() -> C)
+where
+    C: ShardCache<u64, ()> + Send + Sync,
+{
+    group.bench_function(name, |b| {
+        let cache = ShardedCache::with_hasher(4, PassthroughBuildHasher, &factory);
+        (0..CAPACITY as u64).for_each(|key| _ = cache.try_add_arc(key, ().into()));
+        b.iter_custom(|iterations| {
+            let start = Instant::now();
+            std::thread::scope(|scope| {
+                for _ in 0..THREADS {
+                    scope.spawn(|| {
+                        for i in 0..iterations {
+                            cache.try_get(&(i % CAPACITY as u64));
+                        }
+                    });
+                }
+            });
+            start.elapsed()
+        });
+    });
+}
+
+criterion_group!(benches, benchmark);
+criterion_main!(benches);
@@ -0,0 +1,162 @@
+use hyper::header::CONTENT_TYPE;
+use hyper::{HeaderMap, Response};
+
+use crate::buffered_body::BufferedBody;
+use crate::config::RisuConfiguration;
+
+/// Reason for a response not to be admitted in the cache.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Rejection
+{
+    /// The HTTP status code isn't in `cache_status_codes`.
+    Status,
+    /// The gRPC status isn't in `cache_grpc_status_codes`, or the gRPC response has no status at all.
+    GrpcStatus,
+    /// Upstream caching headers forbid caching the response.
+    CacheControl,
+    /// The response varies on request headers the key didn't account for yet, or on anything (`Vary: *`).
+    Vary,
+}
+
+impl Rejection
+{
+    pub fn as_str(&self) -> &'static str
+    {
+        match self {
+            Rejection::Status => "status",
+            Rejection::GrpcStatus => "grpc_status",
+            Rejection::CacheControl => "cache_control",
+            Rejection::Vary => "vary",
+        }
+    }
+}
+
+/// Checks whether a response received from upstream qualifies for caching, based on its status codes.
+/// Rejected responses are still returned to the client.
+pub fn admit(configuration: &RisuConfiguration, response: &Response<BufferedBody>) -> Result<(), Rejection>
+{
+    if !configuration.cache_status_codes.contains(&response.status().as_u16()) {
+        return Err(Rejection::Status);
+    }
+
+    if is_grpc(response.headers()) {
+        match response_grpc_status(response) {
+            Some(code) if configuration.cache_grpc_status_codes.contains(&code) => {}
+            _ => return Err(Rejection::GrpcStatus),
+        }
+    }
+
+    Ok(())
+}
+
+/// Tells whether upstream failed to provide a proper response, with a 5xx status or a gRPC status other than OK,
+/// in which case a stale response may be served instead. Returns the kind of failure.
+pub fn upstream_failure(response: &Response<BufferedBody>) -> Option<&'static str>
+{
+    if response.status().is_server_error() {
+        return Some("upstream_status");
+    }
+    if is_grpc(response.headers()) && response_grpc_status(response) != Some(0) {
+        return Some("upstream_grpc_status");
+    }
+    None
+}
+
+pub fn is_grpc(headers: &HeaderMap) -> bool
+{
+    headers
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with("application/grpc"))
+}
+
+// Status is in trailers, unless upstream sent a trailers-only response, in which case it's in the headers
+fn response_grpc_status(response: &Response<BufferedBody>) -> Option<u16>
+{
+    response
+        .body()
+        .trailers()
+        .and_then(grpc_status)
+        .or_else(|| grpc_status(response.headers()))
+}
+
+fn grpc_status(headers: &HeaderMap) -> Option<u16>
+{
+    headers.get("grpc-status")?.to_str().ok()?.trim().parse().ok()
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    fn configuration() -> RisuConfiguration
+    {
+        serde_yaml::from_str("cache_status_codes: [200]\ncache_grpc_status_codes: [0, 5]").unwrap()
+    }
+
+    fn grpc_response(headers: &[(&'static str, &'static str)], trailers: &[(&'static str, &'static str)])
+        -> Response<BufferedBody>
+    {
+        let mut body = BufferedBody::from_bytes(b"payload");
+        if !trailers.is_empty() {
+            let mut map = HeaderMap::new();
+            for (name, value) in trailers {
+                map.insert(*name, value.parse().unwrap());
+            }
+            body.push_frame(hyper::body::Frame::<bytes::Bytes>::trailers(map));
+        }
+        let mut response = Response::new(body);
+        response
+            .headers_mut()
+            .insert(CONTENT_TYPE, "application/grpc".parse().unwrap());
+        for (name, value) in headers {
+            response.headers_mut().insert(*name, value.parse().unwrap());
+        }
+        response
+    }
+
+    #[test]
+    fn http_status()
+    {
+        let configuration = configuration();
+        let mut response = Response::new(BufferedBody::from_bytes(b"hello"));
+        assert_eq!(admit(&configuration, &response), Ok(()));
+        *response.status_mut() = hyper::StatusCode::INTERNAL_SERVER_ERROR;
+        assert_eq!(admit(&configuration, &response), Err(Rejection::Status));
+    }
+
+    #[test]
+    fn grpc()
+    {
+        let configuration = configuration();
+        assert_eq!(admit(&configuration, &grpc_response(&[], &[("grpc-status", "0")])), Ok(()));
+        assert_eq!(admit(&configuration, &grpc_response(&[], &[("grpc-status", "5")])), Ok(()));
+        assert_eq!(
+            admit(&configuration, &grpc_response(&[], &[("grpc-status", "14")])),
+            Err(Rejection::GrpcStatus)
+        );
+        // Trailers-only response
+        assert_eq!(
+            admit(&configuration, &grpc_response(&[("grpc-status", "14")], &[])),
+            Err(Rejection::GrpcStatus)
+        );
+        // No status at all
+        assert_eq!(admit(&configuration, &grpc_response(&[], &[])), Err(Rejection::GrpcStatus));
+    }
+
+    #[test]
+    fn failures()
+    {
+        let mut response = Response::new(BufferedBody::from_bytes(b"hello"));
+        assert_eq!(upstream_failure(&response), None);
+        *response.status_mut() = hyper::StatusCode::NOT_FOUND;
+        assert_eq!(upstream_failure(&response), None);
+        *response.status_mut() = hyper::StatusCode::BAD_GATEWAY;
+        assert_eq!(upstream_failure(&response), Some("upstream_status"));
+
+        assert_eq!(upstream_failure(&grpc_response(&[], &[("grpc-status", "0")])), None);
+        assert_eq!(upstream_failure(&grpc_response(&[("grpc-status", "14")], &[])), Some("upstream_grpc_status"));
+        assert_eq!(upstream_failure(&grpc_response(&[], &[])), Some("upstream_grpc_status"));
+    }
+}
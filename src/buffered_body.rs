@@ -6,9 +6,11 @@ use std::task::{Context, Poll};
 use bytes::{Buf, BytesMut};
 use futures::Future;
 use hyper::body::{Body, Frame};
-use hyper::HeaderMap;
+use hyper::{HeaderMap, Response};
 use pin_project_lite::pin_project;
 
+use crate::Weigh;
+
 pin_project! {
     /// Future that resolves into a [`Collected`].
     ///
@@ -64,6 +66,29 @@ impl BufferedBody
         self.trailers.as_ref()
     }
 
+    /// Returns the data buffered, trailers excluded.
+    pub fn bytes(&self) -> &[u8]
+    {
+        &self.bufs
+    }
+
+    pub fn with_trailers(mut self, trailers: Option<HeaderMap>) -> BufferedBody
+    {
+        self.trailers = trailers;
+        self
+    }
+
+    /// Returns the number of bytes of data buffered, trailers excluded.
+    pub fn len(&self) -> usize
+    {
+        self.bufs.len()
+    }
+
+    pub fn is_empty(&self) -> bool
+    {
+        self.bufs.is_empty()
+    }
+
     pub(crate) fn push_frame<B>(&mut self, frame: Frame<B>)
     where
         B: Buf,
@@ -142,3 +167,17 @@ impl Hash for BufferedBody
         //self.trailers.hash(state);
     }
 }
+
+fn headers_size(headers: &HeaderMap) -> usize
+{
+    headers.iter().map(|(name, value)| name.as_str().len() + value.len()).sum()
+}
+
+impl Weigh for Response<BufferedBody>
+{
+    fn weigh(&self) -> usize
+    {
+        let body = self.body();
+        body.len() + headers_size(self.headers()) + body.trailers().map_or(0, headers_size)
+    }
+}
@@ -0,0 +1,232 @@
+use std::time::{Duration, SystemTime};
+
+use hyper::header::{AGE, CACHE_CONTROL, DATE, EXPIRES};
+use hyper::HeaderMap;
+
+use crate::config::TtlMode;
+
+/// Directives of the `Cache-Control` response header that matter to a shared cache.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct CacheControl
+{
+    pub max_age: Option<u64>,
+    pub s_maxage: Option<u64>,
+    pub no_store: bool,
+    pub no_cache: bool,
+    pub private: bool,
+    /// https://www.rfc-editor.org/rfc/rfc5861
+    pub stale_while_revalidate: Option<u64>,
+    pub stale_if_error: Option<u64>,
+}
+
+impl CacheControl
+{
+    pub fn from_headers(headers: &HeaderMap) -> Self
+    {
+        let mut cache_control = CacheControl::default();
+        // Directives may be split across several headers
+        for value in headers.get_all(CACHE_CONTROL) {
+            let Ok(value) = value.to_str() else {
+                continue;
+            };
+            for directive in value.split(',') {
+                let (name, argument) = match directive.split_once('=') {
+                    Some((name, argument)) => (name.trim(), Some(argument.trim().trim_matches('"'))),
+                    None => (directive.trim(), None),
+                };
+                let seconds = argument.and_then(|a| a.parse::<u64>().ok());
+                match name.to_ascii_lowercase().as_str() {
+                    "max-age" => cache_control.max_age = seconds,
+                    "s-maxage" => cache_control.s_maxage = seconds,
+                    "no-store" => cache_control.no_store = true,
+                    "no-cache" => cache_control.no_cache = true,
+                    "private" => cache_control.private = true,
+                    "stale-while-revalidate" => cache_control.stale_while_revalidate = seconds,
+                    "stale-if-error" => cache_control.stale_if_error = seconds,
+                    _ => {}
+                }
+            }
+        }
+        cache_control
+    }
+
+    /// Returns how long a response can be served stale while it's refreshed, given the configured mode and default.
+    pub fn stale_while_revalidate(&self, mode: TtlMode, default: Duration) -> Duration
+    {
+        stale_window(self.stale_while_revalidate, mode, default)
+    }
+
+    /// Returns how long a response can be served stale when it can't be refreshed, given the configured mode and
+    /// default.
+    pub fn stale_if_error(&self, mode: TtlMode, default: Duration) -> Duration
+    {
+        stale_window(self.stale_if_error, mode, default)
+    }
+}
+
+fn stale_window(seconds: Option<u64>, mode: TtlMode, default: Duration) -> Duration
+{
+    match (mode, seconds) {
+        (TtlMode::Override, _) | (_, None) => default,
+        (TtlMode::Respect, Some(seconds)) => Duration::from_secs(seconds),
+        (TtlMode::Cap, Some(seconds)) => default.min(Duration::from_secs(seconds)),
+    }
+}
+
+/// What the upstream response headers say about how long a response can be cached.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Freshness
+{
+    /// Upstream didn't say anything about caching.
+    Unspecified,
+    /// The response can be cached for the given duration.
+    Fresh(Duration),
+    /// The response must not be cached (`no-store`, `no-cache`, `private`, or already stale).
+    Uncacheable,
+}
+
+impl Freshness
+{
+    pub fn from_headers(headers: &HeaderMap, now: SystemTime) -> Self
+    {
+        let cache_control = CacheControl::from_headers(headers);
+        if cache_control.no_store || cache_control.no_cache || cache_control.private {
+            return Freshness::Uncacheable;
+        }
+
+        // s-maxage overrides max-age for shared caches, and both override Expires
+        let lifetime = match cache_control.s_maxage.or(cache_control.max_age) {
+            Some(seconds) => Duration::from_secs(seconds),
+            None => match headers.get(EXPIRES) {
+                Some(expires) => {
+                    // An invalid Expires header means the response is already expired
+                    let Some(expires) = parse_date(expires) else {
+                        return Freshness::Uncacheable;
+                    };
+                    let date = headers.get(DATE).and_then(parse_date).unwrap_or(now);
+                    expires.duration_since(date).unwrap_or(Duration::ZERO)
+                }
+                None => return Freshness::Unspecified,
+            },
+        };
+
+        match lifetime.checked_sub(Duration::from_secs(age(headers))) {
+            Some(ttl) if !ttl.is_zero() => Freshness::Fresh(ttl),
+            _ => Freshness::Uncacheable,
+        }
+    }
+
+    /// Returns how long a response can be cached given the configured mode and default TTL,
+    /// or `None` if it must not be cached.
+    pub fn ttl(&self, mode: TtlMode, default: Duration) -> Option<Duration>
+    {
+        match (mode, self) {
+            (_, Freshness::Uncacheable) => None,
+            (TtlMode::Override, _) => Some(default),
+            (_, Freshness::Unspecified) => Some(default),
+            (TtlMode::Respect, Freshness::Fresh(ttl)) => Some(*ttl),
+            (TtlMode::Cap, Freshness::Fresh(ttl)) => Some(default.min(*ttl)),
+        }
+    }
+}
+
+/// Returns the value of the `Age` header, in seconds, or 0 if absent or invalid.
+pub fn age(headers: &HeaderMap) -> u64
+{
+    headers
+        .get(AGE)
+        .and_then(|age| age.to_str().ok())
+        .and_then(|age| age.trim().parse().ok())
+        .unwrap_or(0)
+}
+
+fn parse_date(value: &hyper::header::HeaderValue) -> Option<SystemTime>
+{
+    httpdate::parse_http_date(value.to_str().ok()?).ok()
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap
+    {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(*name, value.parse().unwrap());
+        }
+        headers
+    }
+
+    #[test]
+    fn cache_control_parsing()
+    {
+        let cache_control = CacheControl::from_headers(&headers(&[
+            ("cache-control", "public, Max-Age=60"),
+            ("cache-control", "s-maxage=\"120\", no-cache, stale-while-revalidate=30, stale-if-error=5"),
+        ]));
+        assert_eq!(
+            cache_control,
+            CacheControl {
+                max_age: Some(60),
+                s_maxage: Some(120),
+                no_store: false,
+                no_cache: true,
+                private: false,
+                stale_while_revalidate: Some(30),
+                stale_if_error: Some(5),
+            }
+        );
+
+        let default = Duration::from_secs(10);
+        assert_eq!(cache_control.stale_while_revalidate(TtlMode::Respect, default), Duration::from_secs(30));
+        assert_eq!(cache_control.stale_while_revalidate(TtlMode::Cap, default), default);
+        assert_eq!(CacheControl::default().stale_while_revalidate(TtlMode::Respect, default), default);
+        assert_eq!(cache_control.stale_if_error(TtlMode::Cap, default), Duration::from_secs(5));
+        assert_eq!(cache_control.stale_if_error(TtlMode::Override, default), default);
+    }
+
+    #[test]
+    fn freshness()
+    {
+        let now = SystemTime::now();
+        let freshness = |pairs| Freshness::from_headers(&headers(pairs), now);
+
+        assert_eq!(freshness(&[]), Freshness::Unspecified);
+        assert_eq!(freshness(&[("cache-control", "max-age=60")]), Freshness::Fresh(Duration::from_secs(60)));
+        assert_eq!(
+            freshness(&[("cache-control", "max-age=60, s-maxage=30")]),
+            Freshness::Fresh(Duration::from_secs(30))
+        );
+        assert_eq!(
+            freshness(&[("cache-control", "max-age=60"), ("age", "20")]),
+            Freshness::Fresh(Duration::from_secs(40))
+        );
+        assert_eq!(freshness(&[("cache-control", "max-age=0")]), Freshness::Uncacheable);
+        assert_eq!(freshness(&[("cache-control", "max-age=60, private")]), Freshness::Uncacheable);
+        assert_eq!(freshness(&[("cache-control", "no-store")]), Freshness::Uncacheable);
+        assert_eq!(
+            freshness(&[
+                ("date", "Sun, 06 Nov 1994 08:49:37 GMT"),
+                ("expires", "Sun, 06 Nov 1994 08:59:37 GMT")
+            ]),
+            Freshness::Fresh(Duration::from_secs(600))
+        );
+        assert_eq!(freshness(&[("expires", "0")]), Freshness::Uncacheable);
+    }
+
+    #[test]
+    fn ttl()
+    {
+        let default = Duration::from_secs(100);
+        let fresh = Freshness::Fresh(Duration::from_secs(500));
+
+        assert_eq!(fresh.ttl(TtlMode::Respect, default), Some(Duration::from_secs(500)));
+        assert_eq!(fresh.ttl(TtlMode::Cap, default), Some(default));
+        assert_eq!(fresh.ttl(TtlMode::Override, default), Some(default));
+        assert_eq!(Freshness::Unspecified.ttl(TtlMode::Respect, default), Some(default));
+        assert_eq!(Freshness::Uncacheable.ttl(TtlMode::Respect, default), None);
+        assert_eq!(Freshness::Uncacheable.ttl(TtlMode::Override, default), None);
+    }
+}
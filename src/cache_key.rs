@@ -0,0 +1,170 @@
+use std::hash::Hash;
+
+use gxhash::GxHasher;
+use hyper::Request;
+
+use crate::buffered_body::BufferedBody;
+use crate::config::QueryKeyConfiguration;
+use crate::routing::Target;
+
+/// Hashes the parts of the request selected by the cache key configuration of its target.
+pub fn hash(target: &Target, request: &Request<BufferedBody>) -> u128
+{
+    let configuration = &target.cache_key;
+    let mut hasher = GxHasher::with_seed(123);
+
+    if configuration.method {
+        request.method().as_str().hash(&mut hasher);
+    }
+
+    // Different upstreams serving the same path mean different keys
+    if configuration.upstream {
+        target.uri.scheme_str().hash(&mut hasher);
+        target.uri.authority().map(|authority| authority.as_str()).hash(&mut hasher);
+    }
+
+    if configuration.path {
+        target.uri.path().hash(&mut hasher);
+    }
+
+    hash_query(&configuration.query, target.uri.query(), &mut hasher);
+
+    for name in &configuration.headers {
+        let values: Vec<&[u8]> = request.headers().get_all(name.as_str()).iter().map(|v| v.as_bytes()).collect();
+        values.hash(&mut hasher);
+    }
+
+    // Sometimes, we can't rely on the request body.
+    // For example, protobuf maps are serialized in a non-deterministic order.
+    // https://gist.github.com/kchristidis/39c8b310fd9da43d515c4394c3cd9510
+    // In this case, the caller may define a header to not use the body for the key.
+    let override_value = configuration
+        .override_header
+        .as_ref()
+        .and_then(|name| request.headers().get(name.as_str()));
+    match override_value {
+        Some(value) => value.as_bytes().hash(&mut hasher),
+        None if configuration.body => request.body().hash(&mut hasher),
+        None => {}
+    }
+
+    hasher.finish_u128()
+}
+
+fn hash_query(configuration: &QueryKeyConfiguration, query: Option<&str>, hasher: &mut GxHasher)
+{
+    if !configuration.enabled {
+        return;
+    }
+
+    let query = query.unwrap_or_default();
+    if !configuration.sort && configuration.include.is_none() && configuration.exclude.is_empty() {
+        query.hash(hasher);
+        return;
+    }
+
+    let mut parameters: Vec<&str> = query
+        .split('&')
+        .filter(|parameter| {
+            let name = parameter.split('=').next().unwrap_or_default();
+            !parameter.is_empty()
+                && configuration.include.as_ref().is_none_or(|include| include.iter().any(|n| n == name))
+                && !configuration.exclude.iter().any(|n| n == name)
+        })
+        .collect();
+    if configuration.sort {
+        parameters.sort_unstable();
+    }
+    parameters.hash(hasher);
+}
+
+#[cfg(test)]
+mod tests
+{
+    use std::sync::Arc;
+
+    use hyper::Method;
+
+    use super::*;
+    use crate::config::CacheKeyConfiguration;
+
+    fn key(conf: &str, method: Method, uri: &str, headers: &[(&'static str, &'static str)], body: &[u8]) -> u128
+    {
+        let mut request = Request::builder().method(method).uri(uri);
+        for (name, value) in headers {
+            request = request.header(*name, *value);
+        }
+        let target = Target {
+            uri: uri.parse().unwrap(),
+            http2: false,
+            upstream: None,
+            cache_key: Arc::new(serde_yaml::from_str::<CacheKeyConfiguration>(conf).unwrap()),
+        };
+        hash(&target, &request.body(BufferedBody::from_bytes(body)).unwrap())
+    }
+
+    fn get(conf: &str, uri: &str) -> u128
+    {
+        key(conf, Method::GET, uri, &[], b"")
+    }
+
+    #[test]
+    fn defaults()
+    {
+        let base = get("{}", "http://a.com/path?x=1");
+        assert_eq!(base, get("{}", "http://a.com/path?x=1"));
+        assert_ne!(base, get("{}", "http://b.com/path?x=1"));
+        assert_ne!(base, get("{}", "https://a.com/path?x=1"));
+        assert_ne!(base, get("{}", "http://a.com/other?x=1"));
+        assert_ne!(base, get("{}", "http://a.com/path?x=2"));
+        assert_ne!(base, key("{}", Method::POST, "http://a.com/path?x=1", &[], b""));
+        assert_ne!(base, key("{}", Method::GET, "http://a.com/path?x=1", &[], b"body"));
+        // Headers are ignored, including x-request-id
+        assert_eq!(base, key("{}", Method::GET, "http://a.com/path?x=1", &[("x-request-id", "42")], b""));
+
+        let conf = "{ method: false, upstream: false, body: false }";
+        let base = key(conf, Method::GET, "http://a.com/path", &[], b"");
+        assert_eq!(base, key(conf, Method::POST, "http://b.com/path", &[], b"body"));
+    }
+
+    #[test]
+    fn query()
+    {
+        let conf = "query: { sort: true }";
+        assert_eq!(get(conf, "http://a.com/?x=1&y=2"), get(conf, "http://a.com/?y=2&x=1"));
+        assert_ne!(get("{}", "http://a.com/?x=1&y=2"), get("{}", "http://a.com/?y=2&x=1"));
+
+        let conf = "query: { include: [x, y] }";
+        assert_eq!(get(conf, "http://a.com/?x=1&utm=a&y=2"), get(conf, "http://a.com/?x=1&y=2&utm=b"));
+        assert_ne!(get(conf, "http://a.com/?x=1"), get(conf, "http://a.com/?x=2"));
+
+        let conf = "query: { exclude: [_] }";
+        assert_eq!(get(conf, "http://a.com/?x=1&_=123"), get(conf, "http://a.com/?x=1&_=456"));
+        assert_ne!(get(conf, "http://a.com/?x=1"), get(conf, "http://a.com/?x=2"));
+
+        let conf = "query: { enabled: false }";
+        assert_eq!(get(conf, "http://a.com/?x=1"), get(conf, "http://a.com/"));
+    }
+
+    #[test]
+    fn headers()
+    {
+        let conf = "headers: [Accept-Language]";
+        let uri = "http://a.com/";
+        let fr = key(conf, Method::GET, uri, &[("accept-language", "fr")], b"");
+        assert_eq!(fr, key(conf, Method::GET, uri, &[("accept-language", "fr"), ("x-other", "1")], b""));
+        assert_ne!(fr, key(conf, Method::GET, uri, &[("accept-language", "en")], b""));
+        assert_ne!(fr, key(conf, Method::GET, uri, &[], b""));
+    }
+
+    #[test]
+    fn override_header()
+    {
+        let conf = "override_header: x-cache-key";
+        let uri = "http://a.com/";
+        let base = key(conf, Method::POST, uri, &[("x-cache-key", "42")], b"body");
+        assert_eq!(base, key(conf, Method::POST, uri, &[("x-cache-key", "42")], b"other body"));
+        assert_ne!(base, key(conf, Method::POST, uri, &[("x-cache-key", "43")], b"body"));
+        assert_ne!(key(conf, Method::POST, uri, &[], b"body"), key(conf, Method::POST, uri, &[], b"other body"));
+    }
+}
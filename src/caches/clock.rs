@@ -0,0 +1,142 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Tells the caches what time it is, to expire their entries.
+pub trait Clock: Send + Sync
+{
+    fn now(&self) -> Instant;
+}
+
+/// Reads the monotonic clock of the system every time.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MonotonicClock;
+
+impl Clock for MonotonicClock
+{
+    fn now(&self) -> Instant
+    {
+        Instant::now()
+    }
+}
+
+/// Reads a time updated by a background thread at the given resolution, which is cheaper than asking the system but
+/// lags behind by up to the resolution. The thread stops once the clock is dropped.
+pub struct CoarseClock
+{
+    time: Arc<ElapsedTime>,
+}
+
+/// Only moves when advanced, so that expiration can be tested or simulated without waiting.
+pub struct ManualClock
+{
+    time: ElapsedTime,
+}
+
+// Time as nanoseconds elapsed since a start instant, so that it can be read and written atomically
+struct ElapsedTime
+{
+    start: Instant,
+    nanos: AtomicU64,
+}
+
+impl ElapsedTime
+{
+    fn new() -> Self
+    {
+        Self {
+            start: Instant::now(),
+            nanos: AtomicU64::new(0),
+        }
+    }
+
+    fn now(&self) -> Instant
+    {
+        self.start + Duration::from_nanos(self.nanos.load(Ordering::Relaxed))
+    }
+}
+
+impl CoarseClock
+{
+    pub fn new(resolution: Duration) -> Self
+    {
+        let time = Arc::new(ElapsedTime::new());
+        let ticked = Arc::downgrade(&time);
+        std::thread::Builder::new()
+            .name("risu-clock".to_string())
+            .spawn(move || loop {
+                std::thread::sleep(resolution);
+                let Some(time) = ticked.upgrade() else {
+                    break;
+                };
+                time.nanos.store(time.start.elapsed().as_nanos() as u64, Ordering::Relaxed);
+            })
+            .expect("Failed to spawn the clock thread");
+        Self { time }
+    }
+}
+
+impl Clock for CoarseClock
+{
+    fn now(&self) -> Instant
+    {
+        self.time.now()
+    }
+}
+
+impl ManualClock
+{
+    pub fn new() -> Self
+    {
+        Self { time: ElapsedTime::new() }
+    }
+
+    /// Moves the clock forward.
+    pub fn advance(&self, duration: Duration)
+    {
+        self.time.nanos.fetch_add(duration.as_nanos() as u64, Ordering::Relaxed);
+    }
+}
+
+impl Default for ManualClock
+{
+    fn default() -> Self
+    {
+        Self::new()
+    }
+}
+
+impl Clock for ManualClock
+{
+    fn now(&self) -> Instant
+    {
+        self.time.now()
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn manual()
+    {
+        let clock = ManualClock::new();
+        let start = clock.now();
+        assert_eq!(clock.now(), start, "Clock should only move when advanced");
+        clock.advance(Duration::from_secs(3));
+        assert_eq!(clock.now() - start, Duration::from_secs(3));
+    }
+
+    #[test]
+    fn coarse()
+    {
+        let clock = CoarseClock::new(Duration::from_millis(5));
+        let start = clock.now();
+        std::thread::sleep(Duration::from_millis(50));
+        let elapsed = clock.now() - start;
+        assert!(elapsed >= Duration::from_millis(20), "Clock should tick in the background, got {:?}", elapsed);
+        assert!(clock.now() <= Instant::now());
+    }
+}
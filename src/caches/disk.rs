@@ -0,0 +1,451 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::hash::Hasher;
+use std::io::{Error, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use gxhash::GxHasher;
+
+use crate::{Clock, Expiry, MonotonicClock};
+
+const SEGMENT_EXTENSION: &str = "segment";
+// The size budget is split into this many segments, the oldest being dropped as a whole when over budget
+const SEGMENTS: u64 = 8;
+// Each value is written after its length and before its checksum
+const FRAMING_SIZE: u64 = 16;
+
+/// Cache of serialized values on disk, meant to hold the entries evicted from memory. Values are appended to segment
+/// files and indexed in memory. Since the index isn't persisted, segments left by a previous cache are deleted.
+/// Blocking, so it's better used off the runtime threads.
+pub struct DiskCache
+{
+    directory: PathBuf,
+    max_bytes: u64,
+    segment_bytes: u64,
+    clock: Arc<dyn Clock>,
+    state: Mutex<DiskCacheState>,
+}
+
+struct DiskCacheState
+{
+    index: HashMap<u128, Location>,
+    // Segments by id, values being appended to the last one
+    segments: BTreeMap<u64, Segment>,
+    active: File,
+    bytes: u64,
+}
+
+#[derive(Default)]
+struct Segment
+{
+    bytes: u64,
+    live_bytes: u64,
+    // Keys written to the segment, which may since have been written again elsewhere, or removed
+    keys: Vec<u128>,
+}
+
+#[derive(Clone)]
+struct Location
+{
+    segment: u64,
+    offset: u64,
+    length: u64,
+    stored_at: Instant,
+    expiry: Expiry,
+    tags: Vec<String>,
+}
+
+/// A value found on disk.
+pub struct DiskHit
+{
+    pub value: Vec<u8>,
+    /// What's left of the expiry the value was written with.
+    pub expiry: Expiry,
+    /// How long the value has been on disk.
+    pub age: Duration,
+}
+
+impl DiskCache
+{
+    pub fn new(directory: impl Into<PathBuf>, max_bytes: u64) -> Result<Self, Error>
+    {
+        let directory = directory.into();
+        std::fs::create_dir_all(&directory)?;
+        for entry in std::fs::read_dir(&directory)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|extension| extension == SEGMENT_EXTENSION) {
+                std::fs::remove_file(path)?;
+            }
+        }
+
+        let active = File::create(segment_path(&directory, 0))?;
+        Ok(Self {
+            directory,
+            max_bytes,
+            segment_bytes: (max_bytes / SEGMENTS).max(1),
+            clock: Arc::new(MonotonicClock),
+            state: Mutex::new(DiskCacheState {
+                index: HashMap::new(),
+                segments: BTreeMap::from([(0, Segment::default())]),
+                active,
+                bytes: 0,
+            }),
+        })
+    }
+
+    /// Tells the age of the entries with the given clock, instead of the monotonic clock of the system.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self
+    {
+        self.clock = clock;
+        self
+    }
+
+    /// Writes the value, replacing the previous one of the key. Returns false if the value doesn't fit in a segment.
+    pub fn put(&self, key: u128, value: &[u8], expiry: Expiry, tags: Vec<String>) -> Result<bool, Error>
+    {
+        if value.len() as u64 + FRAMING_SIZE > self.segment_bytes {
+            return Ok(false);
+        }
+        let mut state = self.state.lock().unwrap();
+        self.append(&mut state, key, value, self.clock.now(), expiry, tags)?;
+        Ok(true)
+    }
+
+    /// Returns the value of the key, unless it expired.
+    pub fn get(&self, key: u128) -> Result<Option<DiskHit>, Error>
+    {
+        let (location, expiry, age) = {
+            let mut state = self.state.lock().unwrap();
+            let Some(location) = state.index.get(&key) else {
+                return Ok(None);
+            };
+            let age = self.clock.now() - location.stored_at;
+            match location.expiry.remaining(age) {
+                Some(expiry) => (location.clone(), expiry, age),
+                None => {
+                    state.remove(key);
+                    return Ok(None);
+                }
+            }
+        };
+
+        // The segment may have been dropped or compacted meanwhile
+        match read(&segment_path(&self.directory, location.segment), location.offset, location.length) {
+            Ok(value) => Ok(Some(DiskHit { value, expiry, age })),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Removes the value of the key, if any, and tells whether there was one. Its space is reclaimed on compaction.
+    pub fn remove(&self, key: u128) -> bool
+    {
+        self.state.lock().unwrap().remove(key)
+    }
+
+    /// Removes the values carrying the tag, and returns how many there were.
+    pub fn remove_tagged(&self, tag: &str) -> usize
+    {
+        let mut state = self.state.lock().unwrap();
+        let keys: Vec<u128> = state
+            .index
+            .iter()
+            .filter(|(_, location)| location.tags.iter().any(|other| other == tag))
+            .map(|(key, _)| *key)
+            .collect();
+        keys.into_iter().filter(|key| state.remove(*key)).count()
+    }
+
+    /// Removes all the values, deleting their segments.
+    pub fn clear(&self) -> Result<(), Error>
+    {
+        let mut state = self.state.lock().unwrap();
+        let ids: Vec<u64> = state.segments.keys().copied().collect();
+        let next = ids.last().map_or(0, |id| id + 1);
+        state.active = File::create(segment_path(&self.directory, next))?;
+        state.segments.insert(next, Segment::default());
+        for id in ids {
+            self.drop_segment(&mut state, id)?;
+        }
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize
+    {
+        self.state.lock().unwrap().index.len()
+    }
+
+    pub fn is_empty(&self) -> bool
+    {
+        self.len() == 0
+    }
+
+    /// Returns the size of the segments, dead values included.
+    pub fn bytes(&self) -> u64
+    {
+        self.state.lock().unwrap().bytes
+    }
+
+    /// Removes the expired values, then rewrites the live values of the segments that are at least half dead, and
+    /// deletes them. Values are copied one at a time, so that the cache isn't held for long. Returns the number of
+    /// bytes reclaimed.
+    pub fn compact(&self) -> Result<u64, Error>
+    {
+        let candidates: Vec<u64> = {
+            let mut state = self.state.lock().unwrap();
+            let now = self.clock.now();
+            let expired: Vec<u128> = state
+                .index
+                .iter()
+                .filter(|(_, location)| location.expiry.remaining(now - location.stored_at).is_none())
+                .map(|(key, _)| *key)
+                .collect();
+            for key in expired {
+                state.remove(key);
+            }
+            let active = state.segments.last_key_value().map(|(id, _)| *id);
+            state
+                .segments
+                .iter()
+                .filter(|(id, segment)| Some(**id) != active && segment.live_bytes * 2 <= segment.bytes)
+                .map(|(id, _)| *id)
+                .collect()
+        };
+
+        let bytes = self.bytes();
+        for id in candidates {
+            let path = segment_path(&self.directory, id);
+            let keys = match self.state.lock().unwrap().segments.get(&id) {
+                Some(segment) => segment.keys.clone(),
+                None => continue,
+            };
+            for key in keys {
+                let Some(location) = self.live_location(key, id) else {
+                    continue;
+                };
+                let value = read(&path, location.offset, location.length)?;
+                let mut state = self.state.lock().unwrap();
+                // Written again or removed while it was read
+                let current = state.index.get(&key);
+                if current.is_some_and(|current| current.segment == id && current.offset == location.offset) {
+                    self.append(&mut state, key, &value, location.stored_at, location.expiry, location.tags)?;
+                }
+            }
+            self.drop_segment(&mut self.state.lock().unwrap(), id)?;
+        }
+        Ok(bytes.saturating_sub(self.bytes()))
+    }
+
+    fn live_location(&self, key: u128, segment: u64) -> Option<Location>
+    {
+        let state = self.state.lock().unwrap();
+        state.index.get(&key).filter(|location| location.segment == segment).cloned()
+    }
+
+    fn append(
+        &self, state: &mut DiskCacheState, key: u128, value: &[u8], stored_at: Instant, expiry: Expiry,
+        tags: Vec<String>,
+    ) -> Result<(), Error>
+    {
+        let size = value.len() as u64 + FRAMING_SIZE;
+        let (mut id, mut offset) = state.segments.last_key_value().map(|(id, segment)| (*id, segment.bytes)).unwrap();
+        if offset + size > self.segment_bytes {
+            id += 1;
+            offset = 0;
+            state.active = File::create(segment_path(&self.directory, id))?;
+            state.segments.insert(id, Segment::default());
+        }
+
+        let mut record = Vec::with_capacity(size as usize);
+        record.extend_from_slice(&(value.len() as u64).to_le_bytes());
+        record.extend_from_slice(value);
+        record.extend_from_slice(&checksum(value).to_le_bytes());
+        if let Err(err) = state.active.write_all(&record) {
+            // The segment may hold part of the value, so offsets can't be trusted anymore
+            state.segments.get_mut(&id).unwrap().bytes = self.segment_bytes;
+            return Err(err);
+        }
+
+        let segment = state.segments.get_mut(&id).unwrap();
+        segment.bytes += size;
+        segment.live_bytes += size;
+        segment.keys.push(key);
+        state.bytes += size;
+        let location = Location {
+            segment: id,
+            offset,
+            length: value.len() as u64,
+            stored_at,
+            expiry,
+            tags,
+        };
+        if let Some(previous) = state.index.insert(key, location) {
+            state.unlink(&previous);
+        }
+
+        while state.bytes > self.max_bytes && state.segments.len() > 1 {
+            let oldest = *state.segments.keys().next().unwrap();
+            self.drop_segment(state, oldest)?;
+        }
+        Ok(())
+    }
+
+    fn drop_segment(&self, state: &mut DiskCacheState, id: u64) -> Result<(), Error>
+    {
+        let Some(segment) = state.segments.remove(&id) else {
+            return Ok(());
+        };
+        state.bytes -= segment.bytes;
+        for key in segment.keys {
+            if state.index.get(&key).is_some_and(|location| location.segment == id) {
+                state.index.remove(&key);
+            }
+        }
+        std::fs::remove_file(segment_path(&self.directory, id))
+    }
+}
+
+impl DiskCacheState
+{
+    fn remove(&mut self, key: u128) -> bool
+    {
+        match self.index.remove(&key) {
+            Some(location) => {
+                self.unlink(&location);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Accounts for a value that's no longer indexed
+    fn unlink(&mut self, location: &Location)
+    {
+        if let Some(segment) = self.segments.get_mut(&location.segment) {
+            segment.live_bytes -= location.length + FRAMING_SIZE;
+        }
+    }
+}
+
+fn segment_path(directory: &Path, id: u64) -> PathBuf
+{
+    directory.join(format!("{:016x}.{}", id, SEGMENT_EXTENSION))
+}
+
+fn read(path: &Path, offset: u64, length: u64) -> Result<Vec<u8>, Error>
+{
+    let mut file = File::open(path)?;
+    file.seek(SeekFrom::Start(offset))?;
+    let mut record = vec![0; (length + FRAMING_SIZE) as usize];
+    file.read_exact(&mut record)?;
+
+    let (header, rest) = record.split_at(8);
+    let (value, footer) = rest.split_at(length as usize);
+    if header != length.to_le_bytes() || footer != checksum(value).to_le_bytes() {
+        return Err(Error::new(ErrorKind::InvalidData, "Corrupted value on disk"));
+    }
+    Ok(value.to_vec())
+}
+
+fn checksum(bytes: &[u8]) -> u64
+{
+    let mut hasher = GxHasher::with_seed(123);
+    hasher.write(bytes);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::ManualClock;
+
+    fn directory(name: &str) -> PathBuf
+    {
+        std::env::temp_dir().join(format!("risu-{}-{}", name, std::process::id()))
+    }
+
+    #[test]
+    fn basic()
+    {
+        let clock = Arc::new(ManualClock::new());
+        let disk = DiskCache::new(directory("disk-basic"), 1024).unwrap().with_clock(clock.clone());
+        let expiry = Expiry::new(Duration::from_secs(60));
+        assert!(disk.put(1, b"hello", expiry, vec!["a".to_string()]).unwrap());
+        assert!(disk.put(2, b"world", expiry, vec!["a".to_string(), "b".to_string()]).unwrap());
+        assert!(disk.put(3, b"!", Expiry::new(Duration::ZERO), vec![]).unwrap());
+        // Doesn't fit in a segment
+        assert!(!disk.put(4, &[0; 1024], expiry, vec![]).unwrap());
+
+        let hit = disk.get(1).unwrap().unwrap();
+        assert_eq!(hit.value, b"hello");
+        assert!(hit.expiry.ttl <= expiry.ttl);
+        clock.advance(Duration::from_millis(10));
+        assert!(disk.get(3).unwrap().is_none());
+        assert!(disk.get(4).unwrap().is_none());
+
+        // Replaced
+        assert!(disk.put(1, b"hi", expiry, vec![]).unwrap());
+        assert_eq!(disk.get(1).unwrap().unwrap().value, b"hi");
+        assert_eq!(disk.len(), 2);
+
+        assert_eq!(disk.remove_tagged("a"), 1);
+        assert!(disk.remove(1));
+        assert!(!disk.remove(1));
+        assert!(disk.is_empty());
+        disk.clear().unwrap();
+        assert_eq!(disk.bytes(), 0);
+        std::fs::remove_dir_all(directory("disk-basic")).unwrap();
+    }
+
+    #[test]
+    fn budget()
+    {
+        // Segments of 100 bytes, filled by a single value each
+        let disk = DiskCache::new(directory("disk-budget"), 800).unwrap();
+        let expiry = Expiry::new(Duration::from_secs(60));
+        for key in 0..8 {
+            assert!(disk.put(key, &[key as u8; 84], expiry, vec![]).unwrap());
+        }
+        assert_eq!(disk.bytes(), 800);
+        // The oldest segment is dropped
+        assert!(disk.put(8, &[8; 84], expiry, vec![]).unwrap());
+        assert_eq!(disk.bytes(), 800);
+        assert!(disk.get(0).unwrap().is_none());
+        assert_eq!(disk.get(1).unwrap().unwrap().value, [1; 84]);
+        assert_eq!(disk.len(), 8);
+        std::fs::remove_dir_all(directory("disk-budget")).unwrap();
+    }
+
+    #[test]
+    fn compaction()
+    {
+        // Segments of 100 bytes, holding four values of 9 bytes each
+        let clock = Arc::new(ManualClock::new());
+        let disk = DiskCache::new(directory("disk-compaction"), 800).unwrap().with_clock(clock.clone());
+        let expiry = Expiry::new(Duration::from_secs(60));
+        for key in 0..8 {
+            assert!(disk.put(key, &[key as u8; 9], expiry, vec![]).unwrap());
+        }
+        assert!(disk.remove(0));
+        assert!(disk.remove(1));
+        assert!(disk.remove(2));
+        assert!(disk.remove(4));
+        assert!(disk.put(5, b"replaced", expiry, vec![]).unwrap());
+        assert!(disk.put(8, &[8; 9], Expiry::new(Duration::ZERO), vec![]).unwrap());
+        clock.advance(Duration::from_millis(10));
+
+        // Only 3 is left in the first segment, and 6 and 7 in the second one. They're copied to the last segment,
+        // which holds 5 and the expired 8, and to a new one.
+        let before = disk.bytes();
+        assert_eq!(disk.compact().unwrap(), before - (24 + 25 + 2 * 25) - 25);
+        assert_eq!(disk.len(), 4);
+        for key in [3, 6, 7] {
+            assert_eq!(disk.get(key).unwrap().unwrap().value, [key as u8; 9]);
+        }
+        assert_eq!(disk.get(5).unwrap().unwrap().value, b"replaced");
+        std::fs::remove_dir_all(directory("disk-compaction")).unwrap();
+    }
+}
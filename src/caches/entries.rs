@@ -0,0 +1,445 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::{Clock, EntryState, Evicted, EvictionListener, Expiry, MonotonicClock};
+
+/// Entries of a cache, and the bookkeeping shared by its policies: default expiry, expiration and tag indexes, weight,
+/// and evictions. The policy keeps the entries in a list, in the order it evicts them, and tells each entry its node.
+pub(crate) struct Entries<K, V>
+{
+    map: HashMap<K, Entry<V>>,
+    expiration: Duration,
+    stale_while_revalidate: Duration,
+    stale_if_error: Duration,
+    refresh_ahead_percent: u8,
+    refresh_ahead_min_hits: u32,
+    max_size: usize,
+    max_weight: usize,
+    weight: usize,
+    weigher: fn(&V) -> usize,
+    // Keys by the second, since the creation of the cache, they expire at. Keys may be stale: when the entry is gone,
+    // or was indexed again. This lets expired entries be removed without walking the whole list.
+    epoch: Instant,
+    pub(super) expirations: BTreeMap<u64, Vec<K>>,
+    clock: Arc<dyn Clock>,
+    tagger: fn(&V) -> &[String],
+    // Keys of the entries carrying each tag, kept in sync with the map
+    pub(super) tags: HashMap<String, HashSet<K>>,
+    eviction_listener: Option<EvictionListener<K, V>>,
+}
+
+pub(crate) struct Entry<V>
+{
+    pub node_index: usize,
+    pub insertion: Instant,
+    pub expiry: Expiry,
+    weight: usize,
+    pub value: Arc<V>,
+    // Since the entry was added or last due for a refresh ahead, only counted when refreshing ahead or listening to
+    // evictions. Atomic, like the flags below, so that policies can look entries up while they're shared.
+    hits: AtomicU32,
+    refresh_ahead: AtomicBool,
+    // Only used by SIEVE, whether the entry was looked up since the hand last went over it
+    pub visited: AtomicBool,
+    // Key of the entry in the expiration index, if it ever expires
+    expiration_second: Option<u64>,
+}
+
+impl<V> Entry<V>
+{
+    /// Returns what's left of the expiry of the entry, or `None` if it expired.
+    pub fn remaining(&self, now: Instant) -> Option<Expiry>
+    {
+        self.expiry.remaining(now - self.insertion)
+    }
+
+    /// Tells whether a new value may replace the entry: when it's no longer fresh, or due for a refresh ahead.
+    pub fn is_refreshable(&self, now: Instant) -> bool
+    {
+        now - self.insertion > self.expiry.ttl || self.refresh_ahead.load(Ordering::Relaxed)
+    }
+
+    fn into_evicted<K>(self, key: K, now: Instant) -> Option<Evicted<K, V>>
+    {
+        Some(Evicted {
+            expiry: self.remaining(now)?,
+            key,
+            value: self.value,
+            hits: self.hits.into_inner(),
+        })
+    }
+}
+
+impl<K, V> Entries<K, V>
+where
+    K: Eq + std::hash::Hash + Clone,
+{
+    pub fn new(max_size: usize, expiration: Duration) -> Self
+    {
+        Self {
+            map: HashMap::new(),
+            expiration,
+            stale_while_revalidate: Duration::ZERO,
+            stale_if_error: Duration::ZERO,
+            refresh_ahead_percent: 0,
+            refresh_ahead_min_hits: 0,
+            max_size,
+            max_weight: usize::MAX,
+            weight: 0,
+            weigher: |_| 0,
+            epoch: Instant::now(),
+            expirations: BTreeMap::new(),
+            clock: Arc::new(MonotonicClock),
+            tagger: |_| &[],
+            tags: HashMap::new(),
+            eviction_listener: None,
+        }
+    }
+
+    /// See [`LruCache::with_max_weight`](crate::LruCache::with_max_weight).
+    pub fn with_max_weight(mut self, max_weight: usize, weigher: fn(&V) -> usize) -> Self
+    {
+        self.max_weight = max_weight;
+        self.weigher = weigher;
+        self.weight = self.map.values().map(|entry| weigher(&entry.value)).sum();
+        self
+    }
+
+    /// See [`LruCache::with_tags`](crate::LruCache::with_tags).
+    pub fn with_tags(mut self, tagger: fn(&V) -> &[String]) -> Self
+    {
+        self.tagger = tagger;
+        self.tags.clear();
+        for (key, entry) in &self.map {
+            for tag in tagger(&entry.value) {
+                self.tags.entry(tag.clone()).or_default().insert(key.clone());
+            }
+        }
+        self
+    }
+
+    /// See [`LruCache::with_eviction_listener`](crate::LruCache::with_eviction_listener).
+    pub fn with_eviction_listener(mut self, listener: EvictionListener<K, V>) -> Self
+    {
+        self.eviction_listener = Some(listener);
+        self
+    }
+
+    /// See [`LruCache::with_stale_while_revalidate`](crate::LruCache::with_stale_while_revalidate).
+    pub fn with_stale_while_revalidate(mut self, stale_while_revalidate: Duration) -> Self
+    {
+        self.stale_while_revalidate = stale_while_revalidate;
+        self
+    }
+
+    /// See [`LruCache::with_stale_if_error`](crate::LruCache::with_stale_if_error).
+    pub fn with_stale_if_error(mut self, stale_if_error: Duration) -> Self
+    {
+        self.stale_if_error = stale_if_error;
+        self
+    }
+
+    /// See [`LruCache::with_refresh_ahead`](crate::LruCache::with_refresh_ahead).
+    pub fn with_refresh_ahead(mut self, ttl_percent: u8, min_hits: u32) -> Self
+    {
+        self.refresh_ahead_percent = ttl_percent.min(100);
+        self.refresh_ahead_min_hits = min_hits;
+        self
+    }
+
+    /// See [`LruCache::with_clock`](crate::LruCache::with_clock).
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self
+    {
+        self.epoch = clock.now();
+        self.clock = clock;
+        self
+    }
+
+    pub fn default_expiry(&self) -> Expiry
+    {
+        Expiry::new(self.expiration)
+            .with_stale_while_revalidate(self.stale_while_revalidate)
+            .with_stale_if_error(self.stale_if_error)
+    }
+
+    pub fn expiration(&self) -> Duration
+    {
+        self.expiration
+    }
+
+    pub fn now(&self) -> Instant
+    {
+        self.clock.now()
+    }
+
+    pub fn get(&self, key: &K) -> Option<&Entry<V>>
+    {
+        self.map.get(key)
+    }
+
+    pub fn get_mut(&mut self, key: &K) -> Option<&mut Entry<V>>
+    {
+        self.map.get_mut(key)
+    }
+
+    pub fn len(&self) -> usize
+    {
+        self.map.len()
+    }
+
+    pub fn capacity(&self) -> usize
+    {
+        self.max_size
+    }
+
+    pub fn weight(&self) -> usize
+    {
+        self.weight
+    }
+
+    pub fn max_weight(&self) -> usize
+    {
+        self.max_weight
+    }
+
+    pub fn weigh(&self, value: &V) -> usize
+    {
+        (self.weigher)(value)
+    }
+
+    /// Tells whether the given number of entries and weight can be added without going over the bounds of the cache.
+    pub fn fits(&self, entries: usize, weight: usize) -> bool
+    {
+        self.map.len() + entries <= self.max_size && self.weight + weight <= self.max_weight
+    }
+
+    /// Tells whether the key has an entry that can still be served, fresh or stale.
+    pub fn contains(&self, key: &K) -> bool
+    {
+        self.map
+            .get(key)
+            .is_some_and(|entry| self.clock.now() - entry.insertion <= entry.expiry.lifetime())
+    }
+
+    /// Adds the entry at the given node of the list of the policy, replacing the previous one of the key, if any.
+    pub fn insert(&mut self, key: K, node_index: usize, value: Arc<V>, weight: usize, expiry: Expiry, now: Instant)
+    {
+        if let Some(previous) = self.map.remove(&key) {
+            self.weight -= previous.weight;
+            untag(&mut self.tags, self.tagger, &key, &previous.value);
+        }
+
+        let expiration_second = expiration_second(self.epoch, now, &expiry);
+        if let Some(second) = expiration_second {
+            self.expirations.entry(second).or_default().push(key.clone());
+        }
+        for tag in (self.tagger)(&value) {
+            self.tags.entry(tag.clone()).or_default().insert(key.clone());
+        }
+        self.weight += weight;
+        self.map.insert(
+            key,
+            Entry {
+                node_index,
+                insertion: now,
+                expiry,
+                weight,
+                value,
+                hits: AtomicU32::new(0),
+                refresh_ahead: AtomicBool::new(false),
+                visited: AtomicBool::new(false),
+                expiration_second,
+            },
+        );
+    }
+
+    /// Returns the entry if it's fresh or within the given stale window, and its state, counting the lookup as a hit.
+    /// Expired entries are left for the policy to remove.
+    pub fn hit(
+        &self, key: &K, now: Instant, stale_window: fn(&Expiry) -> Duration,
+    ) -> Option<(&Entry<V>, EntryState)>
+    {
+        let entry = self.map.get(key)?;
+        let age = now - entry.insertion;
+        let expiry = entry.expiry;
+        if age > expiry.ttl.saturating_add(stale_window(&expiry)) {
+            return None;
+        }
+
+        let hits = match self.refresh_ahead_percent > 0 || self.eviction_listener.is_some() {
+            true => entry.hits.fetch_add(1, Ordering::Relaxed).saturating_add(1),
+            false => 0,
+        };
+        let state = if age > expiry.ttl {
+            EntryState::Stale
+        } else if self.refresh_ahead_percent > 0
+            && hits >= self.refresh_ahead_min_hits
+            && expiry.ttl - age <= (expiry.ttl / 100).saturating_mul(self.refresh_ahead_percent as u32)
+        {
+            // Hits are counted again, so that a failed refresh is retried, but not on every hit
+            entry.hits.store(0, Ordering::Relaxed);
+            entry.refresh_ahead.store(true, Ordering::Relaxed);
+            EntryState::RefreshAhead
+        } else {
+            EntryState::Fresh
+        };
+
+        Some((entry, state))
+    }
+
+    /// Returns the value if it's fresh or within its stale-while-revalidate window, and its state, without counting the
+    /// lookup as a hit.
+    pub fn peek(&self, key: &K) -> Option<(Arc<V>, EntryState)>
+    {
+        let entry = self.map.get(key)?;
+        let age = self.clock.now() - entry.insertion;
+        match age > entry.expiry.ttl {
+            false => Some((entry.value.clone(), EntryState::Fresh)),
+            true if age <= entry.expiry.ttl.saturating_add(entry.expiry.stale_while_revalidate) => {
+                Some((entry.value.clone(), EntryState::Stale))
+            }
+            true => None,
+        }
+    }
+
+    /// Removes the entry of the key, and returns it for the policy to unlink its node.
+    pub fn remove(&mut self, key: &K) -> Option<Entry<V>>
+    {
+        let entry = self.map.remove(key)?;
+        self.weight -= entry.weight;
+        untag(&mut self.tags, self.tagger, key, &entry.value);
+        Some(entry)
+    }
+
+    /// Removes the entry of the key to make room for others, and hands it over to the eviction listener unless it
+    /// expired anyway. Returns the node of the entry, for the policy to unlink it.
+    pub fn evict(&mut self, key: K, now: Instant) -> Option<usize>
+    {
+        let entry = self.remove(&key)?;
+        let node_index = entry.node_index;
+        if let Some(listener) = &self.eviction_listener {
+            if let Some(evicted) = entry.into_evicted(key, now) {
+                listener(evicted);
+            }
+        }
+        Some(node_index)
+    }
+
+    /// Removes the entry of the key for the caller to evict it, or move it, unless it expired. The eviction listener
+    /// isn't called.
+    pub fn take(&mut self, key: K, now: Instant) -> Option<(usize, Option<Evicted<K, V>>)>
+    {
+        let entry = self.remove(&key)?;
+        Some((entry.node_index, entry.into_evicted(key, now)))
+    }
+
+    /// Removes the entries whose value carries the tag, unlinking their nodes, and returns how many there were.
+    pub fn remove_tagged(&mut self, tag: &str, mut unlink: impl FnMut(usize)) -> usize
+    {
+        let Some(keys) = self.tags.remove(tag) else {
+            return 0;
+        };
+        let mut removed = 0;
+        for key in keys {
+            if let Some(entry) = self.remove(&key) {
+                unlink(entry.node_index);
+                removed += 1;
+            }
+        }
+        removed
+    }
+
+    /// See [`LruCache::remove_expired`](crate::LruCache::remove_expired). The nodes of the removed entries are
+    /// unlinked with the given function.
+    pub fn remove_expired(&mut self, max_visits: usize, mut unlink: impl FnMut(usize)) -> (usize, bool)
+    {
+        let now = self.clock.now();
+        // Entries indexed by the current second may not have expired yet
+        let current_second = (now - self.epoch).as_secs();
+        let mut removed = 0;
+
+        for _ in 0..max_visits {
+            let Some(mut keys) = self.expirations.first_entry() else {
+                return (removed, false);
+            };
+            let second = *keys.key();
+            if second >= current_second {
+                return (removed, false);
+            }
+            let key = keys.get_mut().pop();
+            if keys.get().is_empty() {
+                keys.remove();
+            }
+            let Some(key) = key else {
+                continue;
+            };
+
+            let Some(entry) = self.map.get_mut(&key) else {
+                continue;
+            };
+            if entry.expiration_second != Some(second) {
+                continue;
+            }
+            if now - entry.insertion <= entry.expiry.lifetime() {
+                // Sliding expiration pushed it back
+                entry.expiration_second = expiration_second(self.epoch, entry.insertion, &entry.expiry);
+                if let Some(second) = entry.expiration_second {
+                    self.expirations.entry(second).or_default().push(key);
+                }
+            } else if let Some(entry) = self.remove(&key) {
+                unlink(entry.node_index);
+                removed += 1;
+            }
+        }
+
+        (removed, true)
+    }
+
+    pub fn clear(&mut self)
+    {
+        self.map.clear();
+        self.expirations.clear();
+        self.tags.clear();
+        self.weight = 0;
+    }
+
+    /// Returns the entries of the given keys, in the same order, with what's left of their expiry. Expired entries are
+    /// skipped.
+    pub fn iter<'a>(
+        &'a self, keys: impl Iterator<Item = &'a K> + 'a,
+    ) -> Box<dyn Iterator<Item = (&'a K, &'a Arc<V>, Expiry)> + 'a>
+    {
+        let now = self.clock.now();
+        Box::new(keys.filter_map(move |key| {
+            let entry = self.map.get(key)?;
+            Some((key, &entry.value, entry.remaining(now)?))
+        }))
+    }
+}
+
+// Removes the key from the index of the tags of its value
+fn untag<K, V>(tags: &mut HashMap<String, HashSet<K>>, tagger: fn(&V) -> &[String], key: &K, value: &V)
+where
+    K: Eq + std::hash::Hash,
+{
+    for tag in tagger(value) {
+        if let Some(keys) = tags.get_mut(tag) {
+            keys.remove(key);
+            if keys.is_empty() {
+                tags.remove(tag);
+            }
+        }
+    }
+}
+
+fn expiration_second(epoch: Instant, insertion: Instant, expiry: &Expiry) -> Option<u64>
+{
+    // Entries living forever aren't indexed, even when added right at the epoch
+    if expiry.lifetime() == Duration::MAX {
+        return None;
+    }
+    let expiration = insertion.saturating_duration_since(epoch).checked_add(expiry.lifetime())?;
+    Some(expiration.as_secs())
+}
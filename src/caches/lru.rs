@@ -1,18 +1,15 @@
-use std::collections::hash_map::Entry;
-use std::collections::HashMap;
 use std::sync::Arc;
-use std::time::{Duration, Instant};
+use std::time::Duration;
 
-use crate::{ArenaLinkedList, Cache};
+use super::entries::Entries;
+use crate::{ArenaLinkedList, Clock, EntryState, Evicted, EvictionListener, Expiry, ShardCache};
 
 #[allow(dead_code)]
 pub struct LruCache<K, V>
 {
     lru_list: ArenaLinkedList<K>,
-    map: HashMap<K, LruCacheEntry<V>>,
-    expiration: Duration,
+    entries: Entries<K, V>,
     expiration_type: ExpirationType,
-    max_size: usize,
 }
 
 #[derive(PartialEq, Clone, Copy)]
@@ -22,71 +19,134 @@ pub enum ExpirationType
     Sliding,
 }
 
-struct LruCacheEntry<V>
-{
-    node_index: usize,
-    insertion: Instant,
-    value: Arc<V>,
-}
-
-impl<K, V> Cache<K, V> for LruCache<K, V>
+impl<K, V> ShardCache<K, V> for LruCache<K, V>
 where
     K: Eq + std::hash::Hash + Clone,
 {
     fn try_add_arc(&mut self, key: K, value: Arc<V>) -> bool
     {
-        let mut added = false;
-
-        self.map.entry(key).or_insert_with_key(|k| {
-            added = true;
-            LruCacheEntry {
-                node_index: self.lru_list.add_last(k.clone()).expect("Failed to add node to list"),
-                insertion: Instant::now(),
-                value: value,
-            }
-        });
+        self.try_add_arc_with_expiry(key, value, self.default_expiry())
+    }
 
-        if added {
-            self.trim();
+    fn try_add_arc_with_expiry(&mut self, key: K, value: Arc<V>, expiry: Expiry) -> bool
+    {
+        let weight = self.entries.weigh(&value);
+        if weight > self.entries.max_weight() {
+            // Would evict everything else and still not fit
+            return false;
         }
 
-        return added;
+        let now = self.entries.now();
+        match self.entries.get(&key) {
+            // A stale entry, or one due for a refresh ahead, is being refreshed, replace it
+            Some(entry) if entry.is_refreshable(now) => {
+                self.lru_list
+                    .remove(entry.node_index)
+                    .expect("Failed to remove node, cache is likely corrupted");
+            }
+            Some(_) => return false,
+            None => {}
+        }
+        let node_index = self
+            .lru_list
+            .add_last(key.clone())
+            .expect("Failed to add node to list, cache is likely corrupted");
+        self.entries.insert(key, node_index, value, weight, expiry, now);
+        self.trim();
+        // Keeps the expiration index from piling up keys of entries that are gone, even if it's never swept
+        self.remove_expired(2);
+        true
     }
 
     fn try_get(&mut self, key: &K) -> Option<Arc<V>>
     {
-        // If found in the map, remove from the lru list and reinsert at the end
-        let lru_list = &mut self.lru_list;
+        self.get(key, |_| Duration::ZERO).map(|(value, _)| value)
+    }
+
+    fn try_get_stale(&mut self, key: &K) -> Option<(Arc<V>, EntryState)>
+    {
+        self.get(key, |expiry| expiry.stale_while_revalidate)
+    }
+
+    fn try_get_stale_if_error(&mut self, key: &K) -> Option<Arc<V>>
+    {
+        self.get(key, |expiry| expiry.stale_if_error).map(|(value, _)| value)
+    }
+
+    fn peek_stale(&self, key: &K) -> Option<(Arc<V>, EntryState)>
+    {
+        self.entries.peek(key)
+    }
 
-        match self.map.entry(key.clone()) {
-            Entry::Occupied(mut entry) => {
-                if Instant::now() - entry.get().insertion > self.expiration {
-                    // Entry has expired, we remove it and pretend it's not in the cache
-                    lru_list
-                        .remove(entry.get().node_index)
-                        .expect("Failed to remove node, cache is likely corrupted");
-                    entry.remove_entry();
-                    None
-                } else {
-                    if self.expiration_type == ExpirationType::Sliding {
-                        // Refresh duration
-                        entry.get_mut().insertion = Instant::now();
-                    }
-
-                    // Move to the end of the list (the "LRU" part)
-                    lru_list
-                        .remove(entry.get().node_index)
-                        .expect("Failed to remove node, cache is likely corrupted");
-                    entry.get_mut().node_index = lru_list
-                        .add_last(key.clone())
-                        .expect("Failed to add node to list, cache is likely corrupted");
-
-                    Some(entry.into_mut().value.clone())
-                }
+    fn remove(&mut self, key: &K) -> bool
+    {
+        match self.entries.remove(key) {
+            Some(entry) => {
+                self.lru_list
+                    .remove(entry.node_index)
+                    .expect("Failed to remove node, cache is likely corrupted");
+                true
             }
-            Entry::Vacant(_) => None,
+            None => false,
         }
     }
+
+    fn clear(&mut self)
+    {
+        self.entries.clear();
+        self.lru_list.clear();
+    }
+
+    fn remove_tagged(&mut self, tag: &str) -> usize
+    {
+        let lru_list = &mut self.lru_list;
+        self.entries.remove_tagged(tag, |index| {
+            lru_list.remove(index).expect("Failed to remove node, cache is likely corrupted")
+        })
+    }
+
+    fn len(&self) -> usize
+    {
+        self.entries.len()
+    }
+
+    fn contains(&self, key: &K) -> bool
+    {
+        self.entries.contains(key)
+    }
+
+    fn capacity(&self) -> usize
+    {
+        self.entries.capacity()
+    }
+
+    /// Returns the entries from the least to the most recently used, with what's left of their expiry. Expired entries
+    /// are skipped.
+    fn entries(&self) -> Box<dyn Iterator<Item = (&K, &Arc<V>, Expiry)> + '_>
+    {
+        self.entries.iter(self.lru_list.iter())
+    }
+
+    fn restore(&mut self, key: K, value: Arc<V>, expiry: Expiry) -> bool
+    {
+        self.try_add_arc_with_expiry(key, value, expiry)
+    }
+
+    /// Returns the total weight of the values currently in the cache.
+    fn weight(&self) -> usize
+    {
+        self.entries.weight()
+    }
+
+    /// Removes the expired entries, wherever they are in the list, visiting at most `max_visits` keys so that the
+    /// cache isn't held for long. Returns the number of entries removed, and whether there may be more to remove.
+    fn remove_expired(&mut self, max_visits: usize) -> (usize, bool)
+    {
+        let lru_list = &mut self.lru_list;
+        self.entries.remove_expired(max_visits, |index| {
+            lru_list.remove(index).expect("Failed to remove node, cache is likely corrupted")
+        })
+    }
 }
 
 #[allow(dead_code)]
@@ -98,40 +158,179 @@ where
     {
         Self {
             lru_list: ArenaLinkedList::new_with_capacity(max_size),
-            map: HashMap::new(),
-            expiration: expiration,
-            expiration_type: expiration_type,
-            max_size: max_size,
+            entries: Entries::new(max_size, expiration),
+            expiration_type,
+        }
+    }
+
+    /// Bounds the cache by the total weight of its values, in addition to its number of entries.
+    /// The weigher is typically [`Weigh::weigh`](crate::Weigh::weigh), to bound the cache by bytes.
+    pub fn with_max_weight(mut self, max_weight: usize, weigher: fn(&V) -> usize) -> Self
+    {
+        self.entries = self.entries.with_max_weight(max_weight, weigher);
+        self
+    }
+
+    /// Indexes the entries by the tags of their values, so that [`ShardCache::remove_tagged`] can remove them.
+    /// The tagger is typically [`Tag::tags`](crate::Tag::tags).
+    pub fn with_tags(mut self, tagger: fn(&V) -> &[String]) -> Self
+    {
+        self.entries = self.entries.with_tags(tagger);
+        self
+    }
+
+    /// Calls the listener with the entries evicted to keep the cache within its bounds, unless they expired anyway.
+    /// It's called while the cache is borrowed, so it should only hand the entries over, such as to a channel.
+    pub fn with_eviction_listener(mut self, listener: EvictionListener<K, V>) -> Self
+    {
+        self.entries = self.entries.with_eviction_listener(listener);
+        self
+    }
+
+    /// Lets entries be served for the given duration once they're no longer fresh, while they're refreshed.
+    /// This is the default of the entries, see [`ShardCache::try_add_arc_with_expiry`].
+    pub fn with_stale_while_revalidate(mut self, stale_while_revalidate: Duration) -> Self
+    {
+        self.entries = self.entries.with_stale_while_revalidate(stale_while_revalidate);
+        self
+    }
+
+    /// Lets entries be served for the given duration once they're no longer fresh, when they can't be refreshed.
+    /// This is the default of the entries, see [`ShardCache::try_add_arc_with_expiry`].
+    pub fn with_stale_if_error(mut self, stale_if_error: Duration) -> Self
+    {
+        self.entries = self.entries.with_stale_if_error(stale_if_error);
+        self
+    }
+
+    /// Makes hits on an entry in the last `ttl_percent` of its time to live tell that the entry should be refreshed,
+    /// if it had at least `min_hits` hits since it was added or last refreshed. See [`EntryState::RefreshAhead`].
+    pub fn with_refresh_ahead(mut self, ttl_percent: u8, min_hits: u32) -> Self
+    {
+        self.entries = self.entries.with_refresh_ahead(ttl_percent, min_hits);
+        self
+    }
+
+    /// Tells the age of the entries with the given clock, instead of the monotonic clock of the system.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self
+    {
+        self.entries = self.entries.with_clock(clock);
+        self
+    }
+
+    /// Returns the default expiry of the entries.
+    pub fn default_expiry(&self) -> Expiry
+    {
+        self.entries.default_expiry()
+    }
+
+    /// Returns whether the key has an entry that a new value may replace: no longer fresh, but still kept for its
+    /// stale windows, or due for a refresh ahead of its expiration.
+    pub fn is_refreshable(&self, key: &K) -> bool
+    {
+        let now = self.entries.now();
+        self.entries
+            .get(key)
+            .is_some_and(|entry| entry.remaining(now).is_some() && entry.is_refreshable(now))
+    }
+
+    /// Returns whether the key has an entry, even one that expired but hasn't been removed yet.
+    pub fn contains_key(&self, key: &K) -> bool
+    {
+        self.entries.get(key).is_some()
+    }
+
+    /// Returns the key of the least recently used entry.
+    pub fn peek_lru(&self) -> Option<&K>
+    {
+        let index = self.lru_list.get_first_index().ok()?;
+        self.lru_list.get(index).ok()?.get_value().as_ref()
+    }
+
+    /// Removes the least recently used entry that hasn't expired and returns it, for the caller to evict or move it.
+    /// Expired entries found on the way are removed too. The eviction listener isn't called.
+    pub fn pop_lru(&mut self) -> Option<Evicted<K, V>>
+    {
+        let now = self.entries.now();
+        while let Some(key) = self.peek_lru().cloned() {
+            let (node_index, evicted) = self
+                .entries
+                .take(key, now)
+                .expect("Node not found in map, cache is likely corrupted");
+            self.lru_list
+                .remove(node_index)
+                .expect("Failed to remove node, cache is likely corrupted");
+            if evicted.is_some() {
+                return evicted;
+            }
         }
+        None
+    }
+
+    /// Removes the entry of the key and returns it unless it expired, for the caller to move it. The eviction listener
+    /// isn't called.
+    pub fn take(&mut self, key: &K) -> Option<Evicted<K, V>>
+    {
+        let now = self.entries.now();
+        let (node_index, evicted) = self.entries.take(key.clone(), now)?;
+        self.lru_list
+            .remove(node_index)
+            .expect("Failed to remove node, cache is likely corrupted");
+        evicted
+    }
+
+    /// Returns the default time to live of the entries.
+    pub fn expiration(&self) -> Duration
+    {
+        self.entries.expiration()
+    }
+
+    // Returns the value if it's fresh or within the given stale window, and its state
+    fn get(&mut self, key: &K, stale_window: fn(&Expiry) -> Duration) -> Option<(Arc<V>, EntryState)>
+    {
+        let now = self.entries.now();
+        if self.entries.get(key)?.remaining(now).is_none() {
+            // Entry has expired, we remove it and pretend it's not in the cache
+            self.remove(key);
+            return None;
+        }
+        // Otherwise kept until its stale windows are over, for it to be served while it's refreshed or on errors
+        let (entry, state) = self.entries.hit(key, now, stale_window)?;
+        let value = entry.value.clone();
+
+        let entry = self.entries.get_mut(key)?;
+        if self.expiration_type == ExpirationType::Sliding && state != EntryState::Stale {
+            // Refresh duration
+            entry.insertion = now;
+        }
+        // Move to the end of the list (the "LRU" part)
+        self.lru_list
+            .remove(entry.node_index)
+            .expect("Failed to remove node, cache is likely corrupted");
+        entry.node_index = self
+            .lru_list
+            .add_last(key.clone())
+            .expect("Failed to add node to list, cache is likely corrupted");
+
+        Some((value, state))
     }
 
     fn trim(&mut self)
     {
-        let mut index = self.lru_list.get_first_index().unwrap_or(usize::MAX);
-        while index != usize::MAX {
-            let node = self
-                .lru_list
-                .get(index)
-                .expect("Failed to get node, cache is likely corrupted");
-            let key = node
-                .get_value()
-                .as_ref()
-                .expect("Node has no value, cache is likely corrupted")
-                .clone();
+        let now = self.entries.now();
+        while let Some(key) = self.peek_lru().cloned() {
             let entry = self
-                .map
+                .entries
                 .get(&key)
                 .expect("Node not found in map, cache is likely corrupted");
-            let next_index = node.get_after_index();
-            if self.lru_list.count() > self.max_size || Instant::now() - entry.insertion > self.expiration {
-                self.map.remove(&key);
+            if self.entries.fits(0, 0) && entry.remaining(now).is_some() {
+                break;
+            }
+            if let Some(index) = self.entries.evict(key, now) {
                 self.lru_list
                     .remove(index)
                     .expect("Failed to remove node, cache is likely corrupted");
-            } else {
-                break;
             }
-            index = next_index;
         }
     }
 }
@@ -140,6 +339,7 @@ where
 mod tests
 {
     use super::*;
+    use crate::ManualClock;
 
     #[test]
     fn basic()
@@ -185,4 +385,249 @@ mod tests
         assert!(lru.try_get(&4).is_some());
         assert!(lru.try_get(&5).is_some());
     }
+
+    #[test]
+    fn ttl()
+    {
+        let clock = Arc::new(ManualClock::new());
+        let mut lru = LruCache::new(4, Duration::MAX, ExpirationType::Absolute).with_clock(clock.clone());
+        assert!(lru.try_add_arc_with_expiry(1, Arc::new("h"), Expiry::new(Duration::ZERO)));
+        assert!(lru.try_add(2, "e"));
+        clock.advance(Duration::from_nanos(1));
+        // Entry 1 has its own expiration, regardless of the cache default
+        assert!(lru.try_get(&1).is_none());
+        assert!(lru.try_get(&2).is_some());
+    }
+
+    #[test]
+    fn ttl_edges()
+    {
+        let clock = Arc::new(ManualClock::new());
+        let mut lru = LruCache::new(4, Duration::from_secs(10), ExpirationType::Absolute).with_clock(clock.clone());
+        assert!(lru.try_add(1, "h"));
+        // Fresh up to its time to live included
+        clock.advance(Duration::from_secs(10));
+        assert!(lru.try_get(&1).is_some());
+        assert!(lru.contains(&1));
+        clock.advance(Duration::from_nanos(1));
+        assert!(lru.try_get(&1).is_none());
+        assert!(!lru.contains(&1));
+
+        let mut lru = LruCache::new(4, Duration::from_secs(10), ExpirationType::Sliding).with_clock(clock.clone());
+        assert!(lru.try_add(1, "h"));
+        // Each hit pushes the expiration back
+        for _ in 0..3 {
+            clock.advance(Duration::from_secs(6));
+            assert!(lru.try_get(&1).is_some());
+        }
+        clock.advance(Duration::from_secs(10) + Duration::from_nanos(1));
+        assert!(lru.try_get(&1).is_none());
+    }
+
+    #[test]
+    fn stale_while_revalidate()
+    {
+        let clock = Arc::new(ManualClock::new());
+        let mut lru = LruCache::new(4, Duration::ZERO, ExpirationType::Absolute)
+            .with_stale_while_revalidate(Duration::from_millis(50))
+            .with_clock(clock.clone());
+        assert!(lru.try_add(1, "h"));
+        assert!(lru.try_add_arc_with_expiry(2, Arc::new("e"), Expiry::new(Duration::ZERO)));
+        clock.advance(Duration::from_millis(1));
+        // Entry 1 is stale, it's only returned when asked for stale entries
+        assert!(lru.try_get(&1).is_none());
+        assert_eq!(lru.try_get_stale(&1), Some((Arc::new("h"), EntryState::Stale)));
+        // Entry 2 has no stale window
+        assert!(lru.try_get_stale(&2).is_none());
+        // A stale entry is replaced by a fresh one
+        assert!(lru.try_add_arc_with_expiry(1, Arc::new("o"), Expiry::new(Duration::MAX)));
+        assert_eq!(lru.try_get_stale(&1), Some((Arc::new("o"), EntryState::Fresh)));
+        assert!(!lru.try_add(1, "w"));
+        clock.advance(Duration::from_millis(60));
+        assert!(lru.try_add(3, "l"));
+        clock.advance(Duration::from_millis(60));
+        // Past its stale window
+        assert!(lru.try_get_stale(&3).is_none());
+        assert_eq!(lru.try_get(&1), Some(Arc::new("o")));
+    }
+
+    #[test]
+    fn stale_if_error()
+    {
+        let clock = Arc::new(ManualClock::new());
+        let mut lru = LruCache::new(4, Duration::ZERO, ExpirationType::Absolute)
+            .with_stale_while_revalidate(Duration::from_millis(10))
+            .with_stale_if_error(Duration::from_millis(100))
+            .with_clock(clock.clone());
+        assert!(lru.try_add(1, "h"));
+        clock.advance(Duration::from_millis(20));
+        // Past its stale-while-revalidate window, but still kept for errors
+        assert!(lru.try_get_stale(&1).is_none());
+        assert!(lru.is_refreshable(&1));
+        assert_eq!(lru.try_get_stale_if_error(&1), Some(Arc::new("h")));
+        clock.advance(Duration::from_millis(100));
+        assert!(lru.try_get_stale_if_error(&1).is_none());
+        assert!(!lru.is_refreshable(&1));
+    }
+
+    #[test]
+    fn refresh_ahead()
+    {
+        let clock = Arc::new(ManualClock::new());
+        let mut lru = LruCache::new(4, Duration::from_millis(100), ExpirationType::Absolute)
+            .with_refresh_ahead(50, 2)
+            .with_clock(clock.clone());
+        assert!(lru.try_add(1, "h"));
+        assert_eq!(lru.try_get_stale(&1), Some((Arc::new("h"), EntryState::Fresh)));
+        clock.advance(Duration::from_millis(60));
+        // Hit twice, and in the last half of its time to live
+        assert_eq!(lru.try_get_stale(&1), Some((Arc::new("h"), EntryState::RefreshAhead)));
+        assert!(lru.is_refreshable(&1));
+        // Hits are counted again
+        assert_eq!(lru.try_get_stale(&1), Some((Arc::new("h"), EntryState::Fresh)));
+        // The fresh entry can be replaced, and the new one is hit from scratch
+        assert!(lru.try_add(1, "e"));
+        assert!(!lru.is_refreshable(&1));
+        assert_eq!(lru.try_get_stale(&1), Some((Arc::new("e"), EntryState::Fresh)));
+        assert_eq!(lru.try_get_stale(&1), Some((Arc::new("e"), EntryState::Fresh)));
+    }
+
+    #[test]
+    fn remove_expired()
+    {
+        let clock = Arc::new(ManualClock::new());
+        let mut lru = LruCache::new(10, Duration::from_millis(100), ExpirationType::Absolute).with_clock(clock.clone());
+        assert!(lru.try_add_arc_with_expiry(2, Arc::new("e"), Expiry::new(Duration::MAX)));
+        assert!(lru.try_add(1, "h"));
+        assert!(lru.try_add(3, "l"));
+        assert_eq!(lru.remove_expired(10), (0, false));
+        // Entries are indexed by the second they expire at
+        clock.advance(Duration::from_millis(1100));
+        assert!(lru.try_get(&3).is_none());
+        // 1 is removed although it's not at the head of the list, 3 already was
+        assert_eq!(lru.remove_expired(1), (0, true));
+        assert_eq!(lru.remove_expired(10), (1, false));
+        assert_eq!(lru.len(), 1);
+        assert!(lru.entries.expirations.is_empty());
+        assert!(lru.try_get(&2).is_some());
+    }
+
+    #[test]
+    fn removal()
+    {
+        let mut lru = LruCache::new(4, Duration::MAX, ExpirationType::Absolute).with_max_weight(10, |v: &&str| v.len());
+        assert!(lru.try_add(1, "h"));
+        assert!(lru.try_add(2, "el"));
+        assert!(lru.try_add(3, "lo"));
+        assert_eq!(lru.len(), 3);
+        assert!(lru.remove(&2));
+        assert!(!lru.remove(&2));
+        assert!(!lru.contains(&2));
+        assert!(lru.contains(&3));
+        assert_eq!((lru.len(), lru.weight()), (2, 3));
+        // The list is still consistent
+        assert!(lru.try_add(4, "w"));
+        assert!(lru.try_add(5, "o"));
+        assert!(lru.try_add(6, "r"));
+        assert!(!lru.contains(&1));
+        lru.clear();
+        assert!(lru.is_empty());
+        assert_eq!(lru.weight(), 0);
+        assert!(lru.try_add(1, "h"));
+        assert_eq!(lru.try_get(&1), Some(Arc::new("h")));
+    }
+
+    #[test]
+    fn tagging()
+    {
+        let tagged = |tags: &[&str]| tags.iter().map(|tag| tag.to_string()).collect::<Vec<_>>();
+        let mut lru: LruCache<u32, Vec<String>> =
+            LruCache::new(3, Duration::MAX, ExpirationType::Absolute).with_tags(|tags| tags.as_slice());
+        assert!(lru.try_add(1, tagged(&["a", "b"])));
+        assert!(lru.try_add(2, tagged(&["a"])));
+        assert!(lru.try_add(3, tagged(&["b"])));
+        assert_eq!(lru.remove_tagged("a"), 2);
+        assert_eq!(lru.remove_tagged("a"), 0);
+        assert!(lru.contains(&3));
+        // Evicting 3 also removes it from the index
+        assert!(lru.try_add(4, tagged(&["a"])));
+        assert!(lru.try_add(5, tagged(&["c"])));
+        assert!(lru.try_add(6, tagged(&["a"])));
+        assert!(!lru.contains(&3));
+        assert!(!lru.entries.tags.contains_key("b"));
+        assert_eq!(lru.remove_tagged("b"), 0);
+        assert_eq!(lru.remove_tagged("a"), 2);
+        assert_eq!(lru.len(), 1);
+        lru.clear();
+        assert!(lru.entries.tags.is_empty());
+        assert_eq!(lru.remove_tagged("c"), 0);
+    }
+
+    #[test]
+    fn eviction()
+    {
+        let clock = Arc::new(ManualClock::new());
+        let evicted = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let mut lru = LruCache::new(2, Duration::from_secs(60), ExpirationType::Absolute)
+            .with_clock(clock.clone())
+            .with_eviction_listener({
+                let evicted = evicted.clone();
+                Arc::new(move |entry: Evicted<u32, &str>| evicted.lock().unwrap().push((entry.key, entry.hits)))
+            });
+        assert!(lru.try_add(1, "h"));
+        assert!(lru.try_add(2, "e"));
+        assert!(lru.try_get(&1).is_some());
+        assert!(lru.try_add(3, "l"));
+        assert!(lru.try_add(4, "l"));
+        assert_eq!(*evicted.lock().unwrap(), vec![(2, 0), (1, 1)]);
+
+        // Removed and expired entries aren't evictions
+        assert!(lru.remove(&3));
+        assert!(lru.try_add_arc_with_expiry(5, Arc::new("o"), Expiry::new(Duration::ZERO)));
+        clock.advance(Duration::from_millis(10));
+        assert!(lru.try_add(6, "w"));
+        assert!(lru.try_add(7, "o"));
+        assert_eq!(*evicted.lock().unwrap(), vec![(2, 0), (1, 1), (4, 0)]);
+    }
+
+    #[test]
+    fn popping()
+    {
+        let clock = Arc::new(ManualClock::new());
+        // Hits are only counted when something listens to them
+        let mut lru = LruCache::new(4, Duration::from_secs(60), ExpirationType::Absolute)
+            .with_clock(clock.clone())
+            .with_eviction_listener(Arc::new(|_: Evicted<u32, &str>| {}));
+        assert!(lru.try_add_arc_with_expiry(1, Arc::new("h"), Expiry::new(Duration::from_millis(50))));
+        assert!(lru.try_add(2, "e"));
+        assert!(lru.try_add(3, "l"));
+        assert!(lru.try_get(&2).is_some());
+        assert_eq!(lru.peek_lru(), Some(&1));
+        clock.advance(Duration::from_millis(60));
+        // Expired entry is removed on the way
+        assert_eq!(lru.pop_lru().map(|evicted| (evicted.key, evicted.hits)), Some((3, 0)));
+        assert!(!lru.contains_key(&1));
+        assert_eq!(lru.pop_lru().map(|evicted| (evicted.key, evicted.hits)), Some((2, 1)));
+        assert!(lru.pop_lru().is_none());
+        assert!(lru.is_empty());
+    }
+
+    #[test]
+    fn weighting()
+    {
+        let mut lru = LruCache::new(100, Duration::MAX, ExpirationType::Absolute).with_max_weight(10, |v: &&str| v.len());
+        assert!(lru.try_add(1, "hell"));
+        assert!(lru.try_add(2, "o w"));
+        assert!(lru.try_add(3, "orl"));
+        assert_eq!(lru.weight(), 10);
+        // Max weight is exceeded, oldest entries are evicted until it fits again
+        assert!(lru.try_add(4, "d!"));
+        assert_eq!(lru.weight(), 8);
+        assert!(lru.try_get(&1).is_none());
+        assert!(lru.try_get(&2).is_some());
+        // A value heavier than the whole budget is never admitted
+        assert!(!lru.try_add(5, "hello world"));
+        assert!(lru.try_get(&5).is_none());
+        assert_eq!(lru.weight(), 8);
+    }
 }
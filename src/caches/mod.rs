@@ -1,16 +1,127 @@
+pub mod clock;
+pub use clock::{Clock, CoarseClock, ManualClock, MonotonicClock};
+
+pub mod disk;
+pub use disk::DiskCache;
+
+mod entries;
+
 pub mod lru;
 pub use lru::LruCache;
 
 pub mod probatory;
 pub use probatory::ProbatoryCache;
 
+pub mod shard;
+pub use shard::Policy;
+
 pub mod sharded;
-use std::{future::Future, sync::Arc};
+use std::{future::Future, sync::Arc, time::Duration};
 
-pub use sharded::ShardedCache;
+pub use sharded::{Lookup, PassthroughBuildHasher, ShardedCache};
 
-#[allow(async_fn_in_trait)]
-pub trait Cache<K, V>
+pub mod sieve;
+pub use sieve::SieveCache;
+
+pub mod tiny_lfu;
+pub use tiny_lfu::TinyLfuCache;
+
+/// How long a cache entry lives.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Expiry
+{
+    /// How long the entry is fresh.
+    pub ttl: Duration,
+    /// How long the entry may still be served once it's no longer fresh, while it's being refreshed.
+    pub stale_while_revalidate: Duration,
+    /// How long the entry may still be served once it's no longer fresh, when it can't be refreshed.
+    pub stale_if_error: Duration,
+}
+
+impl Expiry
+{
+    pub fn new(ttl: Duration) -> Self
+    {
+        Self {
+            ttl,
+            stale_while_revalidate: Duration::ZERO,
+            stale_if_error: Duration::ZERO,
+        }
+    }
+
+    pub fn with_stale_while_revalidate(mut self, stale_while_revalidate: Duration) -> Self
+    {
+        self.stale_while_revalidate = stale_while_revalidate;
+        self
+    }
+
+    pub fn with_stale_if_error(mut self, stale_if_error: Duration) -> Self
+    {
+        self.stale_if_error = stale_if_error;
+        self
+    }
+
+    /// Returns how long the entry can be served at all, fresh or stale.
+    pub fn lifetime(&self) -> Duration
+    {
+        self.ttl.saturating_add(self.stale_while_revalidate.max(self.stale_if_error))
+    }
+
+    /// Returns what's left of the expiry once the entry is as old as given, or `None` if it would have expired.
+    pub fn remaining(&self, age: Duration) -> Option<Expiry>
+    {
+        if age > self.lifetime() {
+            return None;
+        }
+        let stale_age = age.saturating_sub(self.ttl);
+        Some(Expiry {
+            ttl: self.ttl.saturating_sub(age),
+            stale_while_revalidate: self.stale_while_revalidate.saturating_sub(stale_age),
+            stale_if_error: self.stale_if_error.saturating_sub(stale_age),
+        })
+    }
+}
+
+/// State of a cached value when it's looked up.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum EntryState
+{
+    Fresh,
+    /// Still fresh, but hot and about to expire, so it should be refreshed ahead.
+    RefreshAhead,
+    /// No longer fresh, but within its stale-while-revalidate window.
+    Stale,
+}
+
+/// Estimates how much memory a cached value holds, in bytes.
+pub trait Weigh
+{
+    fn weigh(&self) -> usize;
+}
+
+/// An entry evicted to make room for others, while it could still have been served.
+pub struct Evicted<K, V>
+{
+    pub key: K,
+    pub value: Arc<V>,
+    /// What's left of the expiry of the entry.
+    pub expiry: Expiry,
+    /// Hits since the entry was added, or last due for a refresh ahead.
+    pub hits: u32,
+}
+
+/// Called with the entries evicted from a cache, while it's locked.
+pub type EvictionListener<K, V> = Arc<dyn Fn(Evicted<K, V>) + Send + Sync>;
+
+/// Tells the tags of a cached value, by which all the values carrying a tag can be removed at once.
+pub trait Tag
+{
+    fn tags(&self) -> &[String];
+}
+
+/// Cache that is borrowed mutably to be changed, or even looked up, such as the policies a [`ShardedCache`] is made
+/// of. Wrap it in a [`ShardedCache`] to share it between threads.
+pub trait ShardCache<K, V>
 {
     fn try_add(&mut self, key: K, value: V) -> bool
     {
@@ -19,41 +130,174 @@ pub trait Cache<K, V>
 
     fn try_add_arc(&mut self, key: K, value: Arc<V>) -> bool;
 
+    /// Same as [`ShardCache::try_add_arc`], but the entry expires as given instead of the cache default.
+    /// An entry that is no longer fresh, or due for a refresh ahead of its expiration, is replaced.
+    fn try_add_arc_with_expiry(&mut self, key: K, value: Arc<V>, expiry: Expiry) -> bool;
+
+    /// Returns the value only if it's fresh.
     fn try_get(&mut self, key: &K) -> Option<Arc<V>>;
 
-    async fn get_or_add<Vfac, Fut>(&mut self, key: K, factory: Vfac) -> Result<Arc<V>, ()>
+    /// Returns the value, fresh or within its stale-while-revalidate window, and its state.
+    fn try_get_stale(&mut self, key: &K) -> Option<(Arc<V>, EntryState)>
+    {
+        self.try_get(key).map(|value| (value, EntryState::Fresh))
+    }
+
+    /// Returns the value, fresh or within its stale-if-error window, to be served when it can't be refreshed.
+    fn try_get_stale_if_error(&mut self, key: &K) -> Option<Arc<V>>
+    {
+        self.try_get(key)
+    }
+
+    /// Same as [`ShardCache::try_get_stale`], but the lookup isn't counted: neither as a hit on the entry, nor as a
+    /// use of the key by the policy. For checking again on a key that was just looked up.
+    fn peek_stale(&self, key: &K) -> Option<(Arc<V>, EntryState)>;
+
+    /// Removes the entry of the key, if any, and tells whether there was one.
+    fn remove(&mut self, key: &K) -> bool;
+
+    /// Removes the entries whose value carries the tag, and returns how many there were.
+    fn remove_tagged(&mut self, tag: &str) -> usize;
+
+    /// Removes all the entries.
+    fn clear(&mut self);
+
+    /// Returns the number of entries, including the expired ones that haven't been removed yet.
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool
+    {
+        self.len() == 0
+    }
+
+    /// Returns the maximum number of entries.
+    fn capacity(&self) -> usize;
+
+    /// Tells whether the key has an entry that can still be served, fresh or stale.
+    fn contains(&self, key: &K) -> bool;
+
+    /// Returns the entries with what's left of their expiry, in the order they should be restored in. Expired entries
+    /// are skipped.
+    fn entries(&self) -> Box<dyn Iterator<Item = (&K, &Arc<V>, Expiry)> + '_>;
+
+    /// Adds an entry right away, without going through admission, such as one that was cached before a restart.
+    fn restore(&mut self, key: K, value: Arc<V>, expiry: Expiry) -> bool;
+
+    /// Returns the total weight of the values currently in the cache.
+    fn weight(&self) -> usize;
+
+    /// Removes the expired entries, visiting at most `max_visits` keys so that the cache isn't held for long. Returns
+    /// the number of entries removed, and whether there may be more to remove.
+    fn remove_expired(&mut self, max_visits: usize) -> (usize, bool);
+
+    /// Returns the cache if it can be looked up without being borrowed mutably, such as while it's only read locked.
+    /// A [`ShardedCache`] only asks once, when it's built, so it must not depend on the entries of the cache.
+    fn as_shared(&self) -> Option<&dyn SharedCache<K, V>>
+    {
+        None
+    }
+}
+
+/// Cache whose lookups don't need it to be borrowed mutably.
+pub trait SharedCache<K, V>
+{
+    /// Same as [`ShardCache::try_get`].
+    fn get(&self, key: &K) -> Option<Arc<V>>;
+
+    /// Same as [`ShardCache::try_get_stale`].
+    fn get_stale(&self, key: &K) -> Option<(Arc<V>, EntryState)>;
+
+    /// Same as [`ShardCache::try_get_stale_if_error`].
+    fn get_stale_if_error(&self, key: &K) -> Option<Arc<V>>;
+}
+
+/// Cache that can be shared between threads, such as a [`ShardedCache`]: it only needs to be borrowed, and locks
+/// whatever it changes.
+#[allow(async_fn_in_trait)]
+pub trait Cache<K, V>
+{
+    fn try_add(&self, key: K, value: V) -> bool
+    {
+        self.try_add_arc(key, Arc::new(value))
+    }
+
+    fn try_add_arc(&self, key: K, value: Arc<V>) -> bool;
+
+    /// See [`ShardCache::try_add_arc_with_expiry`].
+    fn try_add_arc_with_expiry(&self, key: K, value: Arc<V>, expiry: Expiry) -> bool;
+
+    /// Returns the value only if it's fresh.
+    fn try_get(&self, key: &K) -> Option<Arc<V>>;
+
+    /// Returns the value, fresh or within its stale-while-revalidate window, and its state.
+    fn try_get_stale(&self, key: &K) -> Option<(Arc<V>, EntryState)>;
+
+    /// Returns the value, fresh or within its stale-if-error window, to be served when it can't be refreshed.
+    fn try_get_stale_if_error(&self, key: &K) -> Option<Arc<V>>;
+
+    /// Removes the entry of the key, if any, and tells whether there was one.
+    fn remove(&self, key: &K) -> bool;
+
+    /// Removes the entries whose value carries the tag, and returns how many there were.
+    fn remove_tagged(&self, tag: &str) -> usize;
+
+    /// Removes all the entries.
+    fn clear(&self);
+
+    /// Returns the number of entries, including the expired ones that haven't been removed yet.
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool
+    {
+        self.len() == 0
+    }
+
+    /// Returns the maximum number of entries.
+    fn capacity(&self) -> usize;
+
+    /// Tells whether the key has an entry that can still be served, fresh or stale.
+    fn contains(&self, key: &K) -> bool;
+
+    /// Returns the keys of the entries that haven't expired. Entries added or removed meanwhile may be missed.
+    fn iter_keys(&self) -> impl Iterator<Item = K> + '_;
+
+    /// Same as [`Cache::get_or_add_from_item`], with the key as item.
+    async fn get_or_add<Vfac, Fut, E>(&self, key: K, value_factory: Vfac) -> Result<(Arc<V>, Lookup), E>
     where
         K: Clone,
-        Vfac: Fn(&K) -> Fut,
-        Fut: Future<Output = Result<V, ()>>,
+        V: Send + Sync + 'static,
+        E: Clone + Send + Sync + 'static,
+        Vfac: FnOnce(K) -> Fut,
+        Fut: Future<Output = Result<(V, Option<Expiry>), E>>,
     {
-        self.get_or_add_from_item(key, |d: &K| d.clone(), factory).await
+        self.get_or_add_from_item(key, K::clone, value_factory).await
     }
 
-    async fn get_or_add_from_item<I, Kfac, Vfac, Fut>(
-        &mut self, item: I, key_factory: Kfac, value_factory: Vfac,
-    ) -> Result<Arc<V>, ()>
+    /// Returns the value for the key computed from the given item, or computes it with the value factory on a miss.
+    /// The value factory also returns how long the value may be cached, or `None` if it must not be cached at all.
+    /// Stale values are returned right away, without running the value factory.
+    async fn get_or_add_from_item<I, Kfac, Vfac, Fut, E>(
+        &self, item: I, key_factory: Kfac, value_factory: Vfac,
+    ) -> Result<(Arc<V>, Lookup), E>
     where
         K: Clone,
+        V: Send + Sync + 'static,
+        E: Clone + Send + Sync + 'static,
         Kfac: Fn(&I) -> K,
-        Vfac: Fn(&I) -> Fut,
-        Fut: Future<Output = Result<V, ()>>,
+        Vfac: FnOnce(I) -> Fut,
+        Fut: Future<Output = Result<(V, Option<Expiry>), E>>,
     {
         let key = key_factory(&item);
-        match self.try_get(&key) {
-            Some(value) => return Ok(value),
-            None => {
-                match value_factory(&item).await {
-                    Ok(value) => {
-                        let a_value = Arc::new(value);
-                        // This might fail if the key was added by another thread, but we don't care
-                        // This is preferred over blocking the cache during the whole factory call duration
-                        self.try_add_arc(key.clone(), a_value.clone());
-                        Ok(a_value)
-                    }
-                    Err(()) => Err(()),
-                }
-            }
+        if let Some((value, state)) = self.try_get_stale(&key) {
+            return Ok((value, state.into()));
+        }
+        let (value, expiry) = value_factory(item).await?;
+        let value = Arc::new(value);
+        if let Some(expiry) = expiry {
+            // This might fail if the key was added by another thread, but we don't care
+            // This is preferred over blocking the cache during the whole factory call duration
+            self.try_add_arc_with_expiry(key, value.clone(), expiry);
         }
+        Ok((value, Lookup::Miss))
     }
 }
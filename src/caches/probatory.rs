@@ -1,7 +1,7 @@
 use std::{sync::Arc, time::Duration};
 
 use super::lru::ExpirationType;
-use crate::{Cache, LruCache};
+use crate::{Clock, EntryState, EvictionListener, Expiry, LruCache, ShardCache};
 
 #[allow(dead_code)]
 pub struct ProbatoryCache<K, V>
@@ -10,16 +10,27 @@ pub struct ProbatoryCache<K, V>
     resident: LruCache<K, V>,
 }
 
-impl<K, V> Cache<K, V> for ProbatoryCache<K, V>
+impl<K, V> ShardCache<K, V> for ProbatoryCache<K, V>
 where
     K: Eq + std::hash::Hash + Clone,
 {
     fn try_add_arc(&mut self, key: K, value: Arc<V>) -> bool
     {
+        let expiry = self.resident.default_expiry();
+        self.try_add_arc_with_expiry(key, value, expiry)
+    }
+
+    fn try_add_arc_with_expiry(&mut self, key: K, value: Arc<V>, expiry: Expiry) -> bool
+    {
+        // An entry being refreshed doesn't go through probation again
+        if self.resident.is_refreshable(&key) {
+            return self.resident.try_add_arc_with_expiry(key, value, expiry);
+        }
+
         match self.probatory.try_add(key.clone(), ()) {
             // New key in the probatory cache
             true => true,
-            false => match self.resident.try_add_arc(key.clone(), value) {
+            false => match self.resident.try_add_arc_with_expiry(key.clone(), value, expiry) {
                 // Key was already in the probatory cache, but just entered the resident cache
                 true => true,
                 // Already in the resident cache
@@ -32,6 +43,83 @@ where
     {
         self.resident.try_get(key)
     }
+
+    fn try_get_stale(&mut self, key: &K) -> Option<(Arc<V>, EntryState)>
+    {
+        self.resident.try_get_stale(key)
+    }
+
+    fn try_get_stale_if_error(&mut self, key: &K) -> Option<Arc<V>>
+    {
+        self.resident.try_get_stale_if_error(key)
+    }
+
+    fn peek_stale(&self, key: &K) -> Option<(Arc<V>, EntryState)>
+    {
+        self.resident.peek_stale(key)
+    }
+
+    /// The key is still known to the probatory cache, so that its next value doesn't go through probation again.
+    fn remove(&mut self, key: &K) -> bool
+    {
+        self.resident.remove(key)
+    }
+
+    /// Like [`ShardCache::remove`], the probatory cache keeps the keys.
+    fn remove_tagged(&mut self, tag: &str) -> usize
+    {
+        self.resident.remove_tagged(tag)
+    }
+
+    fn clear(&mut self)
+    {
+        self.probatory.clear();
+        self.resident.clear();
+    }
+
+    /// Only counts the resident entries.
+    fn len(&self) -> usize
+    {
+        self.resident.len()
+    }
+
+    fn contains(&self, key: &K) -> bool
+    {
+        self.resident.contains(key)
+    }
+
+    /// Only counts the resident entries.
+    fn capacity(&self) -> usize
+    {
+        self.resident.capacity()
+    }
+
+    /// Returns the entries of the resident cache. See [`LruCache::entries`].
+    fn entries(&self) -> Box<dyn Iterator<Item = (&K, &Arc<V>, Expiry)> + '_>
+    {
+        self.resident.entries()
+    }
+
+    /// Adds an entry to the resident cache right away, such as one that was resident before a restart.
+    fn restore(&mut self, key: K, value: Arc<V>, expiry: Expiry) -> bool
+    {
+        self.resident.try_add_arc_with_expiry(key, value, expiry)
+    }
+
+    /// Returns the total weight of the values in the resident cache.
+    fn weight(&self) -> usize
+    {
+        self.resident.weight()
+    }
+
+    /// Removes the expired entries of both caches, but only counts the resident ones. See
+    /// [`LruCache::remove_expired`].
+    fn remove_expired(&mut self, max_visits: usize) -> (usize, bool)
+    {
+        let (removed, more_resident) = self.resident.remove_expired(max_visits);
+        let (_, more_probatory) = self.probatory.remove_expired(max_visits);
+        (removed, more_resident || more_probatory)
+    }
 }
 
 #[allow(dead_code)]
@@ -46,12 +134,70 @@ where
             resident: LruCache::new(max_size, expiration, expiration_type),
         }
     }
+
+    /// Remembers up to the given number of keys seen once, instead of 10 times the size of the resident cache.
+    pub fn with_probatory_size(mut self, probatory_size: usize) -> Self
+    {
+        self.probatory = LruCache::new(probatory_size, self.resident.expiration(), ExpirationType::Sliding);
+        self
+    }
+
+    /// Bounds the resident cache by the total weight of its values. See [`LruCache::with_max_weight`].
+    pub fn with_max_weight(mut self, max_weight: usize, weigher: fn(&V) -> usize) -> Self
+    {
+        self.resident = self.resident.with_max_weight(max_weight, weigher);
+        self
+    }
+
+    /// See [`LruCache::with_tags`].
+    pub fn with_tags(mut self, tagger: fn(&V) -> &[String]) -> Self
+    {
+        self.resident = self.resident.with_tags(tagger);
+        self
+    }
+
+    /// Only listens to the evictions of the resident cache. See [`LruCache::with_eviction_listener`].
+    pub fn with_eviction_listener(mut self, listener: EvictionListener<K, V>) -> Self
+    {
+        self.resident = self.resident.with_eviction_listener(listener);
+        self
+    }
+
+    /// See [`LruCache::with_stale_while_revalidate`].
+    pub fn with_stale_while_revalidate(mut self, stale_while_revalidate: Duration) -> Self
+    {
+        self.resident = self.resident.with_stale_while_revalidate(stale_while_revalidate);
+        self
+    }
+
+    /// See [`LruCache::with_stale_if_error`].
+    pub fn with_stale_if_error(mut self, stale_if_error: Duration) -> Self
+    {
+        self.resident = self.resident.with_stale_if_error(stale_if_error);
+        self
+    }
+
+    /// See [`LruCache::with_refresh_ahead`].
+    pub fn with_refresh_ahead(mut self, ttl_percent: u8, min_hits: u32) -> Self
+    {
+        self.resident = self.resident.with_refresh_ahead(ttl_percent, min_hits);
+        self
+    }
+
+    /// Gives the clock to both caches. See [`LruCache::with_clock`].
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self
+    {
+        self.probatory = self.probatory.with_clock(clock.clone());
+        self.resident = self.resident.with_clock(clock);
+        self
+    }
 }
 
 #[cfg(test)]
 mod tests
 {
     use super::*;
+    use crate::ManualClock;
 
     #[test]
     fn basic()
@@ -87,4 +233,36 @@ mod tests
         assert!(lru.try_get(&4).is_some());
         assert!(lru.try_get(&5).is_some());
     }
+
+    #[test]
+    fn removal()
+    {
+        let mut lru = ProbatoryCache::new(4, Duration::MAX, ExpirationType::Absolute);
+        assert!(lru.try_add(1, "h"));
+        assert!(lru.try_add(1, "h"));
+        assert!(lru.remove(&1));
+        assert!(!lru.contains(&1));
+        assert!(lru.try_add(1, "e"));
+        assert_eq!(lru.try_get(&1), Some(Arc::new("e")));
+        lru.clear();
+        assert!(lru.is_empty());
+        assert!(lru.try_add(1, "h"));
+        assert!(lru.try_get(&1).is_none(), "Key should go through probation again");
+    }
+
+    #[test]
+    fn refresh()
+    {
+        let clock = Arc::new(ManualClock::new());
+        let mut lru = ProbatoryCache::new(4, Duration::from_millis(10), ExpirationType::Absolute)
+            .with_stale_while_revalidate(Duration::MAX)
+            .with_clock(clock.clone());
+        assert!(lru.try_add(1, "h"));
+        assert!(lru.try_add(1, "h"));
+        clock.advance(Duration::from_millis(20));
+        assert_eq!(lru.try_get_stale(&1), Some((Arc::new("h"), EntryState::Stale)));
+        // Stale entry is replaced right away, although the key left the probatory cache
+        assert!(lru.try_add_arc_with_expiry(1, Arc::new("e"), Expiry::new(Duration::MAX)));
+        assert_eq!(lru.try_get(&1), Some(Arc::new("e")));
+    }
 }
@@ -0,0 +1,203 @@
+use std::{sync::Arc, time::Duration};
+
+use super::lru::ExpirationType;
+use crate::{
+    Clock, EntryState, EvictionListener, Expiry, ProbatoryCache, ShardCache, SharedCache, SieveCache, TinyLfuCache,
+};
+
+/// How a cache decides which keys are worth caching, and which to evict.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Policy
+{
+    /// Keys are cached when they're added a second time, see [`ProbatoryCache`]. Remembers up to the given number of
+    /// keys seen once.
+    Probatory(usize),
+    /// Keys are cached when they're looked up more often than the ones they would evict, see [`TinyLfuCache`].
+    /// Estimates the frequencies of about the given number of keys.
+    TinyLfu(usize),
+    /// Keys are cached right away, and evicted unless they were looked up since the hand last went over them, see
+    /// [`SieveCache`]. Lookups only need the shard to be read locked. Entries always expire a fixed time after they
+    /// were added, so shards can't be built with [`ExpirationType::Sliding`].
+    Sieve,
+}
+
+/// Cache of a shard of a [`ShardedCache`](crate::ShardedCache), depending on its policy.
+// There's one per shard lock, so the size of the largest cache is paid once per shard, not per entry
+#[allow(clippy::large_enum_variant)]
+pub enum Shard<K, V>
+{
+    Probatory(ProbatoryCache<K, V>),
+    TinyLfu(TinyLfuCache<K, V>),
+    Sieve(SieveCache<K, V>),
+}
+
+// Calls the same method on whichever cache the shard holds
+macro_rules! dispatch {
+    ($shard:expr, $cache:ident => $call:expr) => {
+        match $shard {
+            Shard::Probatory($cache) => $call,
+            Shard::TinyLfu($cache) => $call,
+            Shard::Sieve($cache) => $call,
+        }
+    };
+}
+
+// Same as `dispatch`, for the builder methods returning the cache
+macro_rules! map {
+    ($shard:expr, $cache:ident => $call:expr) => {
+        match $shard {
+            Shard::Probatory($cache) => Shard::Probatory($call),
+            Shard::TinyLfu($cache) => Shard::TinyLfu($call),
+            Shard::Sieve($cache) => Shard::Sieve($call),
+        }
+    };
+}
+
+impl<K, V> ShardCache<K, V> for Shard<K, V>
+where
+    K: Eq + std::hash::Hash + Clone,
+{
+    fn try_add_arc(&mut self, key: K, value: Arc<V>) -> bool
+    {
+        dispatch!(self, cache => cache.try_add_arc(key, value))
+    }
+
+    fn try_add_arc_with_expiry(&mut self, key: K, value: Arc<V>, expiry: Expiry) -> bool
+    {
+        dispatch!(self, cache => cache.try_add_arc_with_expiry(key, value, expiry))
+    }
+
+    fn try_get(&mut self, key: &K) -> Option<Arc<V>>
+    {
+        dispatch!(self, cache => cache.try_get(key))
+    }
+
+    fn try_get_stale(&mut self, key: &K) -> Option<(Arc<V>, EntryState)>
+    {
+        dispatch!(self, cache => cache.try_get_stale(key))
+    }
+
+    fn try_get_stale_if_error(&mut self, key: &K) -> Option<Arc<V>>
+    {
+        dispatch!(self, cache => cache.try_get_stale_if_error(key))
+    }
+
+    fn peek_stale(&self, key: &K) -> Option<(Arc<V>, EntryState)>
+    {
+        dispatch!(self, cache => cache.peek_stale(key))
+    }
+
+    fn remove(&mut self, key: &K) -> bool
+    {
+        dispatch!(self, cache => cache.remove(key))
+    }
+
+    fn remove_tagged(&mut self, tag: &str) -> usize
+    {
+        dispatch!(self, cache => cache.remove_tagged(tag))
+    }
+
+    fn clear(&mut self)
+    {
+        dispatch!(self, cache => cache.clear())
+    }
+
+    fn len(&self) -> usize
+    {
+        dispatch!(self, cache => cache.len())
+    }
+
+    fn contains(&self, key: &K) -> bool
+    {
+        dispatch!(self, cache => cache.contains(key))
+    }
+
+    fn capacity(&self) -> usize
+    {
+        dispatch!(self, cache => cache.capacity())
+    }
+
+    fn entries(&self) -> Box<dyn Iterator<Item = (&K, &Arc<V>, Expiry)> + '_>
+    {
+        dispatch!(self, cache => cache.entries())
+    }
+
+    fn restore(&mut self, key: K, value: Arc<V>, expiry: Expiry) -> bool
+    {
+        dispatch!(self, cache => cache.restore(key, value, expiry))
+    }
+
+    fn weight(&self) -> usize
+    {
+        dispatch!(self, cache => cache.weight())
+    }
+
+    fn remove_expired(&mut self, max_visits: usize) -> (usize, bool)
+    {
+        dispatch!(self, cache => cache.remove_expired(max_visits))
+    }
+
+    fn as_shared(&self) -> Option<&dyn SharedCache<K, V>>
+    {
+        dispatch!(self, cache => cache.as_shared())
+    }
+}
+
+impl<K, V> Shard<K, V>
+where
+    K: Eq + std::hash::Hash + Clone,
+{
+    pub fn new(policy: Policy, max_size: usize, expiration: Duration, expiration_type: ExpirationType) -> Self
+    {
+        match policy {
+            Policy::Probatory(probatory_size) => Shard::Probatory(
+                ProbatoryCache::new(max_size, expiration, expiration_type).with_probatory_size(probatory_size),
+            ),
+            Policy::TinyLfu(sketch_size) => {
+                Shard::TinyLfu(TinyLfuCache::new(max_size, sketch_size, expiration, expiration_type))
+            }
+            Policy::Sieve => {
+                assert!(
+                    expiration_type == ExpirationType::Absolute,
+                    "SIEVE shards don't support sliding expiration, since hits don't change them"
+                );
+                Shard::Sieve(SieveCache::new(max_size, expiration))
+            }
+        }
+    }
+
+    pub fn with_max_weight(self, max_weight: usize, weigher: fn(&V) -> usize) -> Self
+    {
+        map!(self, cache => cache.with_max_weight(max_weight, weigher))
+    }
+
+    pub fn with_tags(self, tagger: fn(&V) -> &[String]) -> Self
+    {
+        map!(self, cache => cache.with_tags(tagger))
+    }
+
+    pub fn with_eviction_listener(self, listener: EvictionListener<K, V>) -> Self
+    {
+        map!(self, cache => cache.with_eviction_listener(listener))
+    }
+
+    pub fn with_stale_while_revalidate(self, stale_while_revalidate: Duration) -> Self
+    {
+        map!(self, cache => cache.with_stale_while_revalidate(stale_while_revalidate))
+    }
+
+    pub fn with_stale_if_error(self, stale_if_error: Duration) -> Self
+    {
+        map!(self, cache => cache.with_stale_if_error(stale_if_error))
+    }
+
+    pub fn with_refresh_ahead(self, ttl_percent: u8, min_hits: u32) -> Self
+    {
+        map!(self, cache => cache.with_refresh_ahead(ttl_percent, min_hits))
+    }
+
+    pub fn with_clock(self, clock: Arc<dyn Clock>) -> Self
+    {
+        map!(self, cache => cache.with_clock(clock))
+    }
+}
@@ -1,90 +1,542 @@
+use std::any::Any;
+use std::collections::HashMap;
 use std::future::Future;
-use std::hash::{DefaultHasher, Hasher};
-use std::sync::{Arc, Mutex};
+use std::hash::{BuildHasher, Hasher};
+use std::marker::PhantomData;
+use std::sync::{Arc, Mutex, RwLock};
 use std::time::Duration;
 
+use gxhash::GxHasher;
+use tokio::sync::watch;
+
 use super::lru::ExpirationType;
-use crate::{Cache, ProbatoryCache};
+use super::shard::Shard;
+use crate::Policy;
+use crate::{Cache, Clock, EntryState, EvictionListener, Expiry, ShardCache, SharedCache};
 
+/// Cache split into shards of cache `C`, each behind its own lock, so that concurrent requests for different keys
+/// rarely wait for each other. Keys are spread across shards by their hash from `S`.
 #[allow(dead_code)]
-pub struct ShardedCache<K, V>
+pub struct ShardedCache<K, V, C = Shard<K, V>, S = PassthroughBuildHasher>
 {
-    shards: Vec<Arc<Mutex<ProbatoryCache<K, V>>>>,
+    shards: Vec<Padded<K, C>>,
+    // Number of shards minus one, which is a power of two
+    mask: usize,
+    hasher: S,
+    // Whether the shards can be looked up while they're only read locked, the same for all of them
+    shared: bool,
+    _value: PhantomData<fn() -> V>,
 }
 
-impl<K, V> Cache<K, V> for ShardedCache<K, V>
-where
-    K: Eq + std::hash::Hash + Clone,
+// A shard and its pending value factories, aligned so that adjacent shards don't share a cache line, and locking one
+// doesn't slow down the cores using the other. 128 bytes covers the prefetcher pulling cache lines in pairs.
+#[repr(align(128))]
+struct Padded<K, C>
+{
+    cache: RwLock<C>,
+    // Values are type-erased `Flight<V, E>` since the error type is only known by the caller of `get_or_add_from_item`.
+    in_flight: Mutex<HashMap<K, Arc<dyn Any + Send + Sync>>>,
+}
+
+/// Builds [`PassthroughHasher`]s.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PassthroughBuildHasher;
+
+impl BuildHasher for PassthroughBuildHasher
 {
-    fn try_add_arc(&mut self, key: K, value: Arc<V>) -> bool
+    type Hasher = PassthroughHasher;
+
+    fn build_hasher(&self) -> PassthroughHasher
     {
-        self.get_shard(&key).lock().unwrap().try_add_arc(key, value)
+        PassthroughHasher::default()
     }
+}
+
+/// Hasher for keys that are already hashes, such as the `u128` cache keys of requests: their bits are used as is.
+/// Any other key is hashed with gxhash.
+#[derive(Default)]
+pub struct PassthroughHasher
+{
+    state: HasherState,
+}
+
+#[derive(Default)]
+enum HasherState
+{
+    #[default]
+    Empty,
+    Passthrough(u64),
+    Hashing(GxHasher),
+}
 
-    fn try_get(&mut self, key: &K) -> Option<Arc<V>>
+impl Hasher for PassthroughHasher
+{
+    fn finish(&self) -> u64
+    {
+        match &self.state {
+            HasherState::Empty => GxHasher::with_seed(123).finish(),
+            HasherState::Passthrough(hash) => *hash,
+            HasherState::Hashing(hasher) => hasher.finish(),
+        }
+    }
+
+    fn write(&mut self, bytes: &[u8])
+    {
+        let hasher = match std::mem::take(&mut self.state) {
+            HasherState::Empty => GxHasher::with_seed(123),
+            HasherState::Passthrough(hash) => {
+                let mut hasher = GxHasher::with_seed(123);
+                hasher.write_u64(hash);
+                hasher
+            }
+            HasherState::Hashing(hasher) => hasher,
+        };
+        self.state = HasherState::Hashing(hasher);
+        if let HasherState::Hashing(hasher) = &mut self.state {
+            hasher.write(bytes);
+        }
+    }
+
+    fn write_u128(&mut self, i: u128)
     {
-        self.get_shard(&key).lock().unwrap().try_get(key)
+        match self.state {
+            // The low bits are the ones picking the shard
+            HasherState::Empty => self.state = HasherState::Passthrough(i as u64),
+            _ => self.write(&i.to_ne_bytes()),
+        }
     }
 }
 
-#[allow(dead_code)]
-impl<K, V> ShardedCache<K, V>
+/// Tells how a value returned by [`Cache::get_or_add_from_item`] was obtained.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Lookup
+{
+    /// The value was already in the cache.
+    Hit,
+    /// The value was produced by the given value factory.
+    Miss,
+    /// The value was produced by the value factory of another caller for the same key, which we waited for.
+    Coalesced,
+    /// The value was in the cache, but is no longer fresh. The caller should refresh it, see
+    /// [`ShardedCache::refresh`].
+    Stale,
+    /// The value was in the cache and is fresh, but it's hot and about to expire. The caller should refresh it, see
+    /// [`ShardedCache::refresh`].
+    RefreshAhead,
+}
+
+impl From<EntryState> for Lookup
+{
+    fn from(state: EntryState) -> Self
+    {
+        match state {
+            EntryState::Fresh => Lookup::Hit,
+            EntryState::RefreshAhead => Lookup::RefreshAhead,
+            EntryState::Stale => Lookup::Stale,
+        }
+    }
+}
+
+type Flight<V, E> = watch::Sender<Option<Result<Arc<V>, E>>>;
+
+enum Role<V, E>
+{
+    Leader(Arc<dyn Any + Send + Sync>),
+    Follower(watch::Receiver<Option<Result<Arc<V>, E>>>),
+    // Another caller is computing the same key with a different error type, we can't wait for it
+    Alone,
+}
+
+/// Unregisters a flight once its leader is done, or when the leader is dropped before completion.
+/// In the latter case, followers notice the sender is gone and retry.
+struct FlightGuard<'a, K>
+where
+    K: Eq + std::hash::Hash,
+{
+    in_flight: &'a Mutex<HashMap<K, Arc<dyn Any + Send + Sync>>>,
+    key: &'a K,
+    flight: Arc<dyn Any + Send + Sync>,
+}
+
+impl<'a, K> Drop for FlightGuard<'a, K>
+where
+    K: Eq + std::hash::Hash,
+{
+    fn drop(&mut self)
+    {
+        let mut in_flight = self.in_flight.lock().unwrap();
+        if in_flight.get(self.key).is_some_and(|f| Arc::ptr_eq(f, &self.flight)) {
+            in_flight.remove(self.key);
+        }
+    }
+}
+
+impl<K, V, C, S> Cache<K, V> for ShardedCache<K, V, C, S>
 where
     K: Eq + std::hash::Hash + Clone,
+    C: ShardCache<K, V>,
+    S: BuildHasher,
 {
-    pub fn try_add_arc2(&self, key: K, value: Arc<V>) -> bool
+    fn try_add_arc(&self, key: K, value: Arc<V>) -> bool
     {
-        self.get_shard(&key).lock().unwrap().try_add_arc(key, value)
+        self.get_shard(&key).write().unwrap().try_add_arc(key, value)
     }
 
-    pub fn try_get2(&self, key: &K) -> Option<Arc<V>>
+    fn try_add_arc_with_expiry(&self, key: K, value: Arc<V>, expiry: Expiry) -> bool
     {
-        self.get_shard(&key).lock().unwrap().try_get(key)
+        self.get_shard(&key).write().unwrap().try_add_arc_with_expiry(key, value, expiry)
     }
 
-    pub fn new(shards: usize, max_size: usize, expiration: Duration, expiration_type: ExpirationType) -> Self
+    /// Only read locks the shard when its cache allows it, see [`ShardCache::as_shared`].
+    fn try_get(&self, key: &K) -> Option<Arc<V>>
     {
-        Self {
-            shards: (0..shards)
-                .map(|_| Arc::new(Mutex::new(ProbatoryCache::new(max_size, expiration, expiration_type))))
-                .collect(),
+        self.lookup(key, |cache| cache.get(key), |cache| cache.try_get(key))
+    }
+
+    /// Only read locks the shard when its cache allows it, see [`ShardCache::as_shared`].
+    fn try_get_stale(&self, key: &K) -> Option<(Arc<V>, EntryState)>
+    {
+        self.lookup(key, |cache| cache.get_stale(key), |cache| cache.try_get_stale(key))
+    }
+
+    /// Only read locks the shard when its cache allows it, see [`ShardCache::as_shared`].
+    fn try_get_stale_if_error(&self, key: &K) -> Option<Arc<V>>
+    {
+        self.lookup(key, |cache| cache.get_stale_if_error(key), |cache| cache.try_get_stale_if_error(key))
+    }
+
+    fn remove(&self, key: &K) -> bool
+    {
+        self.get_shard(key).write().unwrap().remove(key)
+    }
+
+    /// Removes the entries carrying the tag from all shards, one at a time, and returns how many there were.
+    fn remove_tagged(&self, tag: &str) -> usize
+    {
+        self.shards.iter().map(|shard| shard.cache.write().unwrap().remove_tagged(tag)).sum()
+    }
+
+    /// Clears the shards one at a time, so entries added meanwhile to the shards already cleared are kept.
+    fn clear(&self)
+    {
+        for shard in &self.shards {
+            shard.cache.write().unwrap().clear();
         }
     }
 
-    fn get_shard(&self, key: &K) -> &Arc<Mutex<ProbatoryCache<K, V>>>
+    fn len(&self) -> usize
+    {
+        self.shards.iter().map(|shard| shard.cache.read().unwrap().len()).sum()
+    }
+
+    fn capacity(&self) -> usize
     {
-        let mut hasher = DefaultHasher::new();
-        key.hash(&mut hasher);
-        let hash = hasher.finish() as usize;
-        let shard = hash % self.shards.len();
-        &self.shards[shard]
+        self.shards.iter().map(|shard| shard.cache.read().unwrap().capacity()).sum()
     }
 
-    pub async fn get_or_add_from_item2<I, Kfac, Vfac, Fut, E>(
+    fn contains(&self, key: &K) -> bool
+    {
+        self.get_shard(key).read().unwrap().contains(key)
+    }
+
+    /// Collects the keys of one shard at a time, so a shard is only locked while its keys are collected.
+    fn iter_keys(&self) -> impl Iterator<Item = K> + '_
+    {
+        self.shards.iter().flat_map(|shard| {
+            let cache = shard.cache.read().unwrap();
+            cache.entries().map(|(key, _, _)| key.clone()).collect::<Vec<_>>()
+        })
+    }
+
+    /// Concurrent misses on the same key are coalesced: the first caller runs its value factory while the others
+    /// wait for its outcome, whether it's a value or an error.
+    async fn get_or_add_from_item<I, Kfac, Vfac, Fut, E>(
         &self, item: I, key_factory: Kfac, value_factory: Vfac,
-    ) -> Result<Arc<V>, E>
+    ) -> Result<(Arc<V>, Lookup), E>
     where
         K: Clone,
+        V: Send + Sync + 'static,
+        E: Clone + Send + Sync + 'static,
         Kfac: Fn(&I) -> K,
         Vfac: FnOnce(I) -> Fut,
-        Fut: Future<Output = Result<V, E>>,
+        Fut: Future<Output = Result<(V, Option<Expiry>), E>>,
     {
         let key = key_factory(&item);
-        match self.try_get2(&key) {
-            Some(value) => return Ok(value),
-            None => {
-                match value_factory(item).await {
-                    Ok(value) => {
-                        let a_value = Arc::new(value);
-                        // This might fail if the key was added by another thread, but we don't care
-                        // This is preferred over blocking the cache during the whole factory call duration
-                        self.try_add_arc2(key.clone(), a_value.clone());
-                        Ok(a_value)
+        if let Some((value, state)) = self.try_get_stale(&key) {
+            return Ok((value, state.into()));
+        }
+
+        let in_flight = &self.shards[self.get_shard_index(&key)].in_flight;
+        let flight = loop {
+            let role = {
+                let mut in_flight = in_flight.lock().unwrap();
+                match in_flight.get(&key) {
+                    Some(flight) => match flight.downcast_ref::<Flight<V, E>>() {
+                        Some(flight) => Role::Follower(flight.subscribe()),
+                        None => Role::Alone,
+                    },
+                    None => {
+                        // The previous leader might have filled the cache in between. Only peeks, since the miss was
+                        // already counted, by policies estimating how often keys are looked up in particular.
+                        if let Some((value, state)) = self.get_shard(&key).read().unwrap().peek_stale(&key) {
+                            return Ok((value, state.into()));
+                        }
+                        let flight: Arc<dyn Any + Send + Sync> = Arc::new(Flight::<V, E>::new(None));
+                        in_flight.insert(key.clone(), flight.clone());
+                        Role::Leader(flight)
+                    }
+                }
+            };
+
+            match role {
+                Role::Leader(flight) => break Some(flight),
+                Role::Alone => break None,
+                Role::Follower(mut receiver) => {
+                    if let Ok(result) = receiver.wait_for(Option::is_some).await {
+                        let result = result.clone().expect("Flight completed without a result");
+                        return result.map(|value| (value, Lookup::Coalesced));
                     }
-                    Err(e) => Err(e),
+                    // The leader was dropped before completing (eg. client disconnected), try again
                 }
             }
+        };
+
+        self.lead(&key, flight, value_factory(item))
+            .await
+            .map(|value| (value, Lookup::Miss))
+    }
+}
+
+#[allow(dead_code)]
+impl<K, V, C, S> ShardedCache<K, V, C, S>
+where
+    K: Eq + std::hash::Hash + Clone,
+    C: ShardCache<K, V>,
+    S: BuildHasher,
+{
+    /// Makes a cache of `shards` caches made by the factory, rounded up to a power of two, whose keys are spread by
+    /// their hash from the given hasher.
+    pub fn with_hasher(shards: usize, hasher: S, factory: impl Fn() -> C) -> Self
+    {
+        let shards: Vec<_> = (0..shards.max(1).next_power_of_two())
+            .map(|_| Padded {
+                cache: RwLock::new(factory()),
+                in_flight: Mutex::new(HashMap::new()),
+            })
+            .collect();
+        Self {
+            mask: shards.len() - 1,
+            shared: Self::is_shared(&shards),
+            shards,
+            hasher,
+            _value: PhantomData,
+        }
+    }
+
+    /// Returns the resident entries of all shards, one shard at a time, each from the least to the most recently used.
+    /// See [`ShardCache::entries`].
+    pub fn entries(&self) -> Vec<(K, Arc<V>, Expiry)>
+    {
+        let mut entries = Vec::new();
+        for shard in &self.shards {
+            let shard = shard.cache.read().unwrap();
+            entries.extend(shard.entries().map(|(key, value, expiry)| (key.clone(), value.clone(), expiry)));
+        }
+        entries
+    }
+
+    /// Adds an entry without going through admission. Restoring entries in the order of [`ShardedCache::entries`]
+    /// keeps them in the same order of use.
+    pub fn restore(&self, key: K, value: Arc<V>, expiry: Expiry) -> bool
+    {
+        self.get_shard(&key).write().unwrap().restore(key, value, expiry)
+    }
+
+    /// Removes the expired entries of all shards, visiting at most `batch` keys each time a shard is locked, and
+    /// returns how many were removed. See [`ShardCache::remove_expired`].
+    pub async fn remove_expired(&self, batch: usize) -> usize
+    {
+        let mut removed = 0;
+        for shard in &self.shards {
+            loop {
+                let (shard_removed, more) = shard.cache.write().unwrap().remove_expired(batch);
+                removed += shard_removed;
+                if !more {
+                    break;
+                }
+                // Let the requests waiting for the shard go first
+                tokio::task::yield_now().await;
+            }
         }
+        removed
+    }
+
+    /// Returns the total weight of the values in each shard.
+    pub fn shard_weights(&self) -> Vec<usize>
+    {
+        self.shards.iter().map(|shard| shard.cache.read().unwrap().weight()).collect()
+    }
+
+    fn get_shard_index(&self, key: &K) -> usize
+    {
+        self.hasher.hash_one(key) as usize & self.mask
+    }
+
+    fn get_shard(&self, key: &K) -> &RwLock<C>
+    {
+        &self.shards[self.get_shard_index(key)].cache
+    }
+
+    fn is_shared(shards: &[Padded<K, C>]) -> bool
+    {
+        shards.iter().all(|shard| shard.cache.read().unwrap().as_shared().is_some())
+    }
+
+    // Looks the key up with the first function if its shard can be while it's read locked, or with the second one
+    fn lookup<R>(
+        &self, key: &K, shared: impl FnOnce(&dyn SharedCache<K, V>) -> R, exclusive: impl FnOnce(&mut C) -> R,
+    ) -> R
+    {
+        let shard = self.get_shard(key);
+        match self.shared {
+            true => shared(shard.read().unwrap().as_shared().expect("Shards should all be shared alike")),
+            false => exclusive(&mut shard.write().unwrap()),
+        }
+    }
+
+    /// Recomputes the value of a stale key, or one due for a refresh ahead, with the value factory, and replaces it in
+    /// the cache.
+    /// Only one value factory runs at a time for a key: returns `None` without running it if the key is already being
+    /// refreshed, or computed after a miss.
+    pub async fn refresh<Vfac, Fut, E>(&self, key: K, value_factory: Vfac) -> Option<Result<Arc<V>, E>>
+    where
+        V: Send + Sync + 'static,
+        E: Clone + Send + Sync + 'static,
+        Vfac: FnOnce() -> Fut,
+        Fut: Future<Output = Result<(V, Option<Expiry>), E>>,
+    {
+        let flight: Arc<dyn Any + Send + Sync> = Arc::new(Flight::<V, E>::new(None));
+        {
+            let mut in_flight = self.shards[self.get_shard_index(&key)].in_flight.lock().unwrap();
+            if in_flight.contains_key(&key) {
+                return None;
+            }
+            in_flight.insert(key.clone(), flight.clone());
+        }
+
+        Some(self.lead(&key, Some(flight), value_factory()).await)
+    }
+
+    /// Runs the value factory future registered as the given flight, caches its value and shares its outcome with
+    /// the callers waiting for it.
+    async fn lead<Fut, E>(&self, key: &K, flight: Option<Arc<dyn Any + Send + Sync>>, value: Fut) -> Result<Arc<V>, E>
+    where
+        V: Send + Sync + 'static,
+        E: Clone + Send + Sync + 'static,
+        Fut: Future<Output = Result<(V, Option<Expiry>), E>>,
+    {
+        let _guard = flight.as_ref().map(|flight| FlightGuard {
+            in_flight: &self.shards[self.get_shard_index(key)].in_flight,
+            key,
+            flight: flight.clone(),
+        });
+
+        let result = value.await.map(|(value, expiry)| {
+            let value = Arc::new(value);
+            if let Some(expiry) = expiry {
+                // This might fail if the key was added in between, but we don't care
+                self.get_shard(key).write().unwrap().try_add_arc_with_expiry(key.clone(), value.clone(), expiry);
+            }
+            value
+        });
+
+        if let Some(flight) = flight.as_ref().and_then(|f| f.downcast_ref::<Flight<V, E>>()) {
+            flight.send_replace(Some(result.clone()));
+        }
+
+        result
+    }
+}
+
+#[allow(dead_code)]
+impl<K, V> ShardedCache<K, V>
+where
+    K: Eq + std::hash::Hash + Clone,
+{
+    /// Makes a cache of `shards` [`ProbatoryCache`](crate::ProbatoryCache) of `max_size` entries each. The number of
+    /// shards is rounded up to a power of two.
+    pub fn new(shards: usize, max_size: usize, expiration: Duration, expiration_type: ExpirationType) -> Self
+    {
+        Self::new_with_policy(shards, max_size, Policy::Probatory(10 * max_size), expiration, expiration_type)
+    }
+
+    /// Same as [`ShardedCache::new`], with the given policy in each shard.
+    pub fn new_with_policy(
+        shards: usize, max_size: usize, policy: Policy, expiration: Duration, expiration_type: ExpirationType,
+    ) -> Self
+    {
+        Self::with_hasher(shards, PassthroughBuildHasher, || {
+            Shard::new(policy, max_size, expiration, expiration_type)
+        })
+    }
+
+    /// Bounds the cache by the total weight of its values, evenly split across shards.
+    /// See [`LruCache::with_max_weight`](crate::LruCache::with_max_weight).
+    pub fn with_max_weight(self, max_weight: usize, weigher: fn(&V) -> usize) -> Self
+    {
+        let shard_max_weight = max_weight / self.shards.len();
+        self.map_shards(|shard| shard.with_max_weight(shard_max_weight, weigher))
+    }
+
+    /// See [`LruCache::with_tags`](crate::LruCache::with_tags).
+    pub fn with_tags(self, tagger: fn(&V) -> &[String]) -> Self
+    {
+        self.map_shards(|shard| shard.with_tags(tagger))
+    }
+
+    /// See [`ProbatoryCache::with_eviction_listener`](crate::ProbatoryCache::with_eviction_listener) and
+    /// [`TinyLfuCache::with_eviction_listener`](crate::TinyLfuCache::with_eviction_listener).
+    pub fn with_eviction_listener(self, listener: EvictionListener<K, V>) -> Self
+    {
+        self.map_shards(|shard| shard.with_eviction_listener(listener.clone()))
+    }
+
+    /// See [`LruCache::with_stale_while_revalidate`](crate::LruCache::with_stale_while_revalidate).
+    pub fn with_stale_while_revalidate(self, stale_while_revalidate: Duration) -> Self
+    {
+        self.map_shards(|shard| shard.with_stale_while_revalidate(stale_while_revalidate))
+    }
+
+    /// See [`LruCache::with_stale_if_error`](crate::LruCache::with_stale_if_error).
+    pub fn with_stale_if_error(self, stale_if_error: Duration) -> Self
+    {
+        self.map_shards(|shard| shard.with_stale_if_error(stale_if_error))
+    }
+
+    /// See [`LruCache::with_refresh_ahead`](crate::LruCache::with_refresh_ahead).
+    pub fn with_refresh_ahead(self, ttl_percent: u8, min_hits: u32) -> Self
+    {
+        self.map_shards(|shard| shard.with_refresh_ahead(ttl_percent, min_hits))
+    }
+
+    /// Gives the same clock to all shards. See [`LruCache::with_clock`](crate::LruCache::with_clock).
+    pub fn with_clock(self, clock: Arc<dyn Clock>) -> Self
+    {
+        self.map_shards(|shard| shard.with_clock(clock.clone()))
+    }
+
+    fn map_shards(mut self, f: impl Fn(Shard<K, V>) -> Shard<K, V>) -> Self
+    {
+        self.shards = self
+            .shards
+            .into_iter()
+            .map(|shard| Padded {
+                cache: RwLock::new(f(shard.cache.into_inner().unwrap())),
+                in_flight: shard.in_flight,
+            })
+            .collect();
+        self.shared = Self::is_shared(&self.shards);
+        self
     }
 }
 
@@ -92,11 +544,12 @@ where
 mod tests
 {
     use super::*;
+    use crate::ManualClock;
 
     #[test]
     fn basic()
     {
-        let mut lru = ShardedCache::new(1, 4, Duration::MAX, ExpirationType::Absolute);
+        let lru = ShardedCache::new(1, 4, Duration::MAX, ExpirationType::Absolute);
         assert!(lru.try_get(&1).is_none());
         assert!(lru.try_add(1, "hello"));
         assert!(lru.try_get(&1).is_none(), "Key should only be in the probatory cache");
@@ -108,7 +561,7 @@ mod tests
     #[test]
     fn trimming()
     {
-        let mut lru = ShardedCache::new(1, 4, Duration::MAX, ExpirationType::Absolute);
+        let lru = ShardedCache::new(1, 4, Duration::MAX, ExpirationType::Absolute);
         // Add every entry twice for them to enter the resident cache
         assert!(lru.try_add(1, "h"));
         assert!(lru.try_add(1, "h"));
@@ -127,4 +580,192 @@ mod tests
         assert!(lru.try_get(&4).is_some());
         assert!(lru.try_get(&5).is_some());
     }
+
+    #[test]
+    fn tiny_lfu()
+    {
+        let policy = Policy::TinyLfu(100);
+        let lru = ShardedCache::new_with_policy(2, 4, policy, Duration::MAX, ExpirationType::Absolute);
+        assert!(lru.try_add(1, "hello"));
+        assert!(lru.try_get(&1).is_some(), "Key should be admitted to the window right away");
+        assert!(!lru.try_add(1, "hello"));
+        assert_eq!(lru.len(), 1);
+    }
+
+    #[test]
+    fn sieve()
+    {
+        let lru = ShardedCache::new_with_policy(2, 4, Policy::Sieve, Duration::MAX, ExpirationType::Absolute);
+        assert!(lru.shared, "SIEVE shards should be looked up while read locked");
+        assert!(lru.try_add_arc(1, Arc::new("hello")));
+        assert_eq!(lru.try_get_stale(&1), Some((Arc::new("hello"), EntryState::Fresh)));
+        assert!(lru.remove(&1));
+        assert!(lru.try_get(&1).is_none());
+    }
+
+    #[test]
+    #[should_panic(expected = "sliding expiration")]
+    fn sieve_sliding()
+    {
+        ShardedCache::<u32, &str>::new_with_policy(1, 4, Policy::Sieve, Duration::MAX, ExpirationType::Sliding);
+    }
+
+    #[test]
+    fn lru_shards()
+    {
+        let lru = ShardedCache::with_hasher(3, std::hash::RandomState::new(), || {
+            crate::LruCache::new(4, Duration::MAX, ExpirationType::Absolute)
+        });
+        assert_eq!(lru.shard_weights().len(), 4, "Shards should be rounded up to a power of two");
+        assert!(!lru.shared);
+        assert!(lru.try_add(1, "hello"));
+        assert!(lru.try_get(&1).is_some(), "Key should be cached right away");
+        assert!(!lru.try_add(1, "hello"));
+        assert_eq!(lru.len(), 1);
+    }
+
+    #[test]
+    fn introspection()
+    {
+        let lru = ShardedCache::new_with_policy(2, 4, Policy::Sieve, Duration::MAX, ExpirationType::Absolute);
+        assert_eq!(lru.capacity(), 8);
+        for key in 1..=3 {
+            assert!(lru.try_add(key, "hello"));
+        }
+        assert_eq!(lru.len(), 3);
+        let mut keys: Vec<_> = lru.iter_keys().collect();
+        keys.sort();
+        assert_eq!(keys, vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn passthrough_hasher()
+    {
+        let hasher = PassthroughBuildHasher;
+        assert_eq!(hasher.hash_one(0x1234_5678_0000_0000_0000_0000_0000_0042_u128), 0x42);
+        // Other keys are hashed
+        assert_ne!(hasher.hash_one(0x42_u64), 0x42);
+        assert_eq!(hasher.hash_one("hello"), hasher.hash_one("hello"));
+        assert_ne!(hasher.hash_one((1_u128, 2_u128)), hasher.hash_one((2_u128, 1_u128)));
+    }
+
+    #[tokio::test]
+    async fn coalescing()
+    {
+        let cache = Arc::new(ShardedCache::new(1, 4, Duration::MAX, ExpirationType::Absolute));
+        let calls = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+
+        let tasks: Vec<_> = (0..8)
+            .map(|_| {
+                let cache = cache.clone();
+                let calls = calls.clone();
+                tokio::spawn(async move {
+                    cache
+                        .get_or_add_from_item(1, |k| *k, |k| async move {
+                            calls.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                            tokio::time::sleep(Duration::from_millis(50)).await;
+                            Ok::<_, ()>((k * 10, Some(Expiry::new(Duration::MAX))))
+                        })
+                        .await
+                })
+            })
+            .collect();
+
+        let mut lookups = Vec::new();
+        for task in tasks {
+            let (value, lookup) = task.await.unwrap().unwrap();
+            assert_eq!(*value, 10);
+            lookups.push(lookup);
+        }
+
+        assert_eq!(calls.load(std::sync::atomic::Ordering::Relaxed), 1, "Factory should only run once");
+        assert_eq!(lookups.iter().filter(|l| **l == Lookup::Miss).count(), 1);
+        assert_eq!(lookups.iter().filter(|l| **l == Lookup::Coalesced).count(), 7);
+    }
+
+    #[tokio::test]
+    async fn coalescing_error()
+    {
+        let cache = Arc::new(ShardedCache::<i32, i32>::new(1, 4, Duration::MAX, ExpirationType::Absolute));
+
+        let leader = {
+            let cache = cache.clone();
+            tokio::spawn(async move {
+                cache
+                    .get_or_add_from_item(1, |k| *k, |_| async move {
+                        tokio::time::sleep(Duration::from_millis(50)).await;
+                        Err::<(i32, _), _>("boom")
+                    })
+                    .await
+            })
+        };
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        let follower = cache
+            .get_or_add_from_item(1, |k| *k, |_| async move { Ok::<_, &str>((0, Some(Expiry::new(Duration::MAX)))) })
+            .await;
+
+        assert_eq!(follower.unwrap_err(), "boom");
+        assert_eq!(leader.await.unwrap().unwrap_err(), "boom");
+    }
+
+    #[tokio::test]
+    async fn miss_counted_once()
+    {
+        let cache = ShardedCache::with_hasher(1, PassthroughBuildHasher, || {
+            crate::TinyLfuCache::new(4, 100, Duration::MAX, ExpirationType::Absolute)
+        });
+        let (_, lookup) = cache
+            .get_or_add(1, |_| async { Ok::<_, ()>(("h", Some(Expiry::new(Duration::MAX)))) })
+            .await
+            .unwrap();
+        assert_eq!(lookup, Lookup::Miss);
+        assert_eq!(cache.shards[0].cache.read().unwrap().sketch.frequency(&1), 1);
+    }
+
+    #[tokio::test]
+    async fn stale_while_revalidate()
+    {
+        let clock = Arc::new(ManualClock::new());
+        let cache = Arc::new(
+            ShardedCache::<i32, i32>::new(1, 4, Duration::from_millis(10), ExpirationType::Absolute)
+                .with_stale_while_revalidate(Duration::MAX)
+                .with_clock(clock.clone()),
+        );
+        let get = |value| {
+            let cache = cache.clone();
+            async move {
+                let expiry = Expiry::new(Duration::from_millis(10)).with_stale_while_revalidate(Duration::MAX);
+                cache
+                    .get_or_add_from_item(1, |k| *k, |_| async move { Ok::<_, ()>((value, Some(expiry))) })
+                    .await
+                    .unwrap()
+            }
+        };
+        // Twice to get through the probatory cache
+        get(1).await;
+        get(1).await;
+        clock.advance(Duration::from_millis(20));
+
+        // Stale value is returned as is
+        assert_eq!(get(2).await, (Arc::new(1), Lookup::Stale));
+
+        // Only one refresh at a time
+        let refresh = {
+            let cache = cache.clone();
+            tokio::spawn(async move {
+                cache
+                    .refresh(1, || async {
+                        tokio::time::sleep(Duration::from_millis(50)).await;
+                        Ok::<_, ()>((3, Some(Expiry::new(Duration::MAX))))
+                    })
+                    .await
+            })
+        };
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert!(cache.refresh(1, || async { Ok::<_, ()>((4, None)) }).await.is_none());
+        assert_eq!(get(5).await, (Arc::new(1), Lookup::Stale));
+
+        assert_eq!(refresh.await.unwrap(), Some(Ok(Arc::new(3))));
+        assert_eq!(get(6).await, (Arc::new(3), Lookup::Hit));
+    }
 }
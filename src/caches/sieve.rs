@@ -0,0 +1,364 @@
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+
+use super::entries::Entries;
+use crate::{ArenaLinkedList, Clock, EntryState, EvictionListener, Expiry, ShardCache, SharedCache};
+
+/// SIEVE cache: entries stay in the order they were added, and a hit only marks the entry as visited, so lookups don't
+/// need the cache to be borrowed mutably. To evict, a hand goes from the oldest entries to the newest, sparing and
+/// unmarking the visited ones, and picks up where it left off the next time.
+/// Entries expire a fixed time after they were added.
+pub struct SieveCache<K, V>
+{
+    // From the oldest to the newest entry
+    list: ArenaLinkedList<K>,
+    entries: Entries<K, V>,
+    // Node of the next entry to consider for eviction, the oldest one when unset
+    hand: Option<usize>,
+}
+
+impl<K, V> ShardCache<K, V> for SieveCache<K, V>
+where
+    K: Eq + std::hash::Hash + Clone,
+{
+    fn try_add_arc(&mut self, key: K, value: Arc<V>) -> bool
+    {
+        self.try_add_arc_with_expiry(key, value, self.default_expiry())
+    }
+
+    fn try_add_arc_with_expiry(&mut self, key: K, value: Arc<V>, expiry: Expiry) -> bool
+    {
+        let weight = self.entries.weigh(&value);
+        if weight > self.entries.max_weight() {
+            // Would evict everything else and still not fit
+            return false;
+        }
+
+        let now = self.entries.now();
+        let node_index = match self.entries.get(&key) {
+            // A stale entry, or one due for a refresh ahead, is being refreshed, replace it where it is
+            Some(entry) if entry.is_refreshable(now) => entry.node_index,
+            Some(_) => return false,
+            None => {
+                // Evicts before adding, so that the hand doesn't get to the new entry first
+                self.make_room(1, weight);
+                self.list.add_last(key.clone()).expect("Failed to add node to list")
+            }
+        };
+        self.entries.insert(key, node_index, value, weight, expiry, now);
+        self.make_room(0, 0);
+        // Keeps the expiration index from piling up keys of entries that are gone, even if it's never swept
+        self.remove_expired(2);
+        true
+    }
+
+    fn try_get(&mut self, key: &K) -> Option<Arc<V>>
+    {
+        self.get(key)
+    }
+
+    fn try_get_stale(&mut self, key: &K) -> Option<(Arc<V>, EntryState)>
+    {
+        self.get_stale(key)
+    }
+
+    fn try_get_stale_if_error(&mut self, key: &K) -> Option<Arc<V>>
+    {
+        self.get_stale_if_error(key)
+    }
+
+    fn peek_stale(&self, key: &K) -> Option<(Arc<V>, EntryState)>
+    {
+        self.entries.peek(key)
+    }
+
+    fn remove(&mut self, key: &K) -> bool
+    {
+        match self.entries.remove(key) {
+            Some(entry) => {
+                unlink(&mut self.list, &mut self.hand, entry.node_index);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn remove_tagged(&mut self, tag: &str) -> usize
+    {
+        let (list, hand) = (&mut self.list, &mut self.hand);
+        self.entries.remove_tagged(tag, |index| unlink(list, hand, index))
+    }
+
+    fn clear(&mut self)
+    {
+        self.entries.clear();
+        self.list.clear();
+        self.hand = None;
+    }
+
+    fn len(&self) -> usize
+    {
+        self.entries.len()
+    }
+
+    fn contains(&self, key: &K) -> bool
+    {
+        self.entries.contains(key)
+    }
+
+    fn capacity(&self) -> usize
+    {
+        self.entries.capacity()
+    }
+
+    /// Returns the entries from the oldest to the newest, with what's left of their expiry. Expired entries are
+    /// skipped.
+    fn entries(&self) -> Box<dyn Iterator<Item = (&K, &Arc<V>, Expiry)> + '_>
+    {
+        self.entries.iter(self.list.iter())
+    }
+
+    /// Adds an entry, such as one that was cached before a restart.
+    fn restore(&mut self, key: K, value: Arc<V>, expiry: Expiry) -> bool
+    {
+        self.try_add_arc_with_expiry(key, value, expiry)
+    }
+
+    /// Returns the total weight of the values currently in the cache.
+    fn weight(&self) -> usize
+    {
+        self.entries.weight()
+    }
+
+    /// See [`LruCache::remove_expired`](crate::LruCache::remove_expired).
+    fn remove_expired(&mut self, max_visits: usize) -> (usize, bool)
+    {
+        let (list, hand) = (&mut self.list, &mut self.hand);
+        self.entries.remove_expired(max_visits, |index| unlink(list, hand, index))
+    }
+
+    fn as_shared(&self) -> Option<&dyn SharedCache<K, V>>
+    {
+        Some(self)
+    }
+}
+
+impl<K, V> SharedCache<K, V> for SieveCache<K, V>
+where
+    K: Eq + std::hash::Hash + Clone,
+{
+    fn get(&self, key: &K) -> Option<Arc<V>>
+    {
+        self.lookup(key, |_| Duration::ZERO).map(|(value, _)| value)
+    }
+
+    fn get_stale(&self, key: &K) -> Option<(Arc<V>, EntryState)>
+    {
+        self.lookup(key, |expiry| expiry.stale_while_revalidate)
+    }
+
+    fn get_stale_if_error(&self, key: &K) -> Option<Arc<V>>
+    {
+        self.lookup(key, |expiry| expiry.stale_if_error).map(|(value, _)| value)
+    }
+}
+
+impl<K, V> SieveCache<K, V>
+where
+    K: Eq + std::hash::Hash + Clone,
+{
+    pub fn new(max_size: usize, expiration: Duration) -> Self
+    {
+        Self {
+            list: ArenaLinkedList::new_with_capacity(max_size),
+            entries: Entries::new(max_size, expiration),
+            hand: None,
+        }
+    }
+
+    /// See [`LruCache::with_max_weight`](crate::LruCache::with_max_weight).
+    pub fn with_max_weight(mut self, max_weight: usize, weigher: fn(&V) -> usize) -> Self
+    {
+        self.entries = self.entries.with_max_weight(max_weight, weigher);
+        self
+    }
+
+    /// See [`LruCache::with_tags`](crate::LruCache::with_tags).
+    pub fn with_tags(mut self, tagger: fn(&V) -> &[String]) -> Self
+    {
+        self.entries = self.entries.with_tags(tagger);
+        self
+    }
+
+    /// See [`LruCache::with_eviction_listener`](crate::LruCache::with_eviction_listener).
+    pub fn with_eviction_listener(mut self, listener: EvictionListener<K, V>) -> Self
+    {
+        self.entries = self.entries.with_eviction_listener(listener);
+        self
+    }
+
+    /// See [`LruCache::with_stale_while_revalidate`](crate::LruCache::with_stale_while_revalidate).
+    pub fn with_stale_while_revalidate(mut self, stale_while_revalidate: Duration) -> Self
+    {
+        self.entries = self.entries.with_stale_while_revalidate(stale_while_revalidate);
+        self
+    }
+
+    /// See [`LruCache::with_stale_if_error`](crate::LruCache::with_stale_if_error).
+    pub fn with_stale_if_error(mut self, stale_if_error: Duration) -> Self
+    {
+        self.entries = self.entries.with_stale_if_error(stale_if_error);
+        self
+    }
+
+    /// See [`LruCache::with_refresh_ahead`](crate::LruCache::with_refresh_ahead).
+    pub fn with_refresh_ahead(mut self, ttl_percent: u8, min_hits: u32) -> Self
+    {
+        self.entries = self.entries.with_refresh_ahead(ttl_percent, min_hits);
+        self
+    }
+
+    /// See [`LruCache::with_clock`](crate::LruCache::with_clock).
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self
+    {
+        self.entries = self.entries.with_clock(clock);
+        self
+    }
+
+    /// Returns the default expiry of the entries.
+    pub fn default_expiry(&self) -> Expiry
+    {
+        self.entries.default_expiry()
+    }
+
+    // Returns the value if it's fresh or within the given stale window, and its state, and marks it as visited
+    fn lookup(&self, key: &K, stale_window: fn(&Expiry) -> Duration) -> Option<(Arc<V>, EntryState)>
+    {
+        // Expired entries are left for the hand or the sweeper to remove
+        let (entry, state) = self.entries.hit(key, self.entries.now(), stale_window)?;
+        // Only writes when needed, since concurrent lookups of hot entries would contend on their cache lines
+        if !entry.visited.load(Ordering::Relaxed) {
+            entry.visited.store(true, Ordering::Relaxed);
+        }
+        Some((entry.value.clone(), state))
+    }
+
+    // Evicts entries with the hand until the given number of entries and weight fit within the bounds of the cache
+    fn make_room(&mut self, entries: usize, weight: usize)
+    {
+        let now = self.entries.now();
+        while !self.entries.fits(entries, weight) {
+            let Some(index) = self.hand.or_else(|| self.list.get_first_index().ok()) else {
+                break;
+            };
+            let node = self.list.get(index).expect("Failed to get node, cache is likely corrupted");
+            let key = node
+                .get_value()
+                .as_ref()
+                .expect("Node has no value, cache is likely corrupted")
+                .clone();
+            let entry = self
+                .entries
+                .get(&key)
+                .expect("Node not found in map, cache is likely corrupted");
+            if entry.remaining(now).is_some() && entry.visited.swap(false, Ordering::Relaxed) {
+                // Spared until the hand comes back to it
+                let next_index = node.get_after_index();
+                self.hand = (next_index != usize::MAX).then_some(next_index);
+                continue;
+            }
+
+            if let Some(index) = self.entries.evict(key, now) {
+                unlink(&mut self.list, &mut self.hand, index);
+            }
+        }
+    }
+}
+
+// Removes the node from the list, moving the hand past it first
+fn unlink<K>(list: &mut ArenaLinkedList<K>, hand: &mut Option<usize>, index: usize)
+{
+    if *hand == Some(index) {
+        let next_index = list
+            .get(index)
+            .expect("Failed to get node, cache is likely corrupted")
+            .get_after_index();
+        *hand = (next_index != usize::MAX).then_some(next_index);
+    }
+    list.remove(index).expect("Failed to remove node, cache is likely corrupted");
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::{Evicted, ManualClock};
+
+    #[test]
+    fn basic()
+    {
+        let mut sieve = SieveCache::new(4, Duration::MAX);
+        assert!(sieve.get(&1).is_none());
+        assert!(sieve.try_add(1, "hello"));
+        assert!(!sieve.try_add(1, "hello"));
+        assert!(sieve.get(&1).is_some());
+        assert!(sieve.remove(&1));
+        assert!(sieve.is_empty());
+    }
+
+    #[test]
+    fn eviction()
+    {
+        let evicted = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let mut sieve = SieveCache::new(3, Duration::MAX).with_eviction_listener({
+            let evicted = evicted.clone();
+            Arc::new(move |entry: Evicted<u32, &str>| evicted.lock().unwrap().push(entry.key))
+        });
+        assert!(sieve.try_add(1, "h"));
+        assert!(sieve.try_add(2, "e"));
+        assert!(sieve.try_add(3, "l"));
+        assert!(sieve.get(&1).is_some());
+        assert!(sieve.get(&3).is_some());
+        // 1 is spared, 2 wasn't visited
+        assert!(sieve.try_add(4, "l"));
+        assert_eq!(*evicted.lock().unwrap(), vec![2]);
+        // The hand goes on from 3, which is spared, to 4
+        assert!(sieve.try_add(5, "o"));
+        assert_eq!(*evicted.lock().unwrap(), vec![2, 4]);
+        // Then wraps around to 1, no longer visited
+        assert!(sieve.try_add(6, "!"));
+        assert_eq!(*evicted.lock().unwrap(), vec![2, 4, 1]);
+        assert!(sieve.contains(&3));
+        assert!(sieve.contains(&5));
+        assert!(sieve.contains(&6));
+    }
+
+    #[test]
+    fn expiration()
+    {
+        let clock = Arc::new(ManualClock::new());
+        let mut sieve = SieveCache::new(4, Duration::from_millis(10))
+            .with_stale_while_revalidate(Duration::MAX)
+            .with_clock(clock.clone());
+        assert!(sieve.try_add(1, "h"));
+        clock.advance(Duration::from_millis(20));
+        assert!(sieve.get(&1).is_none());
+        assert_eq!(sieve.get_stale(&1), Some((Arc::new("h"), EntryState::Stale)));
+        // Stale entry is replaced
+        assert!(sieve.try_add_arc_with_expiry(1, Arc::new("e"), Expiry::new(Duration::MAX)));
+        assert_eq!(sieve.get(&1), Some(Arc::new("e")));
+        assert_eq!(sieve.len(), 1);
+    }
+
+    #[test]
+    fn tagging()
+    {
+        let mut sieve = SieveCache::new(4, Duration::MAX).with_tags(|v: &Vec<String>| v.as_slice());
+        assert!(sieve.try_add(1, vec!["a".to_string(), "b".to_string()]));
+        assert!(sieve.try_add(2, vec!["b".to_string()]));
+        assert_eq!(sieve.remove_tagged("a"), 1);
+        assert_eq!(sieve.remove_tagged("b"), 1);
+        assert!(sieve.is_empty());
+    }
+}
@@ -0,0 +1,460 @@
+use std::{sync::Arc, time::Duration};
+
+use super::lru::ExpirationType;
+use crate::{Clock, EntryState, Evicted, EvictionListener, Expiry, FrequencySketch, LruCache, ShardCache};
+
+// Share of the entries in the window segment
+const WINDOW_PERCENT: usize = 1;
+// Share of the main segment kept for the entries hit again since they entered it
+const PROTECTED_PERCENT: usize = 80;
+
+/// W-TinyLFU cache: new entries go to a small window LRU, and the entries leaving it only enter the main segment if
+/// their key was looked up more often than the one main would evict for them. How often keys were looked up is
+/// estimated by a [`FrequencySketch`], which takes a few bits per key instead of a whole entry.
+///
+/// Main is a segmented LRU: admitted entries go to its probation LRU, and move to its protected one when they're hit
+/// again. Main evicts from probation, so entries that were only hit once since they were admitted go first.
+pub struct TinyLfuCache<K, V>
+{
+    window: LruCache<K, V>,
+    probation: LruCache<K, V>,
+    protected: LruCache<K, V>,
+    pub(super) sketch: FrequencySketch,
+    window_size: usize,
+    main_size: usize,
+    protected_size: usize,
+    max_weight: usize,
+    eviction_listener: Option<EvictionListener<K, V>>,
+}
+
+impl<K, V> ShardCache<K, V> for TinyLfuCache<K, V>
+where
+    K: Eq + std::hash::Hash + Clone,
+{
+    fn try_add_arc(&mut self, key: K, value: Arc<V>) -> bool
+    {
+        let expiry = self.window.default_expiry();
+        self.try_add_arc_with_expiry(key, value, expiry)
+    }
+
+    fn try_add_arc_with_expiry(&mut self, key: K, value: Arc<V>, expiry: Expiry) -> bool
+    {
+        // An entry of the main segment is replaced there, the window decides on the others
+        let added = if self.protected.contains_key(&key) {
+            self.protected.try_add_arc_with_expiry(key, value, expiry)
+        } else if self.probation.contains_key(&key) {
+            self.probation.try_add_arc_with_expiry(key, value, expiry)
+        } else {
+            self.window.try_add_arc_with_expiry(key, value, expiry)
+        };
+        if added {
+            self.evict();
+        }
+        added
+    }
+
+    fn try_get(&mut self, key: &K) -> Option<Arc<V>>
+    {
+        self.sketch.increment(key);
+        self.lookup(key, LruCache::try_get)
+    }
+
+    fn try_get_stale(&mut self, key: &K) -> Option<(Arc<V>, EntryState)>
+    {
+        self.sketch.increment(key);
+        self.lookup(key, LruCache::try_get_stale)
+    }
+
+    /// Isn't counted as a lookup, since it follows one that couldn't be served, so it doesn't promote the entry either.
+    fn try_get_stale_if_error(&mut self, key: &K) -> Option<Arc<V>>
+    {
+        self.protected
+            .try_get_stale_if_error(key)
+            .or_else(|| self.probation.try_get_stale_if_error(key))
+            .or_else(|| self.window.try_get_stale_if_error(key))
+    }
+
+    /// Isn't counted by the sketch either.
+    fn peek_stale(&self, key: &K) -> Option<(Arc<V>, EntryState)>
+    {
+        self.protected
+            .peek_stale(key)
+            .or_else(|| self.probation.peek_stale(key))
+            .or_else(|| self.window.peek_stale(key))
+    }
+
+    /// The sketch still knows the key, so that a new value is admitted on its past lookups.
+    fn remove(&mut self, key: &K) -> bool
+    {
+        self.protected.remove(key) | self.probation.remove(key) | self.window.remove(key)
+    }
+
+    fn remove_tagged(&mut self, tag: &str) -> usize
+    {
+        self.protected.remove_tagged(tag) + self.probation.remove_tagged(tag) + self.window.remove_tagged(tag)
+    }
+
+    fn clear(&mut self)
+    {
+        self.window.clear();
+        self.probation.clear();
+        self.protected.clear();
+        self.sketch.clear();
+    }
+
+    fn len(&self) -> usize
+    {
+        self.window.len() + self.main_len()
+    }
+
+    fn contains(&self, key: &K) -> bool
+    {
+        self.protected.contains(key) || self.probation.contains(key) || self.window.contains(key)
+    }
+
+    fn capacity(&self) -> usize
+    {
+        self.window_size + self.main_size
+    }
+
+    /// Returns the entries of probation, then the ones of protected and of the window, so that restoring them keeps
+    /// the protected ones longest. See [`LruCache::entries`].
+    fn entries(&self) -> Box<dyn Iterator<Item = (&K, &Arc<V>, Expiry)> + '_>
+    {
+        Box::new(
+            self.probation
+                .entries()
+                .chain(self.protected.entries())
+                .chain(self.window.entries()),
+        )
+    }
+
+    /// Adds an entry to the probation segment of main right away, such as one that was cached before a restart.
+    fn restore(&mut self, key: K, value: Arc<V>, expiry: Expiry) -> bool
+    {
+        let added = match self.main_size {
+            0 => self.window.try_add_arc_with_expiry(key, value, expiry),
+            _ => self.probation.try_add_arc_with_expiry(key, value, expiry),
+        };
+        if added {
+            self.evict();
+        }
+        added
+    }
+
+    /// Returns the total weight of the values in all segments.
+    fn weight(&self) -> usize
+    {
+        self.window.weight() + self.probation.weight() + self.protected.weight()
+    }
+
+    /// Removes the expired entries of all segments. See [`LruCache::remove_expired`].
+    fn remove_expired(&mut self, max_visits: usize) -> (usize, bool)
+    {
+        let (removed_protected, more_protected) = self.protected.remove_expired(max_visits);
+        let (removed_probation, more_probation) = self.probation.remove_expired(max_visits);
+        let (removed_window, more_window) = self.window.remove_expired(max_visits);
+        (
+            removed_protected + removed_probation + removed_window,
+            more_protected || more_probation || more_window,
+        )
+    }
+}
+
+impl<K, V> TinyLfuCache<K, V>
+where
+    K: Eq + std::hash::Hash + Clone,
+{
+    /// Makes a cache of `max_size` entries, whose sketch estimates the frequencies of about `sketch_size` keys.
+    pub fn new(max_size: usize, sketch_size: usize, expiration: Duration, expiration_type: ExpirationType) -> Self
+    {
+        let window_size = (max_size * WINDOW_PERCENT / 100).max(1);
+        let main_size = max_size.saturating_sub(window_size);
+        let protected_size = main_size * PROTECTED_PERCENT / 100;
+        Self {
+            // The window and protected go over their size until their least recently used entry is moved out
+            window: LruCache::new(window_size + 1, expiration, expiration_type),
+            probation: LruCache::new(main_size, expiration, expiration_type),
+            protected: LruCache::new(protected_size + 1, expiration, expiration_type),
+            sketch: FrequencySketch::new(sketch_size),
+            window_size,
+            main_size,
+            protected_size,
+            max_weight: usize::MAX,
+            eviction_listener: None,
+        }
+    }
+
+    /// Bounds all segments together by the total weight of their values. See [`LruCache::with_max_weight`].
+    pub fn with_max_weight(mut self, max_weight: usize, weigher: fn(&V) -> usize) -> Self
+    {
+        self.max_weight = max_weight;
+        self.window = self.window.with_max_weight(max_weight, weigher);
+        self.probation = self.probation.with_max_weight(max_weight, weigher);
+        self.protected = self.protected.with_max_weight(max_weight, weigher);
+        self
+    }
+
+    /// See [`LruCache::with_tags`].
+    pub fn with_tags(mut self, tagger: fn(&V) -> &[String]) -> Self
+    {
+        self.window = self.window.with_tags(tagger);
+        self.probation = self.probation.with_tags(tagger);
+        self.protected = self.protected.with_tags(tagger);
+        self
+    }
+
+    /// Listens to the entries evicted from any segment, including the ones leaving the window that main didn't
+    /// admit. See [`LruCache::with_eviction_listener`].
+    pub fn with_eviction_listener(mut self, listener: EvictionListener<K, V>) -> Self
+    {
+        self.window = self.window.with_eviction_listener(listener.clone());
+        self.probation = self.probation.with_eviction_listener(listener.clone());
+        self.protected = self.protected.with_eviction_listener(listener.clone());
+        self.eviction_listener = Some(listener);
+        self
+    }
+
+    /// See [`LruCache::with_stale_while_revalidate`].
+    pub fn with_stale_while_revalidate(mut self, stale_while_revalidate: Duration) -> Self
+    {
+        self.window = self.window.with_stale_while_revalidate(stale_while_revalidate);
+        self.probation = self.probation.with_stale_while_revalidate(stale_while_revalidate);
+        self.protected = self.protected.with_stale_while_revalidate(stale_while_revalidate);
+        self
+    }
+
+    /// See [`LruCache::with_stale_if_error`].
+    pub fn with_stale_if_error(mut self, stale_if_error: Duration) -> Self
+    {
+        self.window = self.window.with_stale_if_error(stale_if_error);
+        self.probation = self.probation.with_stale_if_error(stale_if_error);
+        self.protected = self.protected.with_stale_if_error(stale_if_error);
+        self
+    }
+
+    /// See [`LruCache::with_refresh_ahead`].
+    pub fn with_refresh_ahead(mut self, ttl_percent: u8, min_hits: u32) -> Self
+    {
+        self.window = self.window.with_refresh_ahead(ttl_percent, min_hits);
+        self.probation = self.probation.with_refresh_ahead(ttl_percent, min_hits);
+        self.protected = self.protected.with_refresh_ahead(ttl_percent, min_hits);
+        self
+    }
+
+    /// See [`LruCache::with_clock`].
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self
+    {
+        self.window = self.window.with_clock(clock.clone());
+        self.probation = self.probation.with_clock(clock.clone());
+        self.protected = self.protected.with_clock(clock);
+        self
+    }
+
+    // Moves the entries out of the window while it's over its size, then evicts entries until the weight fits again
+    fn evict(&mut self)
+    {
+        while self.window.len() > self.window_size {
+            match self.window.pop_lru() {
+                Some(candidate) => self.admit(candidate),
+                None => break,
+            }
+        }
+
+        while self.weight() > self.max_weight {
+            let evicted = self
+                .probation
+                .pop_lru()
+                .or_else(|| self.protected.pop_lru())
+                .or_else(|| self.window.pop_lru());
+            match evicted {
+                Some(evicted) => self.notify(evicted),
+                None => break,
+            }
+        }
+    }
+
+    // Adds the candidate to probation if there's room in main, or if it's used more often than the entry probation
+    // would evict for it
+    fn admit(&mut self, candidate: Evicted<K, V>)
+    {
+        if self.main_len() < self.main_size {
+            self.probation.try_add_arc_with_expiry(candidate.key, candidate.value, candidate.expiry);
+            return;
+        }
+
+        let admitted = self
+            .probation
+            .peek_lru()
+            .is_some_and(|victim| self.sketch.frequency(&candidate.key) > self.sketch.frequency(victim));
+        if !admitted {
+            self.notify(candidate);
+            return;
+        }
+        if let Some(victim) = self.probation.pop_lru() {
+            self.notify(victim);
+        }
+        self.probation.try_add_arc_with_expiry(candidate.key, candidate.value, candidate.expiry);
+    }
+
+    // Looks the key up in protected, then in probation, promoting the entry it's found there, then in the window
+    fn lookup<R>(&mut self, key: &K, get: fn(&mut LruCache<K, V>, &K) -> Option<R>) -> Option<R>
+    {
+        if let Some(found) = get(&mut self.protected, key) {
+            return Some(found);
+        }
+        if let Some(found) = get(&mut self.probation, key) {
+            self.promote(key);
+            return Some(found);
+        }
+        get(&mut self.window, key)
+    }
+
+    // Moves the entry from probation to protected, and the least recently used entry of protected back to probation
+    // when protected goes over its size
+    fn promote(&mut self, key: &K)
+    {
+        let Some(entry) = self.probation.take(key) else {
+            return;
+        };
+        self.protected.try_add_arc_with_expiry(entry.key, entry.value, entry.expiry);
+        if self.protected.len() > self.protected_size {
+            if let Some(demoted) = self.protected.pop_lru() {
+                self.probation.try_add_arc_with_expiry(demoted.key, demoted.value, demoted.expiry);
+            }
+        }
+    }
+
+    fn main_len(&self) -> usize
+    {
+        self.probation.len() + self.protected.len()
+    }
+
+    fn notify(&self, evicted: Evicted<K, V>)
+    {
+        if let Some(listener) = &self.eviction_listener {
+            listener(evicted);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn basic()
+    {
+        let mut lru = TinyLfuCache::new(4, 100, Duration::MAX, ExpirationType::Absolute);
+        assert!(lru.try_get(&1).is_none());
+        assert!(lru.try_add(1, "hello"));
+        assert!(lru.try_get(&1).is_some(), "Key should be in the window right away");
+        assert!(!lru.try_add(1, "hello"));
+        assert!(lru.remove(&1));
+        assert!(!lru.contains(&1));
+    }
+
+    #[test]
+    fn admission()
+    {
+        let evicted = Arc::new(std::sync::Mutex::new(Vec::new()));
+        // Window of 1 entry, main of 3
+        let mut lru = TinyLfuCache::new(4, 100, Duration::MAX, ExpirationType::Absolute).with_eviction_listener({
+            let evicted = evicted.clone();
+            Arc::new(move |entry: Evicted<u32, &str>| evicted.lock().unwrap().push(entry.key))
+        });
+        // Hot keys, looked up several times each
+        for key in 1..=4 {
+            for _ in 0..3 {
+                lru.try_get(&key);
+            }
+            assert!(lru.try_add(key, "hot"));
+        }
+        assert_eq!(lru.len(), 4);
+        // One-hit wonders go through the window, but don't make it to main
+        for key in 10..20 {
+            lru.try_get(&key);
+            assert!(lru.try_add(key, "cold"));
+        }
+        // The last hot key wasn't looked up more often than main's least recently used one either
+        let expected: Vec<_> = [4].into_iter().chain(10..19).collect();
+        assert_eq!(*evicted.lock().unwrap(), expected);
+        for key in 1..=3 {
+            assert!(lru.contains(&key));
+        }
+
+        // A key looked up more often than main's least recently used one replaces it
+        for _ in 0..5 {
+            lru.try_get(&19);
+        }
+        assert!(lru.try_add(20, "cold"));
+        assert!(lru.contains(&19));
+        assert!(!lru.contains(&1));
+    }
+
+    #[test]
+    fn protection()
+    {
+        // Window of 1 entry, main of 3, of which 2 protected
+        let mut lru = TinyLfuCache::new(4, 100, Duration::MAX, ExpirationType::Absolute);
+        for key in 1..=3 {
+            for _ in 0..3 {
+                lru.try_get(&key);
+            }
+        }
+        for key in 1..=4 {
+            assert!(lru.try_add(key, "hot"));
+        }
+        // Hit again in probation, the oldest entry of main is protected
+        assert!(lru.try_get(&1).is_some());
+        assert!(lru.protected.contains_key(&1));
+
+        // Keys looked up more often than probation's least recently used ones replace them, the cold one in the
+        // window doesn't
+        for key in 5..=8 {
+            for _ in 0..5 {
+                lru.try_get(&key);
+            }
+            assert!(lru.try_add(key, "warm"));
+        }
+        assert!(!lru.contains(&4));
+        assert!(!lru.contains(&2));
+        assert!(!lru.contains(&3));
+        assert!(lru.contains(&1));
+
+        // Protected goes over its size, its least recently used entry is moved back to probation
+        assert!(lru.try_get(&5).is_some());
+        assert!(lru.try_get(&6).is_some());
+        assert_eq!(lru.protected.len(), 2);
+        assert!(lru.probation.contains_key(&1));
+        assert_eq!(lru.len(), 4);
+    }
+
+    #[test]
+    fn weighting()
+    {
+        let mut lru = TinyLfuCache::new(100, 100, Duration::MAX, ExpirationType::Absolute)
+            .with_max_weight(10, |v: &&str| v.len());
+        assert!(lru.try_add(1, "hell"));
+        assert!(lru.try_add(2, "o w"));
+        assert!(lru.try_add(3, "orl"));
+        assert_eq!(lru.weight(), 10);
+        // Max weight is exceeded, oldest entries of main are evicted until it fits again
+        assert!(lru.try_add(4, "d!"));
+        assert_eq!(lru.weight(), 8);
+        assert!(!lru.contains(&1));
+        assert!(!lru.try_add(5, "hello world"));
+    }
+
+    #[test]
+    fn restore()
+    {
+        let mut lru = TinyLfuCache::new(4, 100, Duration::MAX, ExpirationType::Absolute);
+        for key in 1..=4 {
+            assert!(lru.restore(key, Arc::new("h"), Expiry::new(Duration::MAX)));
+        }
+        // Main only holds 3 entries
+        assert_eq!(lru.entries().map(|(key, _, _)| *key).collect::<Vec<_>>(), vec![2, 3, 4]);
+    }
+}
@@ -0,0 +1,181 @@
+use std::hash::{Hash, Hasher};
+
+use gxhash::GxHasher;
+
+// Counters per key, one in each row, so that a collision in a row is made up for by the others
+const DEPTH: usize = 4;
+// Counters are 4 bits, packed 16 to a word
+const COUNTERS_PER_WORD: usize = 16;
+const MAX_COUNT: u64 = 15;
+// Clears the bit each counter gets from its neighbor when all the words are shifted right to halve the counters
+const HALVING_MASK: u64 = 0x7777_7777_7777_7777;
+// Bits set in the doorkeeper per key
+const DOORKEEPER_PROBES: u64 = 2;
+
+/// Estimates how often keys were seen lately, in little memory: a count-min sketch of 4-bit counters, behind a Bloom
+/// filter doorkeeper that keeps keys seen only once out of the counters. Once as many keys as 10 times the capacity
+/// were recorded, all counters are halved and the doorkeeper is cleared, so that old hits matter less.
+pub struct FrequencySketch
+{
+    counters: Vec<u64>,
+    // Counters per row, a power of two
+    width: usize,
+    doorkeeper: Vec<u64>,
+    // Bits in the doorkeeper, a power of two
+    doorkeeper_bits: usize,
+    additions: usize,
+    sample_size: usize,
+}
+
+impl FrequencySketch
+{
+    /// Makes a sketch sized to tell apart the frequencies of about `capacity` keys.
+    pub fn new(capacity: usize) -> Self
+    {
+        let width = capacity.max(COUNTERS_PER_WORD).next_power_of_two();
+        let doorkeeper_bits = capacity.max(64).next_power_of_two();
+        Self {
+            counters: vec![0; DEPTH * width / COUNTERS_PER_WORD],
+            width,
+            doorkeeper: vec![0; doorkeeper_bits / 64],
+            doorkeeper_bits,
+            additions: 0,
+            sample_size: capacity.max(1).saturating_mul(10),
+        }
+    }
+
+    /// Records that the key was seen.
+    pub fn increment<K: Hash>(&mut self, key: &K)
+    {
+        let hash = hash(key);
+        // The first sighting only goes to the doorkeeper
+        if self.admit_to_doorkeeper(hash) {
+            for row in 0..DEPTH {
+                let (word, shift) = self.counter(hash, row);
+                if (self.counters[word] >> shift) & MAX_COUNT < MAX_COUNT {
+                    self.counters[word] += 1 << shift;
+                }
+            }
+        }
+
+        self.additions += 1;
+        if self.additions >= self.sample_size {
+            self.age();
+        }
+    }
+
+    /// Returns how many times the key was seen lately, possibly overestimated, up to 16.
+    pub fn frequency<K: Hash>(&self, key: &K) -> u8
+    {
+        let hash = hash(key);
+        let count = (0..DEPTH)
+            .map(|row| {
+                let (word, shift) = self.counter(hash, row);
+                (self.counters[word] >> shift) & MAX_COUNT
+            })
+            .min()
+            .unwrap_or(0);
+        count as u8 + self.in_doorkeeper(hash) as u8
+    }
+
+    /// Forgets all the keys seen.
+    pub fn clear(&mut self)
+    {
+        self.counters.fill(0);
+        self.doorkeeper.fill(0);
+        self.additions = 0;
+    }
+
+    // Halves all the counters and clears the doorkeeper
+    fn age(&mut self)
+    {
+        for word in &mut self.counters {
+            *word = (*word >> 1) & HALVING_MASK;
+        }
+        self.doorkeeper.fill(0);
+        self.additions /= 2;
+    }
+
+    // Returns the word and the bit shift of the counter of the hash in the row
+    fn counter(&self, hash: u64, row: usize) -> (usize, usize)
+    {
+        let index = probe(hash, row as u64) & (self.width - 1);
+        let word = row * self.width / COUNTERS_PER_WORD + index / COUNTERS_PER_WORD;
+        (word, (index % COUNTERS_PER_WORD) * 4)
+    }
+
+    // Sets the bits of the hash in the doorkeeper, and returns whether they were all set already
+    fn admit_to_doorkeeper(&mut self, hash: u64) -> bool
+    {
+        let mut present = true;
+        for i in 0..DOORKEEPER_PROBES {
+            let bit = probe(hash.rotate_left(17), i) & (self.doorkeeper_bits - 1);
+            present &= self.doorkeeper[bit / 64] & (1 << (bit % 64)) != 0;
+            self.doorkeeper[bit / 64] |= 1 << (bit % 64);
+        }
+        present
+    }
+
+    fn in_doorkeeper(&self, hash: u64) -> bool
+    {
+        (0..DOORKEEPER_PROBES).all(|i| {
+            let bit = probe(hash.rotate_left(17), i) & (self.doorkeeper_bits - 1);
+            self.doorkeeper[bit / 64] & (1 << (bit % 64)) != 0
+        })
+    }
+}
+
+fn hash<K: Hash>(key: &K) -> u64
+{
+    let mut hasher = GxHasher::with_seed(123);
+    key.hash(&mut hasher);
+    hasher.finish()
+}
+
+// Derives the i-th index of a hash, by double hashing
+fn probe(hash: u64, i: u64) -> usize
+{
+    hash.wrapping_add(i.wrapping_mul(hash.rotate_left(32) | 1)) as usize
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn frequency()
+    {
+        let mut sketch = FrequencySketch::new(1000);
+        assert_eq!(sketch.frequency(&1), 0);
+        sketch.increment(&1);
+        assert_eq!(sketch.frequency(&1), 1, "First sighting should be in the doorkeeper");
+        for _ in 0..5 {
+            sketch.increment(&1);
+        }
+        assert_eq!(sketch.frequency(&1), 6);
+        for _ in 0..100 {
+            sketch.increment(&1);
+        }
+        assert_eq!(sketch.frequency(&1), 16, "Counters should saturate");
+        assert_eq!(sketch.frequency(&2), 0);
+
+        sketch.clear();
+        assert_eq!(sketch.frequency(&1), 0);
+    }
+
+    #[test]
+    fn aging()
+    {
+        let mut sketch = FrequencySketch::new(100);
+        for _ in 0..9 {
+            sketch.increment(&1);
+        }
+        assert_eq!(sketch.frequency(&1), 9);
+        // Reaches the sample size of 1000 additions
+        for _ in 0..991 {
+            sketch.increment(&2);
+        }
+        assert_eq!(sketch.frequency(&1), 4, "Counters should be halved, and the doorkeeper cleared");
+    }
+}
@@ -1,2 +1,5 @@
 pub mod arena_linked_list;
 pub use arena_linked_list::ArenaLinkedList;
+
+pub mod frequency_sketch;
+pub use frequency_sketch::FrequencySketch;
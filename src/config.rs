@@ -1,25 +1,109 @@
+use std::collections::HashMap;
+
+use ipnet::IpNet;
 use serde::Deserialize;
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct RisuConfiguration
 {
+    /// Number of shards the in-memory cache is split into, rounded up to a power of two.
     #[serde(default = "default_in_memory_shards")]
     pub in_memory_shards: u16,
 
     #[serde(default = "default_cache_resident_size")]
     pub cache_resident_size: usize,
 
+    /// How each shard decides which responses are worth caching, and which to evict.
+    #[serde(default = "default_cache_policy")]
+    pub cache_policy: CachePolicy,
+
+    /// Keys remembered by each shard to decide which are worth caching: the keys seen once with the `probatory`
+    /// policy, or the keys whose frequency is estimated with `tiny_lfu`.
     #[serde(default = "default_cache_probatory_size")]
     pub cache_probatory_size: usize,
 
+    #[serde(default = "default_cache_max_bytes")]
+    pub cache_max_bytes: usize,
+
     #[serde(default = "default_cache_ttl_seconds")]
     pub cache_ttl_seconds: usize,
 
+    #[serde(default = "default_cache_ttl_mode")]
+    pub cache_ttl_mode: TtlMode,
+
+    /// How long responses can be served once expired, while they're refreshed in the background. Responses can set
+    /// their own with the `stale-while-revalidate` `Cache-Control` directive, depending on `cache_ttl_mode`.
+    #[serde(default)]
+    pub cache_stale_while_revalidate_seconds: u64,
+
+    /// How long responses can be served once expired, when the upstream fails to provide a fresh one. Responses can
+    /// set their own with the `stale-if-error` `Cache-Control` directive, depending on `cache_ttl_mode`.
+    #[serde(default)]
+    pub cache_stale_if_error_seconds: u64,
+
+    /// How often expired entries are removed from the cache, rather than waiting for them to be evicted.
+    #[serde(default = "default_cache_sweep_interval_seconds")]
+    pub cache_sweep_interval_seconds: u64,
+
+    /// How often the clock telling the age of cached entries ticks. A coarser clock is cheaper to read, but entries
+    /// can be served up to that much longer.
+    #[serde(default = "default_cache_clock_resolution_ms")]
+    pub cache_clock_resolution_ms: u64,
+
+    /// Refreshes hot entries in the background before they expire. Disabled when unset.
+    #[serde(default)]
+    pub cache_refresh_ahead: Option<RefreshAheadConfiguration>,
+
+    /// Saves the cache to a file periodically and on shutdown, to reload it on startup. Disabled when unset.
+    #[serde(default)]
+    pub cache_snapshot: Option<SnapshotConfiguration>,
+
+    /// Writes the entries evicted from memory to disk, from where they're promoted back on hits. Disabled when unset.
+    #[serde(default)]
+    pub cache_disk: Option<DiskCacheConfiguration>,
+
+    /// Response header listing the space separated tags (surrogate keys) of a response, by which it can be purged.
+    #[serde(default = "default_cache_tag_header")]
+    pub cache_tag_header: String,
+
+    #[serde(default = "default_cache_status_codes")]
+    pub cache_status_codes: Vec<u16>,
+
+    #[serde(default = "default_cache_grpc_status_codes")]
+    pub cache_grpc_status_codes: Vec<u16>,
+
+    /// Can be overridden per route.
+    #[serde(default)]
+    pub cache_key: CacheKeyConfiguration,
+
     #[serde(default = "default_listening_port")]
     pub listening_port: u16,
 
-    #[serde(default = "default_http2")]
-    pub http2: bool,
+    /// Additional ports to listen on, which routes can match on.
+    #[serde(default)]
+    pub extra_listening_ports: Vec<u16>,
+
+    #[serde(default = "default_upstream_protocol")]
+    pub upstream_protocol: UpstreamProtocol,
+
+    #[serde(default)]
+    pub upstreams: HashMap<String, UpstreamConfiguration>,
+
+    /// Evaluated in order, the first matching route wins.
+    #[serde(default)]
+    pub routes: Vec<RouteConfiguration>,
+
+    /// Whether requests matching no route can give their target with the `x-target-host` header.
+    #[serde(default = "default_target_host_header")]
+    pub target_host_header: bool,
+
+    /// Targets given by the `x-target-host` header must match the allowlist, and must not match the denylist.
+    /// Routes are not filtered. With an empty allowlist, all such targets are denied.
+    #[serde(default)]
+    pub target_allowlist: TargetListConfiguration,
+
+    #[serde(default)]
+    pub target_denylist: TargetListConfiguration,
 
     #[serde(default = "default_prometheus_port")]
     pub prometheus_port: u16,
@@ -27,8 +111,320 @@ pub struct RisuConfiguration
     #[serde(default = "default_healthcheck_port")]
     pub healthcheck_port: u16,
 
+    /// Port of the endpoint invalidating cached responses. Disabled when unset, since anyone reaching it can purge
+    /// the cache.
+    #[serde(default)]
+    pub admin_port: Option<u16>,
+
+    #[serde(default = "default_upstream_timeout_seconds")]
+    pub upstream_timeout_seconds: u64,
+
     #[serde(default = "default_max_idle_connections_per_host")]
     pub max_idle_connections_per_host: u16,
+
+    #[serde(default)]
+    pub upstream_tls: UpstreamTlsConfiguration,
+
+    /// Terminates TLS on `listening_port` when set.
+    #[serde(default)]
+    pub tls: Option<ListenerTlsConfiguration>,
+}
+
+/// TLS settings of the listener. Certificates are reloaded from disk on SIGHUP.
+#[derive(Debug, Deserialize, Clone)]
+pub struct ListenerTlsConfiguration
+{
+    /// PEM files with the certificate chain of risu and its private key.
+    pub certificate_file: String,
+
+    pub key_file: String,
+
+    /// PEM file with the certificate authorities client certificates are verified against. Client certificates are
+    /// not requested when unset.
+    #[serde(default)]
+    pub client_ca_file: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct UpstreamConfiguration
+{
+    /// Host and port of the upstream, eg. `greeter:50051`.
+    pub address: String,
+
+    #[serde(default = "default_scheme")]
+    pub scheme: String,
+
+    /// Overrides `upstream_protocol` for this upstream.
+    #[serde(default)]
+    pub protocol: Option<UpstreamProtocol>,
+}
+
+/// Maps requests to an upstream. A route matches if all its conditions match.
+#[derive(Debug, Deserialize, Clone)]
+pub struct RouteConfiguration
+{
+    /// Name of the upstream in `upstreams`.
+    pub upstream: String,
+
+    /// Listening port the request was received on.
+    #[serde(default)]
+    pub port: Option<u16>,
+
+    /// `:authority` or `Host` of the request. The port is ignored unless specified.
+    #[serde(default)]
+    pub authority: Option<String>,
+
+    /// Prefix of the request path, eg. `/helloworld.Greeter/`.
+    #[serde(default)]
+    pub path_prefix: Option<String>,
+
+    /// Leading segment of the request path, stripped before forwarding. `catalog` forwards `/catalog/items` as
+    /// `/items`.
+    #[serde(default)]
+    pub path_segment: Option<String>,
+
+    /// Overrides `cache_key` for requests matching this route.
+    #[serde(default)]
+    pub cache_key: Option<CacheKeyConfiguration>,
+}
+
+/// Parts of the request that make up its cache key.
+#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
+pub struct CacheKeyConfiguration
+{
+    #[serde(default = "default_cache_key_part")]
+    pub method: bool,
+
+    /// Scheme and authority of the target.
+    #[serde(default = "default_cache_key_part")]
+    pub upstream: bool,
+
+    /// Path of the target, after the route is applied.
+    #[serde(default = "default_cache_key_part")]
+    pub path: bool,
+
+    #[serde(default)]
+    pub query: QueryKeyConfiguration,
+
+    /// Request headers whose values are part of the key, eg. `accept-language`.
+    #[serde(default)]
+    pub headers: Vec<String>,
+
+    #[serde(default = "default_cache_key_part")]
+    pub body: bool,
+
+    /// Header whose value, when present, is used instead of the body.
+    #[serde(default)]
+    pub override_header: Option<String>,
+}
+
+impl Default for CacheKeyConfiguration
+{
+    fn default() -> Self
+    {
+        Self {
+            method: default_cache_key_part(),
+            upstream: default_cache_key_part(),
+            path: default_cache_key_part(),
+            query: QueryKeyConfiguration::default(),
+            headers: vec![],
+            body: default_cache_key_part(),
+            override_header: None,
+        }
+    }
+}
+
+/// Parameters are compared by name, as they appear in the query (not percent-decoded).
+#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
+pub struct QueryKeyConfiguration
+{
+    #[serde(default = "default_cache_key_part")]
+    pub enabled: bool,
+
+    /// Sorts the parameters, so that their order doesn't matter.
+    #[serde(default)]
+    pub sort: bool,
+
+    /// Only these parameters are part of the key.
+    #[serde(default)]
+    pub include: Option<Vec<String>>,
+
+    /// These parameters are not part of the key, eg. cache busters or tracking parameters.
+    #[serde(default)]
+    pub exclude: Vec<String>,
+}
+
+impl Default for QueryKeyConfiguration
+{
+    fn default() -> Self
+    {
+        Self {
+            enabled: default_cache_key_part(),
+            sort: false,
+            include: None,
+            exclude: vec![],
+        }
+    }
+}
+
+/// Hits on an entry in the last `ttl_percent` of its time to live trigger a refresh, if the entry had at least
+/// `min_hits` hits since it was cached or last refreshed.
+#[derive(Debug, Deserialize, Clone)]
+pub struct RefreshAheadConfiguration
+{
+    #[serde(default = "default_refresh_ahead_ttl_percent")]
+    pub ttl_percent: u8,
+
+    #[serde(default = "default_refresh_ahead_min_hits")]
+    pub min_hits: u32,
+
+    /// Refreshes beyond this rate are skipped, so that many entries expiring together can't overload upstreams.
+    #[serde(default = "default_refresh_ahead_max_per_second")]
+    pub max_per_second: u32,
+}
+
+/// The snapshot is written to a temporary file next to `path`, then renamed, so that a crash while saving leaves the
+/// previous snapshot intact.
+#[derive(Debug, Deserialize, Clone)]
+pub struct SnapshotConfiguration
+{
+    pub path: String,
+
+    /// Only saved on shutdown when 0.
+    #[serde(default = "default_snapshot_interval_seconds")]
+    pub interval_seconds: u64,
+}
+
+/// Evicted entries are only written to disk if they were hit at least `min_hits` times since they were cached, and
+/// have at least `min_ttl_seconds` left to live. Values left in `directory` by a previous run are deleted on startup.
+#[derive(Debug, Deserialize, Clone)]
+pub struct DiskCacheConfiguration
+{
+    pub directory: String,
+
+    /// Oldest values are dropped beyond this size, values not compacted yet included.
+    #[serde(default = "default_disk_max_bytes")]
+    pub max_bytes: u64,
+
+    #[serde(default = "default_disk_min_hits")]
+    pub min_hits: u32,
+
+    #[serde(default = "default_disk_min_ttl_seconds")]
+    pub min_ttl_seconds: u64,
+
+    /// How often the space of removed and expired values is reclaimed.
+    #[serde(default = "default_disk_compaction_interval_seconds")]
+    pub compaction_interval_seconds: u64,
+}
+
+/// Set of targets. Hosts are exact names, `*.example.com` for any subdomain, or `*` for any host.
+/// Networks are matched against the addresses the target host resolves to.
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct TargetListConfiguration
+{
+    #[serde(default)]
+    pub hosts: Vec<String>,
+
+    #[serde(default)]
+    pub networks: Vec<IpNet>,
+
+    /// Either a single port, or a `{ from, to }` inclusive range.
+    #[serde(default)]
+    pub ports: Vec<PortRange>,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum PortRange
+{
+    Single(u16),
+    Range
+    {
+        from: u16, to: u16
+    },
+}
+
+impl PortRange
+{
+    pub fn contains(&self, port: u16) -> bool
+    {
+        match *self {
+            PortRange::Single(p) => p == port,
+            PortRange::Range { from, to } => (from..=to).contains(&port),
+        }
+    }
+}
+
+/// TLS settings used to reach https targets.
+#[derive(Debug, Deserialize, Clone)]
+pub struct UpstreamTlsConfiguration
+{
+    /// PEM file with the certificate authorities to trust. Defaults to the Mozilla root certificates.
+    #[serde(default)]
+    pub ca_file: Option<String>,
+
+    /// PEM files with the client certificate chain and its private key, for mTLS.
+    #[serde(default)]
+    pub client_certificate_file: Option<String>,
+
+    #[serde(default)]
+    pub client_key_file: Option<String>,
+
+    #[serde(default = "default_sni")]
+    pub sni: bool,
+}
+
+impl Default for UpstreamTlsConfiguration
+{
+    fn default() -> Self
+    {
+        Self {
+            ca_file: None,
+            client_certificate_file: None,
+            client_key_file: None,
+            sni: default_sni(),
+        }
+    }
+}
+
+/// How the `Cache-Control` and `Expires` headers of upstream responses affect their time to live.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TtlMode
+{
+    /// Use the upstream freshness lifetime if any, `cache_ttl_seconds` otherwise. Uncacheable responses are not cached.
+    Respect,
+    /// Always use `cache_ttl_seconds` instead of the upstream freshness lifetime. Uncacheable responses, such as
+    /// `private` or `no-store` ones, are still not cached.
+    Override,
+    /// Same as `Respect`, but never cache for longer than `cache_ttl_seconds`.
+    Cap,
+}
+
+/// Admission and eviction policy of the cache.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CachePolicy
+{
+    /// Cache responses the second time they're fetched, and evict the least recently used.
+    Probatory,
+    /// Cache responses in a small window first, then keep the ones requested most often (W-TinyLFU).
+    TinyLfu,
+    /// Cache responses right away, and evict the ones not requested since the last eviction pass (SIEVE). Hits don't
+    /// need exclusive access to the shard.
+    Sieve,
+}
+
+/// HTTP version used to reach targets. Over TLS, http1 still lets the target pick h2 through ALPN.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum UpstreamProtocol
+{
+    /// Same version as the incoming request.
+    Inbound,
+    Http1,
+    /// HTTP/2 with prior knowledge.
+    Http2,
 }
 
 // https://github.com/serde-rs/serde/issues/368 🙄
@@ -40,21 +436,85 @@ fn default_cache_resident_size() -> usize
 {
     100_000
 }
+fn default_cache_policy() -> CachePolicy
+{
+    CachePolicy::Probatory
+}
 fn default_cache_probatory_size() -> usize
 {
     1_000_000
 }
+fn default_cache_max_bytes() -> usize
+{
+    1 << 30 // 1 GiB
+}
 fn default_cache_ttl_seconds() -> usize
 {
     600
 }
+fn default_cache_ttl_mode() -> TtlMode
+{
+    TtlMode::Respect
+}
+fn default_cache_sweep_interval_seconds() -> u64
+{
+    5
+}
+fn default_cache_clock_resolution_ms() -> u64
+{
+    1
+}
+fn default_cache_tag_header() -> String
+{
+    "surrogate-key".to_string()
+}
+fn default_snapshot_interval_seconds() -> u64
+{
+    300
+}
+fn default_disk_max_bytes() -> u64
+{
+    1_000_000_000
+}
+fn default_disk_min_hits() -> u32
+{
+    1
+}
+fn default_disk_min_ttl_seconds() -> u64
+{
+    10
+}
+fn default_disk_compaction_interval_seconds() -> u64
+{
+    60
+}
+fn default_refresh_ahead_ttl_percent() -> u8
+{
+    10
+}
+fn default_refresh_ahead_min_hits() -> u32
+{
+    10
+}
+fn default_refresh_ahead_max_per_second() -> u32
+{
+    100
+}
+fn default_cache_status_codes() -> Vec<u16>
+{
+    vec![200, 203, 204, 300, 301, 308]
+}
+fn default_cache_grpc_status_codes() -> Vec<u16>
+{
+    vec![0] // OK
+}
 fn default_listening_port() -> u16
 {
     3001
 }
-fn default_http2() -> bool
+fn default_upstream_protocol() -> UpstreamProtocol
 {
-    true
+    UpstreamProtocol::Inbound
 }
 fn default_prometheus_port() -> u16
 {
@@ -64,10 +524,30 @@ fn default_healthcheck_port() -> u16
 {
     8001
 }
+fn default_upstream_timeout_seconds() -> u64
+{
+    30
+}
 fn default_max_idle_connections_per_host() -> u16
 {
     4
 }
+fn default_target_host_header() -> bool
+{
+    true
+}
+fn default_cache_key_part() -> bool
+{
+    true
+}
+fn default_scheme() -> String
+{
+    "http".to_string()
+}
+fn default_sni() -> bool
+{
+    true
+}
 
 #[cfg(test)]
 mod tests
@@ -79,15 +559,55 @@ mod tests
     {
         let conf = "in_memory_shards: 42\n\
                     cache_resident_size: 123\n\
+                    cache_policy: tiny_lfu\n\
                     cache_probatory_size: 456\n\
+                    cache_max_bytes: 1024\n\
+                    cache_ttl_mode: cap\n\
+                    cache_clock_resolution_ms: 10\n\
+                    cache_status_codes: [200, 404]\n\
+                    cache_refresh_ahead:\n  \
+                      min_hits: 5\n\
+                    cache_snapshot:\n  \
+                      path: /var/lib/risu/cache.snapshot\n\
+                    cache_disk:\n  \
+                      directory: /var/cache/risu\n  \
+                      min_hits: 2\n\
                     listening_port: 789\n\
-                    http2: false";
+                    upstream_protocol: http1\n\
+                    upstream_tls:\n  \
+                      ca_file: /etc/risu/ca.pem\n\
+                    target_allowlist:\n  \
+                      hosts: [\"*.example.com\"]\n  \
+                      networks: [10.0.0.0/8]\n  \
+                      ports: [443, { from: 8000, to: 8999 }]";
 
         let configuration: RisuConfiguration = serde_yaml::from_str::<RisuConfiguration>(conf).unwrap();
 
         assert_eq!(configuration.in_memory_shards, 42);
         assert_eq!(configuration.cache_resident_size, 123);
+        assert_eq!(configuration.cache_policy, CachePolicy::TinyLfu);
         assert_eq!(configuration.cache_probatory_size, 456);
+        assert_eq!(configuration.cache_max_bytes, 1024);
+        assert_eq!(configuration.cache_ttl_mode, TtlMode::Cap);
+        assert_eq!(configuration.cache_clock_resolution_ms, 10);
+        assert_eq!(configuration.cache_status_codes, vec![200, 404]);
+        let refresh_ahead = configuration.cache_refresh_ahead.unwrap();
+        assert_eq!((refresh_ahead.ttl_percent, refresh_ahead.min_hits, refresh_ahead.max_per_second), (10, 5, 100));
+        let snapshot = configuration.cache_snapshot.unwrap();
+        assert_eq!((snapshot.path.as_str(), snapshot.interval_seconds), ("/var/lib/risu/cache.snapshot", 300));
+        let disk = configuration.cache_disk.unwrap();
+        assert_eq!((disk.directory.as_str(), disk.max_bytes, disk.min_hits), ("/var/cache/risu", 1_000_000_000, 2));
+        assert_eq!(configuration.cache_grpc_status_codes, vec![0]);
         assert_eq!(configuration.listening_port, 789);
+        assert_eq!(configuration.upstream_protocol, UpstreamProtocol::Http1);
+        assert_eq!(configuration.upstream_tls.ca_file.as_deref(), Some("/etc/risu/ca.pem"));
+        assert!(configuration.upstream_tls.sni);
+        assert_eq!(configuration.target_allowlist.hosts, vec!["*.example.com"]);
+        assert_eq!(configuration.target_allowlist.networks, vec!["10.0.0.0/8".parse::<IpNet>().unwrap()]);
+        assert_eq!(
+            configuration.target_allowlist.ports,
+            vec![PortRange::Single(443), PortRange::Range { from: 8000, to: 8999 }]
+        );
+        assert!(configuration.target_denylist.hosts.is_empty());
     }
 }
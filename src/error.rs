@@ -0,0 +1,147 @@
+use std::fmt;
+
+use hyper::header::CONTENT_TYPE;
+use hyper::{Response, StatusCode};
+
+use crate::buffered_body::BufferedBody;
+
+/// Errors that can happen while proxying a request, each mapping to an HTTP status and a gRPC status.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RisuError
+{
+    /// The request matches no route and doesn't say where to forward it.
+    MissingTarget,
+    /// The request target can't be turned into a valid upstream URI.
+    InvalidTarget(String),
+    /// The target is not allowed by `target_allowlist` and `target_denylist`.
+    ForbiddenTarget(String),
+    /// The request body couldn't be read from the client.
+    InvalidRequest(String),
+    /// The request couldn't be sent upstream, or the response couldn't be read.
+    UpstreamConnection(String),
+    /// Upstream didn't respond in time.
+    UpstreamTimeout,
+}
+
+impl RisuError
+{
+    /// Label of the error in metrics.
+    pub fn kind(&self) -> &'static str
+    {
+        match self {
+            RisuError::MissingTarget => "missing_target",
+            RisuError::InvalidTarget(_) => "invalid_target",
+            RisuError::ForbiddenTarget(_) => "forbidden_target",
+            RisuError::InvalidRequest(_) => "invalid_request",
+            RisuError::UpstreamConnection(_) => "upstream_connection",
+            RisuError::UpstreamTimeout => "upstream_timeout",
+        }
+    }
+
+    pub fn status(&self) -> StatusCode
+    {
+        match self {
+            RisuError::MissingTarget | RisuError::InvalidTarget(_) | RisuError::InvalidRequest(_) => {
+                StatusCode::BAD_REQUEST
+            }
+            RisuError::ForbiddenTarget(_) => StatusCode::FORBIDDEN,
+            RisuError::UpstreamConnection(_) => StatusCode::BAD_GATEWAY,
+            RisuError::UpstreamTimeout => StatusCode::GATEWAY_TIMEOUT,
+        }
+    }
+
+    /// https://grpc.github.io/grpc/core/md_doc_statuscodes.html
+    pub fn grpc_status(&self) -> u16
+    {
+        match self {
+            RisuError::MissingTarget | RisuError::InvalidTarget(_) | RisuError::InvalidRequest(_) => 3, // INVALID_ARGUMENT
+            RisuError::ForbiddenTarget(_) => 7, // PERMISSION_DENIED
+            RisuError::UpstreamConnection(_) => 14, // UNAVAILABLE
+            RisuError::UpstreamTimeout => 4,        // DEADLINE_EXCEEDED
+        }
+    }
+
+    /// Builds the response sent to the client. gRPC clients get a trailers-only response, since they expect
+    /// a 200 status and the actual outcome in `grpc-status`.
+    pub fn to_response(&self, grpc: bool) -> Response<BufferedBody>
+    {
+        let message = self.to_string();
+        if grpc {
+            Response::builder()
+                .status(StatusCode::OK)
+                .header(CONTENT_TYPE, "application/grpc")
+                .header("grpc-status", self.grpc_status())
+                .header("grpc-message", percent_encode(&message))
+                .body(BufferedBody::default())
+                .expect("Failed to build gRPC error response")
+        } else {
+            Response::builder()
+                .status(self.status())
+                .body(BufferedBody::from_bytes(message.as_bytes()))
+                .expect("Failed to build error response")
+        }
+    }
+}
+
+impl fmt::Display for RisuError
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+        match self {
+            RisuError::MissingTarget => write!(f, "No route matches the request, can't forward it"),
+            RisuError::InvalidTarget(e) => write!(f, "Invalid target: {}", e),
+            RisuError::ForbiddenTarget(e) => write!(f, "Forbidden target: {}", e),
+            RisuError::InvalidRequest(e) => write!(f, "Invalid request: {}", e),
+            RisuError::UpstreamConnection(e) => write!(f, "Upstream connection failed: {}", e),
+            RisuError::UpstreamTimeout => write!(f, "Upstream timed out"),
+        }
+    }
+}
+
+impl std::error::Error for RisuError {}
+
+// grpc-message is percent-encoded, as described in https://github.com/grpc/grpc/blob/master/doc/PROTOCOL-HTTP2.md
+fn percent_encode(message: &str) -> String
+{
+    let mut encoded = String::with_capacity(message.len());
+    for byte in message.bytes() {
+        if (0x20..=0x7E).contains(&byte) && byte != b'%' {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn http_response()
+    {
+        let response = RisuError::UpstreamTimeout.to_response(false);
+        assert_eq!(response.status(), StatusCode::GATEWAY_TIMEOUT);
+        assert_eq!(RisuError::MissingTarget.to_response(false).status(), StatusCode::BAD_REQUEST);
+        assert_eq!(RisuError::ForbiddenTarget("".into()).to_response(false).status(), StatusCode::FORBIDDEN);
+        assert_eq!(
+            RisuError::UpstreamConnection("refused".into()).to_response(false).status(),
+            StatusCode::BAD_GATEWAY
+        );
+    }
+
+    #[test]
+    fn grpc_response()
+    {
+        let response = RisuError::InvalidTarget("100% wrong\n".into()).to_response(true);
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["grpc-status"], "3");
+        assert_eq!(response.headers()["grpc-message"], "Invalid target: 100%25 wrong%0A");
+        assert!(response.body().is_empty());
+
+        let response = RisuError::ForbiddenTarget("10.0.0.1:22 is not in the allowlist".into()).to_response(true);
+        assert_eq!(response.headers()["grpc-status"], "7");
+    }
+}
@@ -1,44 +1,122 @@
 #[macro_use]
 extern crate log;
 
+mod admission;
 mod buffered_body;
+mod cache_control;
+mod cache_key;
 mod caches;
 mod collections;
 pub mod config;
+mod error;
 mod executor;
 mod metrics;
+mod rate_limiter;
+mod routing;
+mod snapshot;
+mod target_filter;
+mod tls;
+mod vary;
 
-use std::hash::Hash;
+use std::io::ErrorKind;
 use std::net::SocketAddr;
-use std::sync::atomic::AtomicBool;
-use std::sync::Arc;
-use std::time::Duration;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant, SystemTime};
 
+use admission::Rejection;
 use buffered_body::BufferedBody;
+use cache_control::{CacheControl, Freshness};
 pub use caches::*;
 pub use collections::*;
 pub use config::RisuConfiguration;
+pub use error::RisuError;
 use executor::TokioExecutor;
 use futures::join;
-use gxhash::GxHasher;
 use hyper::body::Incoming;
-use hyper::http::Uri;
-use hyper::server::conn::{http1, http2};
+use hyper::header::{HeaderName, HeaderValue, AGE, WARNING};
+use hyper::server::conn::http1;
 use hyper::service::service_fn;
-use hyper::{Request, Response};
+use hyper::{Method, Request, Response, StatusCode, Version};
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
 use hyper_util::client::legacy::connect::HttpConnector;
 use hyper_util::client::legacy::Client;
 use hyper_util::rt::TokioIo;
+use hyper_util::server::conn::auto;
 use metrics::Metrics;
+use rate_limiter::RateLimiter;
+use routing::{Router, Target};
+use snapshot::SnapshotEntry;
+use target_filter::TargetFilter;
 use tokio::net::TcpListener;
-use std::sync::atomic::Ordering;
+use tokio_rustls::TlsAcceptor;
+use vary::VariantTable;
+
+// Headers that only make sense for a single connection, and must not be forwarded
+const HOP_BY_HOP_HEADERS: [&str; 6] =
+    ["connection", "keep-alive", "proxy-connection", "transfer-encoding", "upgrade", "http2-settings"];
+
+// Keys visited by the expiration sweeper each time it locks a shard
+const SWEEP_BATCH: usize = 1000;
+
+// Tells clients that a response is served stale, and why
+const STALE_HEADER: &str = "x-risu-stale";
+
+// Entries evicted from memory waiting to be written to disk, beyond which they're dropped
+const DISK_QUEUE: usize = 1024;
+// Entries evicted from memory written to disk at once
+const DISK_BATCH: usize = 64;
+
+/// Remembers when a response entered the cache and how old it already was, to compute its `Age` when served.
+#[derive(Clone, Copy)]
+struct Stored
+{
+    at: Instant,
+    age: u64,
+}
+
+impl Stored
+{
+    /// Returns the age of the response at `now`, as told by the `Age` header.
+    fn age(&self, now: Instant) -> u64
+    {
+        self.age + now.saturating_duration_since(self.at).as_secs()
+    }
+}
+
+/// Marks responses moved back to memory from disk.
+#[derive(Clone, Copy)]
+struct Promoted;
+
+/// Tags (surrogate keys) of a response, by which it can be removed from the cache along with the others carrying them.
+#[derive(Clone)]
+struct Tags(Vec<String>);
+
+impl Tag for Response<BufferedBody>
+{
+    fn tags(&self) -> &[String]
+    {
+        self.extensions().get::<Tags>().map_or(&[], |tags| &tags.0)
+    }
+}
 
 pub struct RisuServer
 {
     configuration: RisuConfiguration,
     cache: ShardedCache<u128, Response<BufferedBody>>,
     metrics: Metrics,
-    client: Client<HttpConnector, BufferedBody>,
+    router: Router,
+    target_filter: TargetFilter,
+    variants: VariantTable,
+    refresh_ahead_limiter: RateLimiter,
+    // Shared by the caches, so that responses age the same way they expire
+    clock: Arc<dyn Clock>,
+    // Second tier, holding entries evicted from memory
+    disk: Option<Arc<DiskCache>>,
+    http1_client: Client<HttpsConnector<HttpConnector>, BufferedBody>,
+    http2_client: Client<HttpsConnector<HttpConnector>, BufferedBody>,
+    // Behind a lock so that certificates can be reloaded without restarting
+    listener_tls: Option<RwLock<Arc<rustls::ServerConfig>>>,
 }
 
 impl RisuServer
@@ -60,23 +138,105 @@ impl RisuServer
     }
 
     pub async fn start(configuration: RisuConfiguration) -> Result<(), std::io::Error>
+    {
+        // Expiration is checked on every lookup, so the time is read from a clock ticking in the background
+        let resolution = Duration::from_millis(configuration.cache_clock_resolution_ms.max(1));
+        RisuServer::start_with_clock(configuration, Arc::new(CoarseClock::new(resolution))).await
+    }
+
+    /// Same as [`RisuServer::start`], with the time told by the given clock, such as a [`ManualClock`] to expire
+    /// responses without waiting.
+    pub async fn start_with_clock(
+        configuration: RisuConfiguration, clock: Arc<dyn Clock>,
+    ) -> Result<(), std::io::Error>
     {
         info!("Starting Prequest server...");
 
         let mut connector = HttpConnector::new();
         connector.set_nodelay(true);
+        // Let https URIs through, TLS is handled by the wrapping connector
+        connector.enforce_http(false);
+
+        let tls = tls::client_config(&configuration.upstream_tls)?;
+        // h2 may still be negotiated through ALPN with https targets
+        let http1_connector = HttpsConnectorBuilder::new()
+            .with_tls_config(tls.clone())
+            .https_or_http()
+            .enable_all_versions()
+            .wrap_connector(connector.clone());
+        let http2_connector = HttpsConnectorBuilder::new()
+            .with_tls_config(tls)
+            .https_or_http()
+            .enable_http2()
+            .wrap_connector(connector);
+
+        let listener_tls = match &configuration.tls {
+            Some(tls) => Some(RwLock::new(Arc::new(tls::server_config(tls)?))),
+            None => None,
+        };
+
+        let (refresh_ahead_percent, refresh_ahead_min_hits, refresh_ahead_max_per_second) =
+            match &configuration.cache_refresh_ahead {
+                Some(refresh_ahead) => {
+                    (refresh_ahead.ttl_percent, refresh_ahead.min_hits, refresh_ahead.max_per_second)
+                }
+                None => (0, 0, 0),
+            };
+
+        let metrics = Metrics::new();
+        let policy = match configuration.cache_policy {
+            config::CachePolicy::Probatory => Policy::Probatory(configuration.cache_probatory_size),
+            config::CachePolicy::TinyLfu => Policy::TinyLfu(configuration.cache_probatory_size),
+            config::CachePolicy::Sieve => Policy::Sieve,
+        };
+        let mut cache = ShardedCache::<u128, Response<BufferedBody>>::new_with_policy(
+            configuration.in_memory_shards as usize,
+            configuration.cache_resident_size,
+            policy,
+            Duration::from_secs(configuration.cache_ttl_seconds as u64),
+            lru::ExpirationType::Absolute,
+        )
+        .with_max_weight(configuration.cache_max_bytes, Weigh::weigh)
+        .with_tags(Tag::tags)
+        .with_stale_while_revalidate(Duration::from_secs(configuration.cache_stale_while_revalidate_seconds))
+        .with_stale_if_error(Duration::from_secs(configuration.cache_stale_if_error_seconds))
+        .with_refresh_ahead(refresh_ahead_percent, refresh_ahead_min_hits)
+        .with_clock(clock.clone());
+
+        // Evicted entries are handed over to the disk writer, or dropped if it can't keep up
+        let (disk, evictions) = match &configuration.cache_disk {
+            Some(disk) => {
+                let (sender, receiver) = tokio::sync::mpsc::channel(DISK_QUEUE);
+                let dropped = metrics.cache_disk_writes.with_label_values(&["dropped"]);
+                cache = cache.with_eviction_listener(Arc::new(move |evicted: Evicted<u128, Response<BufferedBody>>| {
+                    if sender.try_send(evicted).is_err() {
+                        dropped.inc();
+                    }
+                }));
+                let disk = DiskCache::new(&disk.directory, disk.max_bytes)?.with_clock(clock.clone());
+                (Some(Arc::new(disk)), Some(receiver))
+            }
+            None => (None, None),
+        };
 
         let server = Arc::new(RisuServer {
             configuration: configuration.clone(),
-            cache: ShardedCache::<u128, Response<BufferedBody>>::new(
+            listener_tls,
+            cache,
+            metrics,
+            router: Router::new(&configuration)?,
+            target_filter: TargetFilter::new(&configuration),
+            variants: VariantTable::new(
                 configuration.in_memory_shards as usize,
                 configuration.cache_resident_size,
                 Duration::from_secs(configuration.cache_ttl_seconds as u64),
-                lru::ExpirationType::Absolute,
             ),
-            metrics: Metrics::new(),
-            client: Client::builder(TokioExecutor)
-                .http2_only(configuration.http2)
+            refresh_ahead_limiter: RateLimiter::new(refresh_ahead_max_per_second).with_clock(clock.clone()),
+            clock,
+            disk,
+            http1_client: Client::builder(TokioExecutor).set_host(false).build(http1_connector),
+            http2_client: Client::builder(TokioExecutor)
+                .http2_only(true)
                 // .pool_max_idle_per_host(configuration.max_idle_connections_per_host as usize)
                 // .http2_max_send_buf_size(128_000_000)
                 // .timer(hyper_util::rt::TokioTimer::new())
@@ -85,50 +245,124 @@ impl RisuServer
                 // .http2_keep_alive_interval(Some(Duration::from_secs(300)))
                 // .retry_canceled_requests(false)
                 .set_host(false)
-                .build(connector),
+                .build(http2_connector),
         });
+        server.load_snapshot();
 
         let service = async {
-            let service_address = SocketAddr::from(([0, 0, 0, 0], server.configuration.listening_port));
-            info!(
-                "Service listening on http://{}, http2:{}",
-                service_address, configuration.http2
-            );
+            let ports = std::iter::once(server.configuration.listening_port)
+                .chain(server.configuration.extra_listening_ports.iter().copied());
+            futures::future::join_all(ports.map(|port| RisuServer::listen(server.clone(), port))).await;
+        };
+
+        let tls_reload = async {
+            #[cfg(unix)]
+            if server.listener_tls.is_some() {
+                let mut hangups = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())?;
+                while hangups.recv().await.is_some() {
+                    match server.reload_tls() {
+                        Ok(()) => info!("Reloaded listener TLS certificates"),
+                        Err(err) => error!("Failed to reload listener TLS certificates: {:?}", err),
+                    }
+                }
+            }
+            Ok::<(), std::io::Error>(())
+        };
+
+        // Expired entries are otherwise only removed when touched, or when they reach the head of the list
+        let sweeper = async {
+            let mut interval =
+                tokio::time::interval(Duration::from_secs(server.configuration.cache_sweep_interval_seconds.max(1)));
+            loop {
+                interval.tick().await;
+                let removed = server.cache.remove_expired(SWEEP_BATCH).await;
+                debug!("Removed {} expired entries", removed);
+                server.metrics.cache_expired.inc_by(removed as f64);
+            }
+        };
+
+        let disk_writer = async {
+            let (true, Some(mut evictions)) = (server.disk.is_some(), evictions) else {
+                return;
+            };
+            let mut batch = Vec::with_capacity(DISK_BATCH);
+            while evictions.recv_many(&mut batch, DISK_BATCH).await > 0 {
+                let evicted = std::mem::replace(&mut batch, Vec::with_capacity(DISK_BATCH));
+                let server = server.clone();
+                if let Err(err) = tokio::task::spawn_blocking(move || server.write_to_disk(evicted)).await {
+                    error!("Failed to write to the disk cache: {:?}", err);
+                }
+            }
+        };
+
+        // Reclaims the space of the values removed from disk, or promoted back to memory
+        let disk_compactor = async {
+            let (Some(disk), Some(configuration)) = (&server.disk, &server.configuration.cache_disk) else {
+                return;
+            };
+            let interval_seconds = configuration.compaction_interval_seconds.max(1);
+            let mut interval = tokio::time::interval(Duration::from_secs(interval_seconds));
+            loop {
+                interval.tick().await;
+                let disk = disk.clone();
+                match tokio::task::spawn_blocking(move || disk.compact()).await.map_err(std::io::Error::other) {
+                    Ok(Ok(reclaimed)) => debug!("Reclaimed {} bytes on disk", reclaimed),
+                    Ok(Err(err)) | Err(err) => warn!("Failed to compact the disk cache: {}", err),
+                }
+            }
+        };
+
+        // Saved periodically as well, in case risu doesn't shut down gracefully
+        let snapshots = async {
+            let Some(interval_seconds) = server
+                .configuration
+                .cache_snapshot
+                .as_ref()
+                .map(|snapshot| snapshot.interval_seconds)
+                .filter(|interval_seconds| *interval_seconds > 0)
+            else {
+                return;
+            };
+            let mut interval = tokio::time::interval(Duration::from_secs(interval_seconds));
+            // The first tick completes right away
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                server.save_snapshot().await;
+            }
+        };
+
+        // Signals are only handled when there's something to do before exiting
+        let shutdown = async {
+            if server.configuration.cache_snapshot.is_none() {
+                return std::future::pending().await;
+            }
+            if let Err(err) = shutdown_signal().await {
+                error!("Failed to listen for shutdown signals: {:?}", err);
+                std::future::pending().await
+            }
+        };
 
-            let listener = TcpListener::bind(service_address).await.unwrap();
+        let admin = async {
+            let Some(admin_port) = server.configuration.admin_port else {
+                return;
+            };
+            let admin_address: SocketAddr = ([0, 0, 0, 0], admin_port).into();
+            info!("Admin listening on http://{}", admin_address);
+            let listener = TcpListener::bind(admin_address).await.unwrap();
 
-            // We start a loop to continuously accept incoming connections
             loop {
                 let (stream, _) = listener.accept().await.unwrap();
-                // Use an adapter to access something implementing `tokio::io` traits as if they implement
-                // `hyper::rt` IO traits.
                 let io = TokioIo::new(stream);
                 let server = server.clone();
-                if configuration.http2 {
-                    tokio::task::spawn(async move {
-                        debug!("Listening for http2 connections...");
-                        let server_for_metrics = server.clone();
-                        if let Err(err) = http2::Builder::new(TokioExecutor)
-                            .serve_connection(io, service_fn(move |req| RisuServer::call_async(server.clone(), req)))
-                            .await
-                        {
-                            server_for_metrics.metrics.connection_reset.inc();
-                            warn!("Error serving connection: {:?}", err);
-                        }
-                    });
-                } else {
-                    tokio::task::spawn(async move {
-                        debug!("Listening for http1 connections...");
-                        let server_for_metrics = server.clone();
-                        if let Err(err) = http1::Builder::new()
-                            .serve_connection(io, service_fn(move |req| RisuServer::call_async(server.clone(), req)))
-                            .await
-                        {
-                            server_for_metrics.metrics.connection_reset.inc();
-                            warn!("Error serving connection: {:?}", err);
-                        }
-                    });
-                }
+                tokio::task::spawn(async move {
+                    if let Err(err) = http1::Builder::new()
+                        .serve_connection(io, service_fn(move |req| RisuServer::admin(server.clone(), req)))
+                        .await
+                    {
+                        warn!("Error serving admin connection: {:?}", err);
+                    }
+                });
             }
         };
 
@@ -173,11 +407,223 @@ impl RisuServer
             }
         };
 
-        join!(service, prometheus, healthcheck);
+        let running = async {
+            join!(
+                service,
+                sweeper,
+                snapshots,
+                disk_writer,
+                disk_compactor,
+                admin,
+                prometheus,
+                healthcheck,
+                tls_reload
+            )
+        };
+        tokio::select! {
+            (_, _, _, _, _, _, _, _, tls_reload) = running => tls_reload?,
+            () = shutdown => {
+                info!("Shutting down");
+                server.save_snapshot().await;
+            }
+        }
 
         Ok(())
     }
 
+    async fn listen(server: Arc<RisuServer>, port: u16)
+    {
+        let service_address = SocketAddr::from(([0, 0, 0, 0], port));
+        info!(
+            "Service listening on {}://{}",
+            if server.listener_tls.is_some() { "https" } else { "http" },
+            service_address
+        );
+
+        let listener = TcpListener::bind(service_address).await.unwrap();
+
+        // We start a loop to continuously accept incoming connections
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            let server = server.clone();
+            match server.tls_acceptor() {
+                Some(acceptor) => {
+                    tokio::task::spawn(async move {
+                        match acceptor.accept(stream).await {
+                            Ok(stream) => RisuServer::serve_connection(server, TokioIo::new(stream), port).await,
+                            Err(err) => warn!("TLS handshake failed: {:?}", err),
+                        }
+                    });
+                }
+                None => {
+                    // Use an adapter to access something implementing `tokio::io` traits as if they implement
+                    // `hyper::rt` IO traits.
+                    let io = TokioIo::new(stream);
+                    tokio::task::spawn(RisuServer::serve_connection(server, io, port));
+                }
+            }
+        }
+    }
+
+    /// Serves both HTTP/1.1 and HTTP/2 on the connection, the latter being detected from the client preface.
+    /// h2c upgrades (deprecated by RFC 9113) are not honored, such requests are served over HTTP/1.1.
+    async fn serve_connection<I>(server: Arc<RisuServer>, io: I, port: u16)
+    where
+        I: hyper::rt::Read + hyper::rt::Write + Unpin + Send + 'static,
+    {
+        debug!("Listening for connections...");
+        let server_for_metrics = server.clone();
+        let result = auto::Builder::new(TokioExecutor)
+            .serve_connection(io, service_fn(move |req| RisuServer::call_async(server.clone(), req, port)))
+            .await;
+        if let Err(err) = result {
+            server_for_metrics.metrics.connection_reset.inc();
+            warn!("Error serving connection: {:?}", err);
+        }
+    }
+
+    fn tls_acceptor(&self) -> Option<TlsAcceptor>
+    {
+        self.listener_tls
+            .as_ref()
+            .map(|tls| TlsAcceptor::from(tls.read().unwrap().clone()))
+    }
+
+    /// Reloads the listener certificates from disk. Established connections keep their session,
+    /// and the cache is left untouched.
+    pub fn reload_tls(&self) -> Result<(), std::io::Error>
+    {
+        if let (Some(tls), Some(configuration)) = (&self.listener_tls, &self.configuration.tls) {
+            let reloaded = tls::server_config(configuration)?;
+            *tls.write().unwrap() = Arc::new(reloaded);
+        }
+        Ok(())
+    }
+
+    /// Removes the response of the key from both tiers, and tells whether there was one.
+    fn remove(&self, key: u128) -> bool
+    {
+        let removed = self.cache.remove(&key);
+        // Always removed from disk as well, since an older response may have been evicted there
+        self.disk.as_ref().is_some_and(|disk| disk.remove(key)) || removed
+    }
+
+    /// Writes the entries evicted from memory to disk, unless they're not worth it. Blocking.
+    fn write_to_disk(&self, evicted: Vec<Evicted<u128, Response<BufferedBody>>>)
+    {
+        let (Some(disk), Some(configuration)) = (&self.disk, &self.configuration.cache_disk) else {
+            return;
+        };
+        for entry in evicted {
+            let outcome = if entry.hits < configuration.min_hits
+                || entry.expiry.ttl < Duration::from_secs(configuration.min_ttl_seconds)
+            {
+                "rejected"
+            } else {
+                let age = entry.value.extensions().get::<Stored>().map_or(0, |stored| stored.age(self.clock.now()));
+                let value = snapshot::encode_response(&entry.value, age);
+                match disk.put(entry.key, &value, entry.expiry, entry.value.tags().to_vec()) {
+                    Ok(true) => "written",
+                    Ok(false) => "rejected",
+                    Err(err) => {
+                        warn!("Failed to write to the disk cache: {}", err);
+                        "failed"
+                    }
+                }
+            };
+            self.metrics.cache_disk_writes.with_label_values(&[outcome]).inc();
+        }
+    }
+
+    /// Looks the key up on disk, and moves the response found back to memory. Stale responses are left to be
+    /// refreshed.
+    async fn promote(&self, key: u128) -> Option<Response<BufferedBody>>
+    {
+        let disk = self.disk.as_ref()?;
+        let reader = disk.clone();
+        let hit = match tokio::task::spawn_blocking(move || reader.get(key)).await.map_err(std::io::Error::other) {
+            Ok(Ok(hit)) => hit.filter(|hit| !hit.expiry.ttl.is_zero()),
+            Ok(Err(err)) | Err(err) => {
+                warn!("Failed to read from the disk cache: {}", err);
+                None
+            }
+        };
+        let promoted = hit.and_then(|hit| match snapshot::decode_response(&hit.value) {
+            Ok((response, age)) => Some((response, age + hit.age.as_secs(), hit.expiry)),
+            Err(err) => {
+                warn!("Failed to read from the disk cache: {}", err);
+                None
+            }
+        });
+        let outcome = if promoted.is_some() { "hit" } else { "miss" };
+        self.metrics.cache_tier_lookups.with_label_values(&["l2", outcome]).inc();
+
+        let (mut response, age, expiry) = promoted?;
+        response.extensions_mut().insert(Stored { at: self.clock.now(), age });
+        insert_tags(&mut response, &self.configuration.cache_tag_header);
+        // Back in memory, it's written to disk again if it's evicted again
+        if self.cache.restore(key, Arc::new(response.clone()), expiry) {
+            disk.remove(key);
+        }
+        response.extensions_mut().insert(Promoted);
+        Some(response)
+    }
+
+    /// Fills the cache with the snapshot, if any. The cache is left empty if the snapshot can't be read.
+    fn load_snapshot(&self)
+    {
+        let Some(configuration) = &self.configuration.cache_snapshot else {
+            return;
+        };
+        let entries = match snapshot::read(Path::new(&configuration.path)) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == ErrorKind::NotFound => return,
+            Err(err) => {
+                warn!("Ignoring cache snapshot {}: {}", configuration.path, err);
+                return;
+            }
+        };
+
+        let now = self.clock.now();
+        let mut restored = 0;
+        for SnapshotEntry { key, expiry, age, mut response } in entries {
+            response.extensions_mut().insert(Stored { at: now, age });
+            insert_tags(&mut response, &self.configuration.cache_tag_header);
+            if self.cache.restore(key, Arc::new(response), expiry) {
+                restored += 1;
+            }
+        }
+        info!("Restored {} cached responses from {}", restored, configuration.path);
+    }
+
+    /// Saves the resident entries of the cache to the snapshot, off the runtime threads.
+    async fn save_snapshot(&self)
+    {
+        let Some(configuration) = &self.configuration.cache_snapshot else {
+            return;
+        };
+        let now = self.clock.now();
+        let entries: Vec<_> = self
+            .cache
+            .entries()
+            .into_iter()
+            .map(|(key, response, expiry)| SnapshotEntry {
+                key,
+                expiry,
+                age: response.extensions().get::<Stored>().map_or(0, |stored| stored.age(now)),
+                response,
+            })
+            .collect();
+        let count = entries.len();
+
+        let path = PathBuf::from(&configuration.path);
+        match tokio::task::spawn_blocking(move || snapshot::write(&path, &entries)).await {
+            Ok(Ok(())) => info!("Saved {} cached responses to {}", count, configuration.path),
+            Ok(Err(err)) => error!("Failed to save cache snapshot {}: {}", configuration.path, err),
+            Err(err) => error!("Failed to save cache snapshot {}: {}", configuration.path, err),
+        }
+    }
+
     pub async fn healthcheck(_req: Request<hyper::body::Incoming>) -> Result<Response<BufferedBody>, hyper::Error>
     {
         Ok(Response::new(BufferedBody::from_bytes(b"Healthy")))
@@ -187,11 +633,108 @@ impl RisuServer
         server: Arc<RisuServer>, _: Request<hyper::body::Incoming>,
     ) -> Result<Response<BufferedBody>, hyper::Error>
     {
+        for (shard, weight) in server.cache.shard_weights().iter().enumerate() {
+            server
+                .metrics
+                .cache_shard_bytes
+                .with_label_values(&[&shard.to_string()])
+                .set(*weight as i64);
+        }
+        if let Some(disk) = &server.disk {
+            server.metrics.cache_disk_bytes.set(disk.bytes() as i64);
+        }
         Ok(Response::new(BufferedBody::from_bytes(&server.metrics.encode())))
     }
 
+    /// Invalidates cached responses:
+    /// - `PURGE` with the path and headers of a proxied request removes its response. The request is routed as if it
+    ///   was received on `listening_port` with the `GET` method, unless told otherwise by the `x-risu-purge-port` and
+    ///   `x-risu-purge-method` headers.
+    /// - `DELETE /keys/{key}` removes the response with the given key, as returned by `PURGE`.
+    /// - `DELETE /keys` removes all the responses.
+    /// - `DELETE /tags/{tag}` removes the responses carrying the tag in their `cache_tag_header` header.
+    pub async fn admin(
+        server: Arc<RisuServer>, request: Request<hyper::body::Incoming>,
+    ) -> Result<Response<BufferedBody>, hyper::Error>
+    {
+        let removed = match (request.method().as_str(), request.uri().path()) {
+            ("PURGE", _) => server.purge(request).await,
+            ("DELETE", "/keys") => {
+                let len = server.cache.len() + server.disk.as_ref().map_or(0, |disk| disk.len());
+                server.cache.clear();
+                if let Some(disk) = server.disk.clone() {
+                    match tokio::task::spawn_blocking(move || disk.clear()).await.map_err(std::io::Error::other) {
+                        Ok(Ok(())) => {}
+                        Ok(Err(err)) | Err(err) => warn!("Failed to clear the disk cache: {}", err),
+                    }
+                }
+                info!("Flushed the cache, {} entries removed", len);
+                return Ok(Response::new(BufferedBody::from_bytes(format!("Removed {} entries", len).as_bytes())));
+            }
+            ("DELETE", path) if path.starts_with("/tags/") => {
+                let tag = &path["/tags/".len()..];
+                let removed = server.cache.remove_tagged(tag)
+                    + server.disk.as_ref().map_or(0, |disk| disk.remove_tagged(tag));
+                info!("Removed {} cached responses tagged {}", removed, tag);
+                return Ok(Response::new(BufferedBody::from_bytes(format!("Removed {} entries", removed).as_bytes())));
+            }
+            ("DELETE", path) if path.starts_with("/keys/") => u128::from_str_radix(&path["/keys/".len()..], 16)
+                .map(|key| (key, server.remove(key)))
+                .map_err(|e| RisuError::InvalidRequest(format!("Invalid key: {}", e))),
+            _ => {
+                let mut response = Response::new(BufferedBody::from_bytes(b"Not found"));
+                *response.status_mut() = StatusCode::NOT_FOUND;
+                return Ok(response);
+            }
+        };
+
+        let response = match removed {
+            Ok((key, true)) => {
+                info!("Removed cached response {:032x}", key);
+                Response::new(BufferedBody::from_bytes(format!("{:032x}", key).as_bytes()))
+            }
+            Ok((key, false)) => {
+                let mut response = Response::new(BufferedBody::from_bytes(format!("{:032x}", key).as_bytes()));
+                *response.status_mut() = StatusCode::NOT_FOUND;
+                response
+            }
+            Err(e) => e.to_response(false),
+        };
+        Ok(response)
+    }
+
+    /// Removes the cached response of the request, and returns its key and whether it was cached.
+    async fn purge(&self, request: Request<hyper::body::Incoming>) -> Result<(u128, bool), RisuError>
+    {
+        let port = match request.headers().get("x-risu-purge-port") {
+            Some(port) => port
+                .to_str()
+                .ok()
+                .and_then(|port| port.parse().ok())
+                .ok_or_else(|| RisuError::InvalidRequest("Invalid x-risu-purge-port header".to_string()))?,
+            None => self.configuration.listening_port,
+        };
+        let method = match request.headers().get("x-risu-purge-method") {
+            Some(method) => Method::from_bytes(method.as_bytes())
+                .map_err(|_| RisuError::InvalidRequest("Invalid x-risu-purge-method header".to_string()))?,
+            None => Method::GET,
+        };
+
+        let (mut parts, body) = request.into_parts();
+        parts.method = method;
+        let body = BufferedBody::collect_buffered(body)
+            .await
+            .map_err(|e| RisuError::InvalidRequest(e.to_string()))?;
+        let request = Request::from_parts(parts, body);
+
+        let target = self.router.route(port, &request)?;
+        let (primary_key, vary) = self.primary_key(&target, &request);
+        let key = vary::variant_key(primary_key, &vary, request.headers());
+        Ok((key, self.remove(key)))
+    }
+
     pub async fn call_async(
-        service: Arc<RisuServer>, request: Request<Incoming>,
+        service: Arc<RisuServer>, request: Request<Incoming>, port: u16,
     ) -> Result<Response<BufferedBody>, hyper::Error>
     {
         debug!("Request received");
@@ -199,103 +742,339 @@ impl RisuServer
         let timestamp = std::time::Instant::now();
         service.metrics.cache_calls.inc();
 
-        let key_factory = |request: &Request<BufferedBody>| {
-            // Hash request content
-            let mut hasher = GxHasher::with_seed(123);
-            // Different path/query means different key
-            request.uri().path().hash(&mut hasher);
-            request.uri().query().hash(&mut hasher);
-            // Sometimes, we can't rely on the request body.
-            // For example, protobuf maps are serialized in a non-deterministic order.
-            // https://gist.github.com/kchristidis/39c8b310fd9da43d515c4394c3cd9510
-            // In this case, the caller may define a hash header to not use the body for the key.
-            match request.headers().get("x-request-id") {
-                // If the request has a request id header, use it as the key
-                Some(value) => value.as_bytes().hash(&mut hasher),
-                // Otherwise hash the request body
-                None => {
-                    request.body().hash(&mut hasher);
+        let grpc = admission::is_grpc(request.headers());
+
+        let target = match service.router.route(port, &request) {
+            Ok(target) => target,
+            Err(e) => return Ok(service.error_response(e, grpc)),
+        };
+
+        let (mut parts, body) = request.into_parts();
+        let buffered_body = match BufferedBody::collect_buffered(body).await {
+            Ok(buffered_body) => buffered_body,
+            Err(e) => return Ok(service.error_response(RisuError::InvalidRequest(e.to_string()), grpc)),
+        };
+        parts.extensions.insert(target.clone());
+        let request = Request::from_parts(parts, buffered_body);
+
+        let (primary_key, vary) = service.primary_key(&target, &request);
+        let key_factory = |request: &&Request<BufferedBody>| vary::variant_key(primary_key, &vary, request.headers());
+
+        let value_factory = |_: &Request<BufferedBody>| async {
+            debug!("Cache miss");
+            service.metrics.cache_misses.inc();
+            if let Some(response) = service.promote(vary::variant_key(primary_key, &vary, request.headers())).await {
+                return Ok((response, None));
+            }
+            let result = RisuServer::fetch(&service, &request, primary_key, &vary).await;
+
+            // If upstream fails, an expired response is better than none
+            let failure = match &result {
+                Ok((response, _)) => admission::upstream_failure(response),
+                Err(e @ (RisuError::UpstreamConnection(_) | RisuError::UpstreamTimeout)) => Some(e.kind()),
+                Err(_) => None,
+            };
+            let key = vary::variant_key(primary_key, &vary, request.headers());
+            match failure.and_then(|failure| Some((failure, service.cache.try_get_stale_if_error(&key)?))) {
+                Some((failure, stale)) => {
+                    warn!("Upstream failed ({}), serving a stale response", failure);
+                    service.metrics.cache_stale_if_error.with_label_values(&[failure]).inc();
+                    let mut response = stale.as_ref().clone();
+                    mark_stale(&mut response, "111 - \"Revalidation Failed\"", "if-error");
+                    Ok((response, None))
                 }
+                None => result,
             }
-            hasher.finish_u128()
         };
 
-        let cached: AtomicBool = true.into();
+        let result: Result<(Arc<Response<BufferedBody>>, Lookup), RisuError> = service
+            .cache
+            .get_or_add_from_item(&request, key_factory, value_factory)
+            .await;
 
-        let value_factory = |request: Request<BufferedBody>| async {
+        let (response, mut lookup) = match result {
+            Ok(result) => result,
+            Err(e) => return Ok(service.error_response(e, grpc)),
+        };
+        let mut response: Response<BufferedBody> = response.as_ref().clone();
 
-            cached.store(false, Ordering::Relaxed);
+        // Requests waiting for the same key share the values of the headers the key accounted for. If the response
+        // varies on other headers, it might not be suitable for this one.
+        if lookup == Lookup::Coalesced && vary::varies_on(response.headers()).as_ref() != Some(&*vary) {
+            debug!("Coalesced response varies on other headers, forwarding again");
+            lookup = Lookup::Miss;
+            response = match RisuServer::forward(&service, &request).await {
+                Ok(response) => response,
+                Err(e) => return Ok(service.error_response(e, grpc)),
+            };
+        }
+        debug!("Received response from target with status: {:?}", response);
 
-            debug!("Cache miss");
-            service.metrics.cache_misses.inc();
+        // The stale response is served right away, while a single background task per key refreshes it
+        if lookup == Lookup::Stale {
+            debug!("Cache hit on a stale entry, refreshing it");
+            service.metrics.cache_stale.inc();
+            mark_stale(&mut response, "110 - \"Response is Stale\"", "while-revalidate");
+            RisuServer::spawn_refresh(&service, &request, primary_key, vary.clone());
+        }
+
+        // Rather than keeping the original request around, the one of the hit is replayed, since it has the same key
+        if lookup == Lookup::RefreshAhead {
+            let outcome = match service.refresh_ahead_limiter.try_acquire() {
+                true => {
+                    debug!("Cache hit on an entry about to expire, refreshing it");
+                    RisuServer::spawn_refresh(&service, &request, primary_key, vary.clone());
+                    "scheduled"
+                }
+                false => "throttled",
+            };
+            service.metrics.cache_refresh_ahead.with_label_values(&[outcome]).inc();
+        }
 
-            let target_host = request.headers().get("x-target-host").expect("Missing X-Target-Host header! Can't forward the request.").to_str().unwrap();
+        let l1_hit = matches!(lookup, Lookup::Hit | Lookup::Stale | Lookup::RefreshAhead);
+        if lookup != Lookup::Coalesced {
+            let outcome = if l1_hit { "hit" } else { "miss" };
+            service.metrics.cache_tier_lookups.with_label_values(&["l1", outcome]).inc();
+        }
 
-            let target_uri = Uri::builder()
-                .scheme("http")
-                .authority(target_host)
-                .path_and_query(request.uri().path_and_query().unwrap().clone())
-                .build()
-                .expect("Failed to build target URI");
+        // Responses served stale on errors or promoted from disk come from the cache as well
+        let promoted = response.extensions_mut().remove::<Promoted>().is_some();
+        let cached = l1_hit || promoted || response.headers().contains_key(STALE_HEADER);
+        if cached {
+            if let Some(age) = response.extensions().get::<Stored>().map(|stored| stored.age(service.clock.now())) {
+                response.headers_mut().insert(AGE, age.into());
+            }
+        }
+
+        if lookup == Lookup::Coalesced {
+            service.metrics.cache_coalesced.inc();
+        }
+
+        let elapsed = timestamp.elapsed();
+        let cached_str = if cached { &["true"] } else { &["false"] };
+        service.metrics.request_duration.with_label_values(cached_str).observe(elapsed.as_secs_f64());
 
-            // Copy path and query
-            let mut forwarded_req = Request::builder()
-                .method(request.method())
-                .uri(target_uri)
-                .version(request.version());
+        Ok(response)
+    }
 
-            // Copy headers
-            let headers = forwarded_req.headers_mut().expect("Failed to get headers");
-            headers.extend(request.headers().iter().map(|(k, v)| (k.clone(), v.clone())));
+    /// Returns the primary key of the request, and the request headers its responses vary on.
+    fn primary_key(&self, target: &Target, request: &Request<BufferedBody>) -> (u128, Arc<Vec<HeaderName>>)
+    {
+        // Responses may vary on request headers, in which case the key is the one of the variant selected by the
+        // request. We only know what responses vary on once we've got one, so the first response is not cached.
+        let primary_key = cache_key::hash(target, request);
+        (primary_key, self.variants.get(primary_key))
+    }
+
+    /// Refreshes the cached response for the request in the background, unless it's already being refreshed.
+    fn spawn_refresh(
+        service: &Arc<RisuServer>, request: &Request<BufferedBody>, primary_key: u128, vary: Arc<Vec<HeaderName>>,
+    )
+    {
+        let key = vary::variant_key(primary_key, &vary, request.headers());
+        let request = RisuServer::copy_request(request);
+        let service = service.clone();
+        tokio::spawn(async move {
+            let refresh = service
+                .cache
+                .refresh(key, || RisuServer::fetch(&service, &request, primary_key, &vary));
+            if let Some(Err(e)) = refresh.await {
+                warn!("Failed to refresh cache entry: {}", e);
+                service.metrics.errors.with_label_values(&[e.kind()]).inc();
+            }
+        });
+    }
+
+    /// Forwards the request and tells whether and how long the response can be cached.
+    async fn fetch(
+        service: &RisuServer, request: &Request<BufferedBody>, primary_key: u128, vary: &[HeaderName],
+    ) -> Result<(Response<BufferedBody>, Option<Expiry>), RisuError>
+    {
+        let mut response = RisuServer::forward(service, request).await?;
 
-            let body = request.into_body();
+        insert_tags(&mut response, &service.configuration.cache_tag_header);
 
-            debug!("Buffering request...");
+        let ttl = Freshness::from_headers(response.headers(), SystemTime::now()).ttl(
+            service.configuration.cache_ttl_mode,
+            Duration::from_secs(service.configuration.cache_ttl_seconds as u64),
+        );
 
-            // Copy body
-            let forwarded_req = forwarded_req.body(body).expect("Failed building request");
+        let varies_on = vary::varies_on(response.headers());
+        if let Some(varies_on) = varies_on.as_ref().filter(|varies_on| **varies_on != *vary) {
+            service.variants.set(primary_key, varies_on.clone());
+        }
+
+        let admission = admission::admit(&service.configuration, &response)
+            .and_then(|()| match varies_on {
+                Some(varies_on) if varies_on == *vary => Ok(()),
+                _ => Err(Rejection::Vary),
+            })
+            .and_then(|()| ttl.ok_or(Rejection::CacheControl));
+        let expiry = match admission {
+            Ok(ttl) => {
+                let cache_control = CacheControl::from_headers(response.headers());
+                let mode = service.configuration.cache_ttl_mode;
+                let stale_while_revalidate = cache_control.stale_while_revalidate(
+                    mode,
+                    Duration::from_secs(service.configuration.cache_stale_while_revalidate_seconds),
+                );
+                let stale_if_error = cache_control
+                    .stale_if_error(mode, Duration::from_secs(service.configuration.cache_stale_if_error_seconds));
+                Some(
+                    Expiry::new(ttl)
+                        .with_stale_while_revalidate(stale_while_revalidate)
+                        .with_stale_if_error(stale_if_error),
+                )
+            }
+            Err(rejection) => {
+                debug!("Response not admitted in the cache: {:?}", rejection);
+                service
+                    .metrics
+                    .cache_rejections
+                    .with_label_values(&[rejection.as_str()])
+                    .inc();
+                None
+            }
+        };
+
+        Ok((response, expiry))
+    }
+
+    /// Copies what forwarding needs from a request, which can't be cloned because of its extensions.
+    fn copy_request(request: &Request<BufferedBody>) -> Request<BufferedBody>
+    {
+        let mut copy = Request::new(request.body().clone());
+        *copy.method_mut() = request.method().clone();
+        *copy.uri_mut() = request.uri().clone();
+        *copy.version_mut() = request.version();
+        *copy.headers_mut() = request.headers().clone();
+        if let Some(target) = request.extensions().get::<Target>() {
+            copy.extensions_mut().insert(target.clone());
+        }
+        copy
+    }
+
+    /// Forwards the request to its target, as resolved by the router, and buffers the response.
+    async fn forward(
+        service: &RisuServer, request: &Request<BufferedBody>,
+    ) -> Result<Response<BufferedBody>, RisuError>
+    {
+        let target = request
+            .extensions()
+            .get::<Target>()
+            .cloned()
+            .ok_or(RisuError::MissingTarget)?;
+
+        // Configured upstreams are trusted, targets given by clients are not. Only checked before connecting, so that
+        // cache hits don't wait for names to be resolved, nor fail when they can't be. Denied targets are counted
+        // by the errors metric, as `forbidden_target`.
+        if target.upstream.is_none() {
+            service.target_filter.check(&target.uri).await?;
+        }
+
+        let (client, version) = match target.http2 {
+            true => (&service.http2_client, Version::HTTP_2),
+            false => (&service.http1_client, Version::HTTP_11),
+        };
 
-            debug!("Forwarding request");
+        // Copy path and query
+        let mut forwarded_req = Request::builder()
+            .method(request.method())
+            .uri(target.uri)
+            .version(version);
 
+        // Copy headers, except the connection-specific ones
+        if let Some(headers) = forwarded_req.headers_mut() {
+            headers.extend(
+                request
+                    .headers()
+                    .iter()
+                    .filter(|(k, _)| !HOP_BY_HOP_HEADERS.contains(&k.as_str()))
+                    .map(|(k, v)| (k.clone(), v.clone())),
+            );
+        }
+
+        let body = request.body().clone();
+
+        // Copy body
+        let forwarded_req = forwarded_req
+            .body(body)
+            .map_err(|e| RisuError::InvalidTarget(e.to_string()))?;
+
+        debug!("Forwarding request");
+
+        let timeout = Duration::from_secs(service.configuration.upstream_timeout_seconds);
+        let exchange = async {
             // Await the response...
-            let response: Response<Incoming> = service
-                .client
+            let response: Response<Incoming> = client
                 .request(forwarded_req)
                 .await
-                .expect("Failed to send request");
+                .map_err(|e| RisuError::UpstreamConnection(e.to_string()))?;
 
             // Buffer response body so that we can cache it and return it
             let (parts, body) = response.into_parts();
-            let buffered_response_body = BufferedBody::collect_buffered(body).await.unwrap();
-
-            debug!("Received response from target with status: {:?}", parts.status);
+            let buffered_response_body = BufferedBody::collect_buffered(body)
+                .await
+                .map_err(|e| RisuError::UpstreamConnection(e.to_string()))?;
 
-            Ok(Response::from_parts(parts, buffered_response_body))
+            Ok::<_, RisuError>((parts, buffered_response_body))
         };
+        let (parts, buffered_response_body) = tokio::time::timeout(timeout, exchange)
+            .await
+            .map_err(|_| RisuError::UpstreamTimeout)??;
 
-        let (parts, body) = request.into_parts();
-        let buffered_body = BufferedBody::collect_buffered(body).await.unwrap();
-        let request = Request::from_parts(parts, buffered_body);
-
-        let result: Result<Arc<Response<BufferedBody>>, hyper::Error> = service
-            .cache
-            .get_or_add_from_item2(request, key_factory, value_factory)
-            .await;
+        debug!("Received response from target with status: {:?}", parts.status);
 
-        let response = match result {
-            Ok(response) => {
-                let response = response.as_ref();
-                let response: Response<BufferedBody> = response.clone();
-                debug!("Received response from target with status: {:?}", response);
-                Ok(response)
-            }
-            Err(e) => Err(e),
+        let stored = Stored {
+            at: service.clock.now(),
+            age: cache_control::age(&parts.headers),
         };
+        let mut response = Response::from_parts(parts, buffered_response_body);
+        response.extensions_mut().insert(stored);
 
-        let elapsed = timestamp.elapsed();
-        let cached_str = if cached.load(Ordering::Relaxed) { &["true"] } else { &["false"] };
-        service.metrics.request_duration.with_label_values(cached_str).observe(elapsed.as_secs_f64());
+        Ok(response)
+    }
+
+    fn error_response(&self, error: RisuError, grpc: bool) -> Response<BufferedBody>
+    {
+        warn!("Failed to proxy request: {}", error);
+        self.metrics.errors.with_label_values(&[error.kind()]).inc();
+        error.to_response(grpc)
+    }
+}
+
+// Reads the tags of the response from the header listing them
+fn insert_tags(response: &mut Response<BufferedBody>, header: &str)
+{
+    let tags: Vec<String> = response
+        .headers()
+        .get_all(header)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split_ascii_whitespace().map(str::to_string))
+        .collect();
+    if !tags.is_empty() {
+        response.extensions_mut().insert(Tags(tags));
+    }
+}
 
-        response
+// Resolves on ctrl-c, or on SIGTERM on unix, which is how orchestrators stop processes
+async fn shutdown_signal() -> Result<(), std::io::Error>
+{
+    #[cfg(unix)]
+    {
+        let mut terminations = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())?;
+        tokio::select! {
+            result = tokio::signal::ctrl_c() => result,
+            _ = terminations.recv() => Ok(()),
+        }
     }
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await
+}
+
+fn mark_stale(response: &mut Response<BufferedBody>, warning: &'static str, reason: &'static str)
+{
+    response.headers_mut().insert(WARNING, HeaderValue::from_static(warning));
+    response.headers_mut().insert(STALE_HEADER, HeaderValue::from_static(reason));
 }
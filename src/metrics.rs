@@ -1,4 +1,6 @@
-use prometheus::{Counter, Encoder, HistogramVec, HistogramOpts, Opts, Registry, TextEncoder};
+use prometheus::{
+    Counter, CounterVec, Encoder, HistogramOpts, HistogramVec, IntGauge, IntGaugeVec, Opts, Registry, TextEncoder,
+};
 
 pub struct Metrics
 {
@@ -6,7 +8,18 @@ pub struct Metrics
     pub cache_calls: Counter,
     // cache_hits: Counter,
     pub cache_misses: Counter,
+    pub cache_coalesced: Counter,
+    pub cache_stale: Counter,
+    pub cache_stale_if_error: CounterVec,
+    pub cache_refresh_ahead: CounterVec,
+    pub cache_expired: Counter,
+    pub cache_rejections: CounterVec,
+    pub cache_tier_lookups: CounterVec,
+    pub cache_disk_writes: CounterVec,
+    pub cache_disk_bytes: IntGauge,
     pub connection_reset: Counter,
+    pub errors: CounterVec,
+    pub cache_shard_bytes: IntGaugeVec,
     // cache_evictions: Counter,
     // cache_resident_size: Counter,
     // cache_probatory_size: Counter,
@@ -32,8 +45,72 @@ impl Metrics
             .unwrap(),
             cache_calls: Counter::with_opts(Opts::new("cache_calls", "Number of cache calls")).unwrap(),
             cache_misses: Counter::with_opts(Opts::new("cache_misses", "Number of cache misses")).unwrap(),
+            cache_coalesced: Counter::with_opts(Opts::new(
+                "cache_coalesced",
+                "Number of cache misses that waited for an identical in-flight request",
+            ))
+            .unwrap(),
+            cache_stale: Counter::with_opts(Opts::new(
+                "cache_stale",
+                "Number of stale responses served while refreshing them in the background",
+            ))
+            .unwrap(),
+            cache_stale_if_error: CounterVec::new(
+                Opts::new(
+                    "cache_stale_if_error",
+                    "Number of stale responses served because upstream failed, by kind of failure",
+                ),
+                &["reason"],
+            )
+            .unwrap(),
+            cache_refresh_ahead: CounterVec::new(
+                Opts::new(
+                    "cache_refresh_ahead",
+                    "Number of refreshes of hot entries about to expire, scheduled or throttled by the rate limit",
+                ),
+                &["outcome"],
+            )
+            .unwrap(),
+            cache_expired: Counter::with_opts(Opts::new(
+                "cache_expired",
+                "Number of expired entries removed from the cache by the background sweeper",
+            ))
+            .unwrap(),
+            cache_rejections: CounterVec::new(
+                Opts::new("cache_rejections", "Number of upstream responses not admitted in the cache"),
+                &["reason"],
+            )
+            .unwrap(),
+            cache_tier_lookups: CounterVec::new(
+                Opts::new(
+                    "cache_tier_lookups",
+                    "Number of lookups in the memory (l1) and disk (l2) tiers of the cache, by outcome",
+                ),
+                &["tier", "outcome"],
+            )
+            .unwrap(),
+            cache_disk_writes: CounterVec::new(
+                Opts::new(
+                    "cache_disk_writes",
+                    "Number of entries evicted from memory, by outcome of their write to disk",
+                ),
+                &["outcome"],
+            )
+            .unwrap(),
+            cache_disk_bytes: IntGauge::with_opts(Opts::new(
+                "cache_disk_bytes",
+                "Size of the disk tier of the cache, values not compacted yet included (bytes)",
+            ))
+            .unwrap(),
             connection_reset: Counter::with_opts(Opts::new("connection_reset", "Number of connection reset (RST)"))
                 .unwrap(),
+            errors: CounterVec::new(Opts::new("errors", "Number of requests that failed, by kind"), &["kind"])
+                .unwrap(),
+            cache_shard_bytes: IntGaugeVec::new(
+                Opts::new("cache_shard_bytes", "Estimated size of the cached responses, per shard (bytes)"),
+                &["shard"],
+            )
+            .unwrap(),
             registry: Registry::new(),
         };
         metrics
@@ -48,11 +125,55 @@ impl Metrics
             .registry
             .register(Box::new(metrics.cache_misses.clone()))
             .unwrap();
+        metrics
+            .registry
+            .register(Box::new(metrics.cache_coalesced.clone()))
+            .unwrap();
+        metrics
+            .registry
+            .register(Box::new(metrics.cache_stale.clone()))
+            .unwrap();
+        metrics
+            .registry
+            .register(Box::new(metrics.cache_stale_if_error.clone()))
+            .unwrap();
+        metrics
+            .registry
+            .register(Box::new(metrics.cache_refresh_ahead.clone()))
+            .unwrap();
+        metrics
+            .registry
+            .register(Box::new(metrics.cache_expired.clone()))
+            .unwrap();
+        metrics
+            .registry
+            .register(Box::new(metrics.cache_rejections.clone()))
+            .unwrap();
+        metrics
+            .registry
+            .register(Box::new(metrics.cache_tier_lookups.clone()))
+            .unwrap();
+        metrics
+            .registry
+            .register(Box::new(metrics.cache_disk_writes.clone()))
+            .unwrap();
+        metrics
+            .registry
+            .register(Box::new(metrics.cache_disk_bytes.clone()))
+            .unwrap();
         metrics
             .registry
             .register(Box::new(metrics.connection_reset.clone()))
             .unwrap();
         metrics
+            .registry
+            .register(Box::new(metrics.errors.clone()))
+            .unwrap();
+        metrics
+            .registry
+            .register(Box::new(metrics.cache_shard_bytes.clone()))
+            .unwrap();
+        metrics
     }
 
     pub fn encode(&self) -> Vec<u8>
@@ -0,0 +1,82 @@
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use crate::{Clock, MonotonicClock};
+
+/// Token bucket allowing a number of operations per second, with bursts of up to one second worth of them.
+pub struct RateLimiter
+{
+    per_second: f64,
+    bucket: Mutex<Bucket>,
+    clock: Arc<dyn Clock>,
+}
+
+struct Bucket
+{
+    tokens: f64,
+    at: Instant,
+}
+
+impl RateLimiter
+{
+    pub fn new(per_second: u32) -> Self
+    {
+        Self {
+            per_second: per_second as f64,
+            bucket: Mutex::new(Bucket {
+                tokens: per_second as f64,
+                at: Instant::now(),
+            }),
+            clock: Arc::new(MonotonicClock),
+        }
+    }
+
+    /// Refills the bucket with the time of the given clock, instead of the monotonic clock of the system.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self
+    {
+        self.bucket.get_mut().unwrap().at = clock.now();
+        self.clock = clock;
+        self
+    }
+
+    /// Returns whether the operation is allowed, in which case it's accounted for.
+    pub fn try_acquire(&self) -> bool
+    {
+        let mut bucket = self.bucket.lock().unwrap();
+        let now = self.clock.now();
+        bucket.tokens = (bucket.tokens + (now - bucket.at).as_secs_f64() * self.per_second).min(self.per_second);
+        bucket.at = now;
+        if bucket.tokens >= 1. {
+            bucket.tokens -= 1.;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use std::time::Duration;
+
+    use super::*;
+    use crate::ManualClock;
+
+    #[test]
+    fn rate()
+    {
+        let clock = Arc::new(ManualClock::new());
+        let limiter = RateLimiter::new(10).with_clock(clock.clone());
+        assert_eq!((0..20).filter(|_| limiter.try_acquire()).count(), 10);
+        clock.advance(Duration::from_millis(150));
+        assert!(limiter.try_acquire());
+        assert!(!limiter.try_acquire());
+        // Bursts are capped at one second worth of operations
+        clock.advance(Duration::from_secs(5));
+        assert_eq!((0..20).filter(|_| limiter.try_acquire()).count(), 10);
+
+        let limiter = RateLimiter::new(0);
+        assert!(!limiter.try_acquire());
+    }
+}
@@ -0,0 +1,341 @@
+use std::io::{Error, ErrorKind};
+use std::sync::Arc;
+
+use hyper::header::HOST;
+use hyper::http::uri::{Authority, PathAndQuery, Scheme};
+use hyper::{Request, Uri, Version};
+
+use crate::config::{
+    CacheKeyConfiguration, RisuConfiguration, RouteConfiguration, UpstreamConfiguration, UpstreamProtocol,
+};
+use crate::RisuError;
+
+/// Where a request is forwarded to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Target
+{
+    pub uri: Uri,
+    pub http2: bool,
+    /// Name of the configured upstream, or `None` if the target was given by the `x-target-host` header.
+    pub upstream: Option<String>,
+    /// Parts of the request that make up its cache key.
+    pub cache_key: Arc<CacheKeyConfiguration>,
+}
+
+struct Route
+{
+    configuration: RouteConfiguration,
+    upstream: UpstreamConfiguration,
+    cache_key: Arc<CacheKeyConfiguration>,
+    authority: Option<Authority>,
+}
+
+/// Maps requests to targets, using the configured routes first, and the `x-target-host` header as a fallback.
+pub struct Router
+{
+    routes: Vec<Route>,
+    target_host_header: bool,
+    cache_key: Arc<CacheKeyConfiguration>,
+    upstream_protocol: UpstreamProtocol,
+}
+
+impl Router
+{
+    pub fn new(configuration: &RisuConfiguration) -> Result<Self, Error>
+    {
+        let cache_key = Arc::new(configuration.cache_key.clone());
+        let mut routes = Vec::with_capacity(configuration.routes.len());
+        for route in &configuration.routes {
+            let upstream = configuration.upstreams.get(&route.upstream).ok_or_else(|| {
+                Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("Route refers to an unknown upstream {}", route.upstream),
+                )
+            })?;
+            parse_scheme(&upstream.scheme).map_err(|e| Error::new(ErrorKind::InvalidInput, e.to_string()))?;
+            upstream
+                .address
+                .parse::<Authority>()
+                .map_err(|e| Error::new(ErrorKind::InvalidInput, format!("{}: {}", upstream.address, e)))?;
+            let authority = match &route.authority {
+                Some(authority) => Some(
+                    authority
+                        .parse::<Authority>()
+                        .map_err(|e| Error::new(ErrorKind::InvalidInput, format!("{}: {}", authority, e)))?,
+                ),
+                None => None,
+            };
+            routes.push(Route {
+                configuration: route.clone(),
+                upstream: upstream.clone(),
+                cache_key: match &route.cache_key {
+                    Some(route_cache_key) => Arc::new(route_cache_key.clone()),
+                    None => cache_key.clone(),
+                },
+                authority,
+            });
+        }
+
+        Ok(Self {
+            routes,
+            target_host_header: configuration.target_host_header,
+            cache_key,
+            upstream_protocol: configuration.upstream_protocol,
+        })
+    }
+
+    pub fn route<B>(&self, port: u16, request: &Request<B>) -> Result<Target, RisuError>
+    {
+        for route in &self.routes {
+            if let Some(path_and_query) = Self::matches(route, port, request) {
+                let upstream = &route.upstream;
+                let uri = Uri::builder()
+                    .scheme(upstream.scheme.as_str())
+                    .authority(upstream.address.as_str())
+                    .path_and_query(path_and_query)
+                    .build()
+                    .map_err(|e| RisuError::InvalidTarget(e.to_string()))?;
+                return Ok(Target {
+                    uri,
+                    http2: self.http2(upstream.protocol.unwrap_or(self.upstream_protocol), request),
+                    upstream: Some(route.configuration.upstream.clone()),
+                    cache_key: route.cache_key.clone(),
+                });
+            }
+        }
+
+        if self.target_host_header {
+            if let Some(target_host) = request.headers().get("x-target-host") {
+                return self.route_from_headers(target_host, request);
+            }
+        }
+
+        Err(RisuError::MissingTarget)
+    }
+
+    /// Returns the path and query to forward the request with if the route matches.
+    fn matches<B>(route: &Route, port: u16, request: &Request<B>) -> Option<PathAndQuery>
+    {
+        if route.configuration.port.is_some_and(|p| p != port) {
+            return None;
+        }
+
+        if let Some(expected) = &route.authority {
+            // HTTP/2 requests carry the :authority pseudo-header in their URI
+            let authority = match request.uri().authority() {
+                Some(authority) => authority.clone(),
+                None => request.headers().get(HOST)?.to_str().ok()?.parse::<Authority>().ok()?,
+            };
+            if !authority.host().eq_ignore_ascii_case(expected.host()) {
+                return None;
+            }
+            // Port only matters if the route specifies one
+            if expected.port_u16().is_some_and(|port| authority.port_u16() != Some(port)) {
+                return None;
+            }
+        }
+
+        let path_and_query = request.uri().path_and_query()?;
+        let path = path_and_query.path();
+
+        if route.configuration.path_prefix.as_ref().is_some_and(|prefix| !path.starts_with(prefix.as_str())) {
+            return None;
+        }
+
+        match &route.configuration.path_segment {
+            Some(segment) => {
+                // "/segment/rest" is forwarded as "/rest"
+                let rest = path.strip_prefix('/')?.strip_prefix(segment.as_str())?;
+                if !rest.is_empty() && !rest.starts_with('/') {
+                    return None;
+                }
+                let rest = if rest.is_empty() { "/" } else { rest };
+                let path_and_query = match path_and_query.query() {
+                    Some(query) => format!("{}?{}", rest, query),
+                    None => rest.to_string(),
+                };
+                path_and_query.parse().ok()
+            }
+            None => Some(path_and_query.clone()),
+        }
+    }
+
+    fn route_from_headers<B>(
+        &self, target_host: &hyper::header::HeaderValue, request: &Request<B>,
+    ) -> Result<Target, RisuError>
+    {
+        let target_host = target_host
+            .to_str()
+            .map_err(|e| RisuError::InvalidTarget(e.to_string()))?;
+
+        // The scheme is either given as a prefix of the target, or in its own header, and defaults to http
+        let (scheme, target_host) = match target_host.split_once("://") {
+            Some((scheme, target_host)) => (scheme, target_host),
+            None => match request.headers().get("x-target-scheme") {
+                Some(scheme) => (
+                    scheme
+                        .to_str()
+                        .map_err(|e| RisuError::InvalidTarget(e.to_string()))?,
+                    target_host,
+                ),
+                None => ("http", target_host),
+            },
+        };
+
+        let protocol = match request.headers().get("x-target-protocol") {
+            Some(protocol) => match protocol.as_bytes() {
+                b"http1" => UpstreamProtocol::Http1,
+                b"http2" => UpstreamProtocol::Http2,
+                _ => return Err(RisuError::InvalidTarget(format!("Unsupported protocol {:?}", protocol))),
+            },
+            None => self.upstream_protocol,
+        };
+
+        let path_and_query = request
+            .uri()
+            .path_and_query()
+            .ok_or_else(|| RisuError::InvalidTarget("Request has no path".to_string()))?;
+
+        let uri = Uri::builder()
+            .scheme(parse_scheme(scheme)?)
+            .authority(target_host)
+            .path_and_query(path_and_query.clone())
+            .build()
+            .map_err(|e| RisuError::InvalidTarget(e.to_string()))?;
+
+        Ok(Target {
+            uri,
+            http2: self.http2(protocol, request),
+            upstream: None,
+            cache_key: self.cache_key.clone(),
+        })
+    }
+
+    fn http2<B>(&self, protocol: UpstreamProtocol, request: &Request<B>) -> bool
+    {
+        match protocol {
+            UpstreamProtocol::Inbound => request.version() == Version::HTTP_2,
+            UpstreamProtocol::Http1 => false,
+            UpstreamProtocol::Http2 => true,
+        }
+    }
+}
+
+fn parse_scheme(scheme: &str) -> Result<Scheme, RisuError>
+{
+    if scheme.eq_ignore_ascii_case("http") {
+        Ok(Scheme::HTTP)
+    } else if scheme.eq_ignore_ascii_case("https") {
+        Ok(Scheme::HTTPS)
+    } else {
+        Err(RisuError::InvalidTarget(format!("Unsupported scheme {}", scheme)))
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    fn new_router(conf: &str) -> Router
+    {
+        Router::new(&serde_yaml::from_str::<RisuConfiguration>(conf).unwrap()).unwrap()
+    }
+
+    fn request(uri: &str, headers: &[(&'static str, &'static str)]) -> Request<()>
+    {
+        let mut request = Request::builder().uri(uri);
+        for (name, value) in headers {
+            request = request.header(*name, *value);
+        }
+        request.body(()).unwrap()
+    }
+
+    const CONF: &str = "upstreams:\n  \
+                          greeter:\n    \
+                            address: greeter:50051\n    \
+                            protocol: http2\n  \
+                          catalog:\n    \
+                            address: catalog.internal\n    \
+                            scheme: https\n\
+                        routes:\n  \
+                          - upstream: greeter\n    \
+                            path_prefix: /helloworld.Greeter/\n  \
+                          - upstream: catalog\n    \
+                            port: 3005\n    \
+                            path_segment: catalog\n  \
+                          - upstream: catalog\n    \
+                            authority: catalog.example.com\n    \
+                            cache_key:\n      \
+                              body: false\n  \
+                          - upstream: greeter\n    \
+                            authority: \"[::1]:3001\"\n";
+
+    #[test]
+    fn routes()
+    {
+        let router = new_router(CONF);
+
+        let target = router.route(3001, &request("/helloworld.Greeter/SayHello", &[])).unwrap();
+        assert_eq!(target.uri, "http://greeter:50051/helloworld.Greeter/SayHello");
+        assert!(target.http2);
+        assert_eq!(target.upstream.as_deref(), Some("greeter"));
+
+        // Leading path segment is stripped
+        let target = router.route(3005, &request("/catalog/items?id=42", &[])).unwrap();
+        assert_eq!(target.uri, "https://catalog.internal/items?id=42");
+        assert!(!target.http2);
+        let target = router.route(3005, &request("/catalog", &[])).unwrap();
+        assert_eq!(target.uri, "https://catalog.internal/");
+        // Only on the right port, and only for the whole segment
+        assert!(router.route(3001, &request("/catalog/items", &[])).is_err());
+        assert!(router.route(3005, &request("/catalogue/items", &[])).is_err());
+
+        // Authority, either from the URI or the Host header
+        let target = router.route(3001, &request("http://catalog.example.com:3001/items", &[])).unwrap();
+        assert_eq!(target.uri, "https://catalog.internal/items");
+        let target = router.route(3001, &request("/items", &[("host", "Catalog.Example.com")])).unwrap();
+        assert_eq!(target.uri, "https://catalog.internal/items");
+        assert!(!target.cache_key.body);
+        // Global cache key otherwise
+        assert!(router.route(3005, &request("/catalog", &[])).unwrap().cache_key.body);
+
+        // IPv6 literals keep their colons
+        let target = router.route(3001, &request("/items", &[("host", "[::1]:3001")])).unwrap();
+        assert_eq!(target.uri, "http://greeter:50051/items");
+        assert!(router.route(3001, &request("/items", &[("host", "[::1]:3002")])).is_err());
+        assert!(router.route(3001, &request("/items", &[("host", "[::2]:3001")])).is_err());
+    }
+
+    #[test]
+    fn target_host_header()
+    {
+        let router = new_router(CONF);
+        let target = router
+            .route(3001, &request("/hello?world", &[("x-target-host", "https://example.com")]))
+            .unwrap();
+        assert_eq!(target.uri, "https://example.com/hello?world");
+        assert_eq!(target.upstream, None);
+
+        // Routes take precedence
+        let target = router
+            .route(3001, &request("/helloworld.Greeter/SayHello", &[("x-target-host", "example.com")]))
+            .unwrap();
+        assert_eq!(target.upstream.as_deref(), Some("greeter"));
+
+        assert_eq!(router.route(3001, &request("/hello", &[])), Err(RisuError::MissingTarget));
+
+        let router = new_router(&format!("{}target_host_header: false", CONF));
+        assert_eq!(
+            router.route(3001, &request("/hello", &[("x-target-host", "example.com")])),
+            Err(RisuError::MissingTarget)
+        );
+    }
+
+    #[test]
+    fn unknown_upstream()
+    {
+        let configuration = serde_yaml::from_str::<RisuConfiguration>("routes:\n  - upstream: nope").unwrap();
+        assert!(Router::new(&configuration).is_err());
+    }
+}
@@ -0,0 +1,334 @@
+use std::fs::File;
+use std::hash::Hasher;
+use std::io::{Error, ErrorKind, Write};
+use std::ops::Deref;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use bytes::BufMut;
+use gxhash::GxHasher;
+use hyper::header::{HeaderName, HeaderValue};
+use hyper::{HeaderMap, Response, StatusCode};
+
+use crate::buffered_body::BufferedBody;
+use crate::Expiry;
+
+// A snapshot is a header, then records made of their length, their content, and a checksum of their content:
+// header:  magic (8) | version (u32) | saved at, in ms since the unix epoch (u64) | records (u64) | checksum (u64)
+// record:  key (u128) | ttl, stale-while-revalidate, stale-if-error, in ms (u64 each) | age, in s (u64)
+//          | status (u16) | headers | body length (u64) | body | has trailers (u8) | trailers
+// headers: count (u32), then for each: name length (u16) | name | value length (u32) | value
+// Integers are little endian. Any change to the format must bump the version, so that older snapshots are ignored.
+const MAGIC: &[u8; 8] = b"RISUSNAP";
+const VERSION: u32 = 1;
+const HEADER_SIZE: usize = MAGIC.len() + 4 + 8 + 8;
+
+/// A cached response, as saved in a snapshot or loaded from it.
+pub struct SnapshotEntry<R = Response<BufferedBody>>
+{
+    pub key: u128,
+    /// What's left of the expiry of the entry.
+    pub expiry: Expiry,
+    /// Age of the response, as told by the `Age` header.
+    pub age: u64,
+    pub response: R,
+}
+
+/// Saves the entries, replacing the file only once they're all written.
+pub fn write<R>(path: &Path, entries: &[SnapshotEntry<R>]) -> Result<(), Error>
+where
+    R: Deref<Target = Response<BufferedBody>>,
+{
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(".tmp");
+
+    let mut file = File::create(&temporary)?;
+    file.write_all(&to_bytes(entries, SystemTime::now()))?;
+    file.sync_all()?;
+    std::fs::rename(temporary, path)
+}
+
+/// Loads the entries, skipping those which expired since they were saved. Fails if the file isn't a snapshot of the
+/// current version, or if it's corrupted.
+pub fn read(path: &Path) -> Result<Vec<SnapshotEntry>, Error>
+{
+    from_bytes(&std::fs::read(path)?, SystemTime::now())
+}
+
+fn to_bytes<R>(entries: &[SnapshotEntry<R>], now: SystemTime) -> Vec<u8>
+where
+    R: Deref<Target = Response<BufferedBody>>,
+{
+    let mut bytes = Vec::new();
+    bytes.put_slice(MAGIC);
+    bytes.put_u32_le(VERSION);
+    bytes.put_u64_le(millis(now.duration_since(UNIX_EPOCH).unwrap_or_default()));
+    bytes.put_u64_le(entries.len() as u64);
+    bytes.put_u64_le(checksum(&bytes));
+
+    let mut record = Vec::new();
+    for entry in entries {
+        record.clear();
+        record.put_u128_le(entry.key);
+        record.put_u64_le(millis(entry.expiry.ttl));
+        record.put_u64_le(millis(entry.expiry.stale_while_revalidate));
+        record.put_u64_le(millis(entry.expiry.stale_if_error));
+        put_response(&mut record, &entry.response, entry.age);
+
+        bytes.put_u64_le(record.len() as u64);
+        bytes.put_slice(&record);
+        bytes.put_u64_le(checksum(&record));
+    }
+    bytes
+}
+
+fn from_bytes(mut bytes: &[u8], now: SystemTime) -> Result<Vec<SnapshotEntry>, Error>
+{
+    let header = take(&mut bytes, HEADER_SIZE)?;
+    if &header[..MAGIC.len()] != MAGIC {
+        return Err(invalid_data("Not a snapshot"));
+    }
+    let mut fields = &header[MAGIC.len()..];
+    let version = u32::from_le_bytes(take_array(&mut fields)?);
+    if version != VERSION {
+        return Err(invalid_data(format!("Unsupported snapshot version {}", version)));
+    }
+    if u64::from_le_bytes(take_array(&mut bytes)?) != checksum(header) {
+        return Err(invalid_data("Corrupted snapshot header"));
+    }
+    let saved_at = UNIX_EPOCH + duration(u64::from_le_bytes(take_array(&mut fields)?));
+    let count = u64::from_le_bytes(take_array(&mut fields)?);
+    let downtime = now.duration_since(saved_at).unwrap_or_default();
+
+    let mut entries = Vec::new();
+    for _ in 0..count {
+        let length = u64::from_le_bytes(take_array(&mut bytes)?);
+        let record = take(&mut bytes, usize::try_from(length).map_err(invalid_data)?)?;
+        if u64::from_le_bytes(take_array(&mut bytes)?) != checksum(record) {
+            return Err(invalid_data("Corrupted snapshot record"));
+        }
+        entries.extend(read_record(record, downtime)?);
+    }
+    if !bytes.is_empty() {
+        return Err(invalid_data("Unexpected data after the snapshot records"));
+    }
+    Ok(entries)
+}
+
+fn read_record(mut record: &[u8], downtime: Duration) -> Result<Option<SnapshotEntry>, Error>
+{
+    let key = u128::from_le_bytes(take_array(&mut record)?);
+    let ttl = duration(u64::from_le_bytes(take_array(&mut record)?));
+    let stale_while_revalidate = duration(u64::from_le_bytes(take_array(&mut record)?));
+    let stale_if_error = duration(u64::from_le_bytes(take_array(&mut record)?));
+
+    let expiry = Expiry::new(ttl)
+        .with_stale_while_revalidate(stale_while_revalidate)
+        .with_stale_if_error(stale_if_error);
+    let Some(expiry) = expiry.remaining(downtime) else {
+        return Ok(None);
+    };
+
+    let (response, age) = decode_response(record)?;
+    Ok(Some(SnapshotEntry {
+        key,
+        expiry,
+        age: age.saturating_add(downtime.as_secs()),
+        response,
+    }))
+}
+
+/// Serializes the response and its age, as in the records of snapshots.
+pub fn encode_response(response: &Response<BufferedBody>, age: u64) -> Vec<u8>
+{
+    let mut bytes = Vec::new();
+    put_response(&mut bytes, response, age);
+    bytes
+}
+
+/// Deserializes a response and its age, as serialized by [`encode_response`].
+pub fn decode_response(mut bytes: &[u8]) -> Result<(Response<BufferedBody>, u64), Error>
+{
+    let age = u64::from_le_bytes(take_array(&mut bytes)?);
+    let status = StatusCode::from_u16(u16::from_le_bytes(take_array(&mut bytes)?)).map_err(invalid_data)?;
+    let headers = take_headers(&mut bytes)?;
+    let length = u64::from_le_bytes(take_array(&mut bytes)?);
+    let body = take(&mut bytes, usize::try_from(length).map_err(invalid_data)?)?;
+    let trailers = match take_array::<1>(&mut bytes)? {
+        [0] => None,
+        _ => Some(take_headers(&mut bytes)?),
+    };
+
+    let mut response = Response::new(BufferedBody::from_bytes(body).with_trailers(trailers));
+    *response.status_mut() = status;
+    *response.headers_mut() = headers;
+    Ok((response, age))
+}
+
+fn put_response(bytes: &mut Vec<u8>, response: &Response<BufferedBody>, age: u64)
+{
+    bytes.put_u64_le(age);
+    bytes.put_u16_le(response.status().as_u16());
+    put_headers(bytes, response.headers());
+    let body = response.body();
+    bytes.put_u64_le(body.len() as u64);
+    bytes.put_slice(body.bytes());
+    match body.trailers() {
+        Some(trailers) => {
+            bytes.put_u8(1);
+            put_headers(bytes, trailers);
+        }
+        None => bytes.put_u8(0),
+    }
+}
+
+fn put_headers(bytes: &mut Vec<u8>, headers: &HeaderMap)
+{
+    bytes.put_u32_le(headers.len() as u32);
+    for (name, value) in headers {
+        bytes.put_u16_le(name.as_str().len() as u16);
+        bytes.put_slice(name.as_str().as_bytes());
+        bytes.put_u32_le(value.len() as u32);
+        bytes.put_slice(value.as_bytes());
+    }
+}
+
+fn take_headers(bytes: &mut &[u8]) -> Result<HeaderMap, Error>
+{
+    let count = u32::from_le_bytes(take_array(bytes)?);
+    let mut headers = HeaderMap::new();
+    for _ in 0..count {
+        let length = u16::from_le_bytes(take_array(bytes)?);
+        let name = HeaderName::from_bytes(take(bytes, length as usize)?).map_err(invalid_data)?;
+        let length = u32::from_le_bytes(take_array(bytes)?);
+        let value = HeaderValue::from_bytes(take(bytes, length as usize)?).map_err(invalid_data)?;
+        headers.append(name, value);
+    }
+    Ok(headers)
+}
+
+fn take<'a>(bytes: &mut &'a [u8], length: usize) -> Result<&'a [u8], Error>
+{
+    if bytes.len() < length {
+        return Err(invalid_data("Truncated snapshot"));
+    }
+    let (taken, rest) = bytes.split_at(length);
+    *bytes = rest;
+    Ok(taken)
+}
+
+fn take_array<const N: usize>(bytes: &mut &[u8]) -> Result<[u8; N], Error>
+{
+    Ok(take(bytes, N)?.try_into().unwrap())
+}
+
+fn checksum(bytes: &[u8]) -> u64
+{
+    let mut hasher = GxHasher::with_seed(123);
+    hasher.write(bytes);
+    hasher.finish()
+}
+
+// Durations are saved in milliseconds, the longest ones standing for "forever"
+fn millis(duration: Duration) -> u64
+{
+    u64::try_from(duration.as_millis()).unwrap_or(u64::MAX)
+}
+
+fn duration(millis: u64) -> Duration
+{
+    match millis {
+        u64::MAX => Duration::MAX,
+        millis => Duration::from_millis(millis),
+    }
+}
+
+fn invalid_data<E>(error: E) -> Error
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    Error::new(ErrorKind::InvalidData, error)
+}
+
+#[cfg(test)]
+mod tests
+{
+    use std::sync::Arc;
+
+    use super::*;
+
+    fn entry(key: u128, ttl: u64, body: &[u8]) -> SnapshotEntry<Arc<Response<BufferedBody>>>
+    {
+        let mut trailers = HeaderMap::new();
+        trailers.insert("grpc-status", HeaderValue::from_static("0"));
+        let mut response = Response::new(BufferedBody::from_bytes(body).with_trailers(Some(trailers)));
+        *response.status_mut() = StatusCode::NOT_FOUND;
+        response.headers_mut().append("surrogate-key", HeaderValue::from_static("a"));
+        response.headers_mut().append("surrogate-key", HeaderValue::from_static("b"));
+        SnapshotEntry {
+            key,
+            expiry: Expiry::new(Duration::from_secs(ttl)).with_stale_if_error(Duration::MAX),
+            age: 3,
+            response: Arc::new(response),
+        }
+    }
+
+    #[test]
+    fn roundtrip()
+    {
+        let saved_at = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let bytes = to_bytes(&[entry(1, 60, b"hello"), entry(u128::MAX, 5, b"")], saved_at);
+
+        let entries = from_bytes(&bytes, saved_at + Duration::from_secs(10)).unwrap();
+        assert_eq!(entries.len(), 2);
+        let (fresh, stale) = (&entries[0], &entries[1]);
+        assert_eq!((fresh.key, stale.key), (1, u128::MAX));
+        // The time since the snapshot was saved is accounted for
+        assert_eq!(fresh.expiry.ttl, Duration::from_secs(50));
+        assert_eq!(stale.expiry.ttl, Duration::ZERO);
+        assert_eq!(stale.expiry.stale_if_error, Duration::MAX - Duration::from_secs(5));
+        assert_eq!(fresh.age, 13);
+        assert_eq!(fresh.response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(fresh.response.headers().get_all("surrogate-key").iter().count(), 2);
+        assert_eq!(fresh.response.body().bytes(), b"hello");
+        assert_eq!(fresh.response.body().trailers().unwrap()["grpc-status"], "0");
+        assert!(stale.response.body().is_empty());
+    }
+
+    #[test]
+    fn expired()
+    {
+        let saved_at = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let mut expiring = entry(1, 5, b"hello");
+        expiring.expiry = Expiry::new(Duration::from_secs(5));
+        let bytes = to_bytes(&[expiring, entry(2, 60, b"world")], saved_at);
+
+        let entries = from_bytes(&bytes, saved_at + Duration::from_secs(10)).unwrap();
+        assert_eq!(entries.iter().map(|entry| entry.key).collect::<Vec<_>>(), vec![2]);
+    }
+
+    #[test]
+    fn incompatible()
+    {
+        let saved_at = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let bytes = to_bytes(&[entry(1, 60, b"hello")], saved_at);
+        assert!(from_bytes(&bytes, saved_at).is_ok());
+
+        // Corrupted record
+        let mut corrupted = bytes.clone();
+        *corrupted.last_mut().unwrap() ^= 1;
+        assert!(from_bytes(&corrupted, saved_at).is_err());
+        // Corrupted header
+        let mut corrupted = bytes.clone();
+        corrupted[HEADER_SIZE - 1] ^= 1;
+        assert!(from_bytes(&corrupted, saved_at).is_err());
+        // Other version
+        let mut other = bytes.clone();
+        other[MAGIC.len()] += 1;
+        assert!(from_bytes(&other, saved_at).is_err());
+        // Truncated or extended
+        assert!(from_bytes(&bytes[..bytes.len() - 1], saved_at).is_err());
+        assert!(from_bytes(&[&bytes[..], &[0]].concat(), saved_at).is_err());
+        assert!(from_bytes(b"hello", saved_at).is_err());
+    }
+}
@@ -0,0 +1,214 @@
+use std::net::IpAddr;
+
+use hyper::http::uri::Scheme;
+use hyper::Uri;
+use tokio::net::lookup_host;
+
+use crate::config::{RisuConfiguration, TargetListConfiguration};
+use crate::RisuError;
+
+/// Keeps risu from being an open proxy, by filtering the targets clients give with the `x-target-host` header.
+pub struct TargetFilter
+{
+    allowlist: TargetListConfiguration,
+    denylist: TargetListConfiguration,
+}
+
+impl TargetFilter
+{
+    pub fn new(configuration: &RisuConfiguration) -> Self
+    {
+        Self {
+            allowlist: normalize(&configuration.target_allowlist),
+            denylist: normalize(&configuration.target_denylist),
+        }
+    }
+
+    /// Fails with `ForbiddenTarget` unless the target matches the allowlist and doesn't match the denylist.
+    /// Names are only resolved if networks are configured.
+    pub async fn check(&self, uri: &Uri) -> Result<(), RisuError>
+    {
+        let host = uri
+            .host()
+            .ok_or_else(|| RisuError::InvalidTarget("Target has no host".to_string()))?;
+        let host = normalize_host(host.trim_start_matches('[').trim_end_matches(']'));
+        let port = uri
+            .port_u16()
+            .unwrap_or(if uri.scheme() == Some(&Scheme::HTTPS) { 443 } else { 80 });
+
+        let addresses = self.resolve(&host, port).await?;
+
+        // All the addresses must be allowed, since we don't know which one the connector will pick
+        let host_allowed = matches_host(&self.allowlist, &host)
+            || !addresses.is_empty() && addresses.iter().all(|a| matches_address(&self.allowlist, a));
+        let port_allowed = self.allowlist.ports.is_empty() || matches_port(&self.allowlist, port);
+        if !host_allowed || !port_allowed {
+            let message = format!("{}:{} is not in the allowlist", host, port);
+            return Err(RisuError::ForbiddenTarget(message));
+        }
+
+        if matches_host(&self.denylist, &host) {
+            return Err(RisuError::ForbiddenTarget(format!("{} is in the denylist", host)));
+        }
+        if let Some(address) = addresses.iter().find(|a| matches_address(&self.denylist, a)) {
+            let message = format!("{} resolves to {}, which is in the denylist", host, address);
+            return Err(RisuError::ForbiddenTarget(message));
+        }
+        if matches_port(&self.denylist, port) {
+            return Err(RisuError::ForbiddenTarget(format!("Port {} is in the denylist", port)));
+        }
+
+        Ok(())
+    }
+
+    // Note that the connector resolves the name again, so a target switching to a denied address in between
+    // (DNS rebinding) isn't caught. Denying by name is not affected.
+    async fn resolve(&self, host: &str, port: u16) -> Result<Vec<IpAddr>, RisuError>
+    {
+        if let Ok(address) = host.parse::<IpAddr>() {
+            return Ok(vec![canonical(address)]);
+        }
+        if self.allowlist.networks.is_empty() && self.denylist.networks.is_empty() {
+            return Ok(vec![]);
+        }
+        let addresses = lookup_host((host, port))
+            .await
+            .map_err(|e| RisuError::UpstreamConnection(format!("Failed to resolve {}: {}", host, e)))?;
+        Ok(addresses.map(|address| canonical(address.ip())).collect())
+    }
+}
+
+fn normalize(list: &TargetListConfiguration) -> TargetListConfiguration
+{
+    TargetListConfiguration {
+        hosts: list.hosts.iter().map(|host| normalize_host(host)).collect(),
+        ..list.clone()
+    }
+}
+
+// Names are case-insensitive, and may be written fully qualified
+fn normalize_host(host: &str) -> String
+{
+    host.trim_end_matches('.').to_ascii_lowercase()
+}
+
+// IPv4-mapped IPv6 addresses must match IPv4 networks
+fn canonical(address: IpAddr) -> IpAddr
+{
+    match address {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(address),
+        IpAddr::V4(_) => address,
+    }
+}
+
+fn matches_host(list: &TargetListConfiguration, host: &str) -> bool
+{
+    list.hosts.iter().any(|pattern| match pattern.strip_prefix('*') {
+        Some("") => true,
+        // "*.example.com" matches subdomains, but not example.com itself
+        Some(suffix) if suffix.starts_with('.') => host.len() > suffix.len() && host.ends_with(suffix),
+        _ => pattern == host,
+    })
+}
+
+fn matches_address(list: &TargetListConfiguration, address: &IpAddr) -> bool
+{
+    list.networks.iter().any(|network| network.contains(address))
+}
+
+fn matches_port(list: &TargetListConfiguration, port: u16) -> bool
+{
+    list.ports.iter().any(|range| range.contains(port))
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    fn new_filter(conf: &str) -> TargetFilter
+    {
+        TargetFilter::new(&serde_yaml::from_str::<RisuConfiguration>(conf).unwrap())
+    }
+
+    async fn allowed(filter: &TargetFilter, uri: &str) -> bool
+    {
+        match filter.check(&uri.parse().unwrap()).await {
+            Ok(()) => true,
+            Err(RisuError::ForbiddenTarget(_)) => false,
+            Err(e) => panic!("Unexpected error {:?}", e),
+        }
+    }
+
+    #[tokio::test]
+    async fn deny_by_default()
+    {
+        let filter = new_filter("{}");
+        assert!(!allowed(&filter, "http://example.com/").await);
+        assert!(!allowed(&filter, "http://127.0.0.1/").await);
+    }
+
+    #[tokio::test]
+    async fn hosts()
+    {
+        let filter = new_filter(
+            "target_allowlist:\n  \
+               hosts: [Example.com, \"*.internal\", \"::1\"]\n\
+             target_denylist:\n  \
+               hosts: [admin.internal]",
+        );
+        assert!(allowed(&filter, "http://example.com/").await);
+        assert!(allowed(&filter, "https://EXAMPLE.com.:8443/").await);
+        assert!(!allowed(&filter, "http://www.example.com/").await);
+        assert!(allowed(&filter, "http://catalog.internal/").await);
+        assert!(allowed(&filter, "http://a.b.internal/").await);
+        assert!(!allowed(&filter, "http://internal/").await);
+        assert!(!allowed(&filter, "http://evilinternal/").await);
+        assert!(!allowed(&filter, "http://admin.internal/").await);
+        assert!(allowed(&filter, "http://[::1]:8080/").await);
+    }
+
+    #[tokio::test]
+    async fn networks()
+    {
+        let filter = new_filter(
+            "target_allowlist:\n  \
+               networks: [127.0.0.0/8, \"::1/128\"]\n\
+             target_denylist:\n  \
+               networks: [127.0.0.2/32]",
+        );
+        assert!(allowed(&filter, "http://127.0.0.1/").await);
+        assert!(allowed(&filter, "http://[::ffff:127.0.0.1]/").await);
+        assert!(!allowed(&filter, "http://127.0.0.2/").await);
+        assert!(!allowed(&filter, "http://10.0.0.1/").await);
+        // After resolution
+        assert!(allowed(&filter, "http://localhost/").await);
+
+        let filter = new_filter(
+            "target_allowlist:\n  \
+               hosts: [\"*\"]\n\
+             target_denylist:\n  \
+               networks: [127.0.0.0/8, \"::1/128\"]",
+        );
+        assert!(allowed(&filter, "http://10.0.0.1/").await);
+        assert!(!allowed(&filter, "http://localhost/").await);
+    }
+
+    #[tokio::test]
+    async fn ports()
+    {
+        let filter = new_filter(
+            "target_allowlist:\n  \
+               hosts: [\"*\"]\n  \
+               ports: [80, 443, { from: 8000, to: 8999 }]\n\
+             target_denylist:\n  \
+               ports: [8080]",
+        );
+        assert!(allowed(&filter, "http://example.com/").await);
+        assert!(allowed(&filter, "https://example.com/").await);
+        assert!(allowed(&filter, "http://example.com:8000/").await);
+        assert!(!allowed(&filter, "http://example.com:8080/").await);
+        assert!(!allowed(&filter, "https://example.com:9000/").await);
+        assert!(!allowed(&filter, "http://example.com:22/").await);
+    }
+}
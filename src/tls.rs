@@ -0,0 +1,92 @@
+use std::fs::File;
+use std::io::{BufReader, Error, ErrorKind};
+use std::sync::Arc;
+
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{ClientConfig, RootCertStore, ServerConfig};
+
+use crate::config::{ListenerTlsConfiguration, UpstreamTlsConfiguration};
+
+/// Builds the TLS configuration used to reach https targets.
+/// Application protocols are negotiated by the connector, depending on the enabled HTTP versions.
+pub fn client_config(configuration: &UpstreamTlsConfiguration) -> Result<ClientConfig, Error>
+{
+    let mut roots = RootCertStore::empty();
+    match &configuration.ca_file {
+        Some(ca_file) => {
+            for certificate in load_certificates(ca_file)? {
+                roots.add(certificate).map_err(invalid_data)?;
+            }
+        }
+        None => roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
+    }
+
+    let builder = ClientConfig::builder().with_root_certificates(Arc::new(roots));
+
+    let mut config = match (&configuration.client_certificate_file, &configuration.client_key_file) {
+        (Some(certificate_file), Some(key_file)) => builder
+            .with_client_auth_cert(load_certificates(certificate_file)?, load_private_key(key_file)?)
+            .map_err(invalid_data)?,
+        (None, None) => builder.with_no_client_auth(),
+        _ => {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "Both a client certificate and a client key are required for mTLS",
+            ))
+        }
+    };
+    config.enable_sni = configuration.sni;
+
+    Ok(config)
+}
+
+/// Builds the TLS configuration of the listener. Both h2 and http/1.1 are offered through ALPN.
+pub fn server_config(configuration: &ListenerTlsConfiguration) -> Result<ServerConfig, Error>
+{
+    let builder = match &configuration.client_ca_file {
+        Some(client_ca_file) => {
+            let mut roots = RootCertStore::empty();
+            for certificate in load_certificates(client_ca_file)? {
+                roots.add(certificate).map_err(invalid_data)?;
+            }
+            let verifier = WebPkiClientVerifier::builder(Arc::new(roots))
+                .build()
+                .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+            ServerConfig::builder().with_client_cert_verifier(verifier)
+        }
+        None => ServerConfig::builder().with_no_client_auth(),
+    };
+
+    let mut config = builder
+        .with_single_cert(
+            load_certificates(&configuration.certificate_file)?,
+            load_private_key(&configuration.key_file)?,
+        )
+        .map_err(invalid_data)?;
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
+    Ok(config)
+}
+
+pub fn load_certificates(path: &str) -> Result<Vec<CertificateDer<'static>>, Error>
+{
+    let mut reader = BufReader::new(File::open(path)?);
+    let certificates = rustls_pemfile::certs(&mut reader).collect::<Result<Vec<_>, _>>()?;
+    if certificates.is_empty() {
+        return Err(Error::new(ErrorKind::InvalidData, format!("No certificate found in {}", path)));
+    }
+    Ok(certificates)
+}
+
+pub fn load_private_key(path: &str) -> Result<PrivateKeyDer<'static>, Error>
+{
+    let mut reader = BufReader::new(File::open(path)?);
+    rustls_pemfile::private_key(&mut reader)?
+        .ok_or_else(|| Error::new(ErrorKind::InvalidData, format!("No private key found in {}", path)))
+}
+
+fn invalid_data(e: rustls::Error) -> Error
+{
+    Error::new(ErrorKind::InvalidData, e)
+}
@@ -0,0 +1,137 @@
+use std::hash::Hash;
+use std::sync::Arc;
+use std::time::Duration;
+
+use gxhash::GxHasher;
+use hyper::header::{HeaderName, VARY};
+use hyper::HeaderMap;
+
+use crate::caches::lru::ExpirationType;
+use crate::{Cache, LruCache, PassthroughBuildHasher, ShardedCache};
+
+/// Remembers which request headers the responses for a primary key vary on, as told by their `Vary` header.
+/// Lookups for a primary key use the key of the variant selected by these headers.
+pub struct VariantTable
+{
+    headers: ShardedCache<u128, Vec<HeaderName>, LruCache<u128, Vec<HeaderName>>>,
+    none: Arc<Vec<HeaderName>>,
+}
+
+impl VariantTable
+{
+    pub fn new(shards: usize, max_size: usize, expiration: Duration) -> Self
+    {
+        Self {
+            headers: ShardedCache::with_hasher(shards, PassthroughBuildHasher, || {
+                LruCache::new(max_size, expiration, ExpirationType::Sliding)
+            }),
+            none: Arc::default(),
+        }
+    }
+
+    /// Returns the headers the responses for the primary key vary on, which is none if unknown.
+    pub fn get(&self, primary_key: u128) -> Arc<Vec<HeaderName>>
+    {
+        self.headers.try_get(&primary_key).unwrap_or_else(|| self.none.clone())
+    }
+
+    pub fn set(&self, primary_key: u128, headers: Vec<HeaderName>)
+    {
+        // A fresh entry isn't replaced by adding it again
+        self.headers.remove(&primary_key);
+        self.headers.try_add(primary_key, headers);
+    }
+}
+
+/// Returns the request headers the response varies on, sorted, or `None` if it varies on anything (`Vary: *`).
+pub fn varies_on(headers: &HeaderMap) -> Option<Vec<HeaderName>>
+{
+    let mut names = vec![];
+    for value in headers.get_all(VARY) {
+        // Not a valid list of header names, so it can't be honored
+        let value = value.to_str().ok()?;
+        for name in value.split(',').map(str::trim).filter(|name| !name.is_empty()) {
+            if name == "*" {
+                return None;
+            }
+            names.push(HeaderName::from_bytes(name.as_bytes()).ok()?);
+        }
+    }
+    names.sort_unstable_by(|a, b| a.as_str().cmp(b.as_str()));
+    names.dedup();
+    Some(names)
+}
+
+/// Returns the key of the variant selected by the request values of the given headers.
+/// Without headers, there's a single variant, whose key is the primary key.
+pub fn variant_key(primary_key: u128, headers: &[HeaderName], request_headers: &HeaderMap) -> u128
+{
+    if headers.is_empty() {
+        return primary_key;
+    }
+
+    let mut hasher = GxHasher::with_seed(123);
+    primary_key.hash(&mut hasher);
+    for name in headers {
+        name.as_str().hash(&mut hasher);
+        let values: Vec<&[u8]> = request_headers.get_all(name).iter().map(|v| v.as_bytes()).collect();
+        values.hash(&mut hasher);
+    }
+    hasher.finish_u128()
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    fn headers(headers: &[(&'static str, &'static str)]) -> HeaderMap
+    {
+        let mut map = HeaderMap::new();
+        for (name, value) in headers {
+            map.append(*name, value.parse().unwrap());
+        }
+        map
+    }
+
+    #[test]
+    fn parsing()
+    {
+        assert_eq!(varies_on(&headers(&[])), Some(vec![]));
+        assert_eq!(
+            varies_on(&headers(&[("vary", "Accept-Language, accept-encoding"), ("vary", "accept-language")])),
+            Some(vec![HeaderName::from_static("accept-encoding"), HeaderName::from_static("accept-language")])
+        );
+        assert_eq!(varies_on(&headers(&[("vary", "accept-encoding, *")])), None);
+    }
+
+    #[test]
+    fn keys()
+    {
+        let accept_language = [HeaderName::from_static("accept-language")];
+        let fr = headers(&[("accept-language", "fr"), ("user-agent", "a")]);
+        assert_eq!(variant_key(42, &[], &fr), 42);
+        assert_ne!(variant_key(42, &accept_language, &fr), 42);
+        assert_eq!(
+            variant_key(42, &accept_language, &fr),
+            variant_key(42, &accept_language, &headers(&[("accept-language", "fr"), ("user-agent", "b")]))
+        );
+        assert_ne!(
+            variant_key(42, &accept_language, &fr),
+            variant_key(42, &accept_language, &headers(&[("accept-language", "en")]))
+        );
+        assert_ne!(variant_key(42, &accept_language, &fr), variant_key(42, &accept_language, &headers(&[])));
+    }
+
+    #[test]
+    fn table()
+    {
+        let table = VariantTable::new(2, 10, Duration::MAX);
+        assert!(table.get(1).is_empty());
+        table.set(1, vec![HeaderName::from_static("accept-encoding")]);
+        assert_eq!(*table.get(1), vec![HeaderName::from_static("accept-encoding")]);
+        table.set(1, vec![]);
+        assert!(table.get(1).is_empty());
+        assert!(table.get(2).is_empty());
+    }
+}
@@ -1,11 +1,24 @@
 include!("../proto/helloworld.rs");
 
-use std::{clone, time::Duration};
+use std::future::Future;
+use std::net::SocketAddr;
+use std::{clone, sync::Arc, time::Duration};
 
+use bytes::Bytes;
 use greeter_client::GreeterClient;
 use greeter_server::{Greeter, GreeterServer};
-use risu::{self, RisuServer};
+use http_body_util::{BodyExt, Empty, Full};
+use hyper::service::service_fn;
+use hyper::{HeaderMap, StatusCode};
+use hyper_util::client::legacy::connect::HttpConnector;
+use hyper_util::client::legacy::Client;
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper_util::server::conn::auto;
+use risu::{self, ManualClock, RisuConfiguration, RisuServer};
+use tokio::net::TcpListener;
 use tokio::sync::oneshot;
+use tokio_rustls::rustls::{self, pki_types::PrivatePkcs8KeyDer};
+use tokio_rustls::TlsAcceptor;
 use tonic::{metadata::MetadataMap, metadata::MetadataValue, transport::Server, Extensions, Request, Response, Status};
 use warp::Filter;
 
@@ -35,16 +48,64 @@ pub struct TestServer
 
 impl TestServer
 {
-    pub fn new_grpc() -> Self
+    /// Serves the greeter on a free port, returned along with the server
+    pub fn new_grpc() -> (Self, u16)
     {
-        Self::start(Server::builder()
-            .add_service(GreeterServer::new(MyGreeter::default()))
-            .serve("127.0.0.1:3002".parse().unwrap()))
+        let listener = bind_free();
+        let port = listener.local_addr().unwrap().port();
+        let server = Self::start(async move {
+            let listener = TcpListener::from_std(listener).unwrap();
+            let incoming = futures::stream::unfold(listener, |listener| async move {
+                Some((listener.accept().await.map(|(stream, _)| stream), listener))
+            });
+            Server::builder()
+                .add_service(GreeterServer::new(MyGreeter::default()))
+                .serve_with_incoming(incoming)
+                .await
+        });
+        (server, port)
     }
 
-    pub fn new_risu() -> Self
+    /// Serves "Hello over TLS!" over https on a free port, negotiating h2 or http/1.1 through ALPN
+    pub fn new_https(certified: rcgen::CertifiedKey) -> (Self, u16)
     {
-        Self::start(RisuServer::start_from_config_file("tests/config.yaml"))
+        let certificates = vec![certified.cert.der().clone()];
+        let key = PrivatePkcs8KeyDer::from(certified.key_pair.serialize_der()).into();
+        let mut tls = rustls::ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(certificates, key)
+            .unwrap();
+        tls.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+        let acceptor = TlsAcceptor::from(Arc::new(tls));
+
+        let listener = bind_free();
+        let port = listener.local_addr().unwrap().port();
+        let server = Self::start(async move {
+            let listener = TcpListener::from_std(listener).unwrap();
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let acceptor = acceptor.clone();
+                tokio::spawn(async move {
+                    let stream = acceptor.accept(stream).await.unwrap();
+                    let service = service_fn(|_| async {
+                        Ok::<_, std::convert::Infallible>(hyper::Response::new(Full::new(Bytes::from(
+                            "Hello over TLS!",
+                        ))))
+                    });
+                    let _ = auto::Builder::new(TokioExecutor::new())
+                        .serve_connection(TokioIo::new(stream), service)
+                        .await;
+                });
+            }
+        });
+        (server, port)
+    }
+
+    /// Serves a warp filter bound with `warp::serve(filter).bind_ephemeral(..)`, and returns its address as a target
+    pub fn new_warp(bound: (SocketAddr, impl Future<Output = ()> + Send + 'static)) -> (Self, String)
+    {
+        let (address, server) = bound;
+        (Self::start(server), address.to_string())
     }
 
     fn start<F>(fut: F) -> Self
@@ -73,6 +134,156 @@ impl TestServer
     }
 }
 
+/// Risu started on ports of its own, with a client to send it plain http requests.
+pub struct Risu
+{
+    server: TestServer,
+    pub port: u16,
+    pub prometheus_port: u16,
+    pub admin_port: u16,
+    client: Client<HttpConnector, Empty<Bytes>>,
+}
+
+/// Response of risu, with its whole body.
+#[derive(Debug)]
+pub struct Reply
+{
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: String,
+}
+
+/// Starts risu with the configuration, which mustn't set its ports, and waits until it accepts connections.
+pub async fn start_risu(config: &str) -> Risu
+{
+    Risu::start(config, None).await
+}
+
+/// Same as [`start_risu`], with responses expiring when the clock is advanced rather than in real time.
+pub async fn start_risu_with_clock(config: &str, clock: Arc<ManualClock>) -> Risu
+{
+    Risu::start(config, Some(clock)).await
+}
+
+impl Risu
+{
+    async fn start(config: &str, clock: Option<Arc<ManualClock>>) -> Self
+    {
+        let [port, prometheus_port, healthcheck_port, admin_port] = [(); 4].map(|_| free_port());
+        let config = format!(
+            "listening_port: {}\n\
+             prometheus_port: {}\n\
+             healthcheck_port: {}\n\
+             admin_port: {}\n\
+             {}",
+            port, prometheus_port, healthcheck_port, admin_port, config
+        );
+        let configuration: RisuConfiguration = serde_yaml::from_str(&config).unwrap();
+        let server = TestServer::start(async move {
+            match clock {
+                Some(clock) => RisuServer::start_with_clock(configuration, clock).await.unwrap(),
+                None => RisuServer::start(configuration).await.unwrap(),
+            }
+        });
+        for port in [port, prometheus_port, healthcheck_port, admin_port] {
+            wait_for_listener(port).await;
+        }
+        Self {
+            server,
+            port,
+            prometheus_port,
+            admin_port,
+            client: Client::builder(TokioExecutor::new()).build_http(),
+        }
+    }
+
+    /// Builds a GET request for the path, to be forwarded to the target.
+    pub fn get(&self, path: &str, target: &str) -> hyper::http::request::Builder
+    {
+        hyper::Request::builder()
+            .uri(format!("http://127.0.0.1:{}{}", self.port, path))
+            .header("x-target-host", target)
+    }
+
+    /// Builds a request for the path of the admin endpoint.
+    pub fn admin(&self, method: &str, path: &str) -> hyper::http::request::Builder
+    {
+        hyper::Request::builder()
+            .method(method)
+            .uri(format!("http://127.0.0.1:{}{}", self.admin_port, path))
+    }
+
+    pub async fn send(&self, request: hyper::http::request::Builder) -> Reply
+    {
+        let response = self.client.request(request.body(Empty::new()).unwrap()).await.unwrap();
+        let status = response.status();
+        let headers = response.headers().clone();
+        let body = response.into_body().collect().await.unwrap().to_bytes().to_vec();
+        Reply {
+            status,
+            headers,
+            body: String::from_utf8(body).unwrap(),
+        }
+    }
+
+    /// Returns the metrics exposed to prometheus.
+    pub async fn metrics(&self) -> String
+    {
+        let request = hyper::Request::builder().uri(format!("http://127.0.0.1:{}/", self.prometheus_port));
+        self.send(request).await.body
+    }
+
+    pub async fn shutdown(self)
+    {
+        self.server.shutdown().await;
+    }
+}
+
+// Address the backends bind to, on a port the system picks
+const LOCALHOST: ([u8; 4], u16) = ([127, 0, 0, 1], 0);
+
+// Binds a listener to a port the system picks, so that tests running at the same time don't clash
+fn bind_free() -> std::net::TcpListener
+{
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.set_nonblocking(true).unwrap();
+    listener
+}
+
+// Port that was free a moment ago, for servers that bind their own listeners
+fn free_port() -> u16
+{
+    bind_free().local_addr().unwrap().port()
+}
+
+async fn wait_for_listener(port: u16)
+{
+    for _ in 0..500 {
+        if tokio::net::TcpStream::connect(("127.0.0.1", port)).await.is_ok() {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    panic!("Nothing is listening on port {}", port);
+}
+
+// Probes until the expected value comes up, for what risu does in the background
+async fn eventually<T, F>(expected: T, mut probe: impl FnMut() -> F)
+where
+    T: PartialEq + std::fmt::Debug,
+    F: Future<Output = T>,
+{
+    let mut value = probe().await;
+    for _ in 0..500 {
+        if value == expected {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        value = probe().await;
+    }
+    assert_eq!(value, expected);
+}
+
 use simplelog::*;
 
 #[tokio::test]
@@ -86,16 +297,18 @@ async fn grpc()
     )])
     .unwrap();
 
-    let server = TestServer::new_grpc();
-    let risu = TestServer::new_risu();
-
-    // Warmup
-    tokio::time::sleep(Duration::from_secs(1)).await;
+    let (server, server_port) = TestServer::new_grpc();
+    let target = format!("127.0.0.1:{}", server_port);
+    let risu = start_risu(
+        "target_allowlist:\n  \
+           hosts: [127.0.0.1]",
+    )
+    .await;
 
-    let mut client = GreeterClient::connect("http://127.0.0.1:3001").await.unwrap();
+    let mut client = GreeterClient::connect(format!("http://127.0.0.1:{}", risu.port)).await.unwrap();
 
     let mut metadata1 = MetadataMap::new();
-    metadata1.insert("x-target-host", "127.0.0.1:3002".parse().unwrap());
+    metadata1.insert("x-target-host", target.parse().unwrap());
 
     let request1 = tonic::Request::from_parts(
         metadata1,
@@ -103,7 +316,7 @@ async fn grpc()
         HelloRequest { name: "Tonic".into() });
 
     let mut metadata2 = MetadataMap::new();
-    metadata2.insert("x-target-host", "127.0.0.1:3002".parse().unwrap());
+    metadata2.insert("x-target-host", target.parse().unwrap());
 
     let request2 = tonic::Request::from_parts(
         metadata2,
@@ -111,7 +324,7 @@ async fn grpc()
         HelloRequest { name: "Mom".into() });
 
     let mut metadata3 = MetadataMap::new();
-    metadata3.insert("x-target-host", "127.0.0.1:3002".parse().unwrap());
+    metadata3.insert("x-target-host", target.parse().unwrap());
 
     let request3 = tonic::Request::from_parts(
         metadata3,
@@ -141,6 +354,566 @@ async fn grpc()
     // assert!(response3.get_ref().message == "Hello Dad!");
 }
 
+#[tokio::test]
+async fn https_upstream()
+{
+    // Self-signed backend, trusted by risu through its CA file
+    let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+    let ca_file = std::env::temp_dir().join("risu-tests-upstream-ca.pem");
+    std::fs::write(&ca_file, certified.cert.pem()).unwrap();
+
+    let (server, server_port) = TestServer::new_https(certified);
+    let risu = start_risu(&format!(
+        "target_allowlist:\n  \
+           hosts: [localhost]\n\
+         upstream_protocol: http1\n\
+         upstream_tls:\n  \
+           ca_file: {}",
+        ca_file.display()
+    ))
+    .await;
+
+    // Scheme as a prefix of the target
+    let reply = risu.send(risu.get("/hello", &format!("https://localhost:{}", server_port))).await;
+    assert_eq!(reply.status, 200);
+    assert_eq!(reply.body, "Hello over TLS!");
+
+    // Scheme in its own header
+    let target = format!("localhost:{}", server_port);
+    let reply = risu.send(risu.get("/world", &target).header("x-target-scheme", "https")).await;
+    assert_eq!(reply.status, 200);
+
+    // Plain http to a TLS backend fails, but risu still responds
+    let reply = risu.send(risu.get("/plain", &target)).await;
+    assert_eq!(reply.status, 502);
+
+    server.shutdown().await;
+    risu.shutdown().await;
+}
+
+#[tokio::test]
+async fn https_listener()
+{
+    // Self-signed risu, trusted by the client
+    let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+    let certificate_file = std::env::temp_dir().join("risu-tests-listener-cert.pem");
+    let key_file = std::env::temp_dir().join("risu-tests-listener-key.pem");
+    std::fs::write(&certificate_file, certified.cert.pem()).unwrap();
+    std::fs::write(&key_file, certified.key_pair.serialize_pem()).unwrap();
+
+    let hello = warp::any().map(|| "Hello, World!");
+    let (server, target) = TestServer::new_warp(warp::serve(hello).bind_ephemeral(LOCALHOST));
+    let risu = start_risu(&format!(
+        "target_allowlist:\n  \
+           networks: [127.0.0.0/8]\n\
+         tls:\n  \
+           certificate_file: {}\n  \
+           key_file: {}",
+        certificate_file.display(),
+        key_file.display()
+    ))
+    .await;
+
+    let mut roots = rustls::RootCertStore::empty();
+    roots.add(certified.cert.der().clone()).unwrap();
+    let tls = rustls::ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+
+    // Both protocols are served on the same port
+    for http2 in [false, true] {
+        let connector = hyper_rustls::HttpsConnectorBuilder::new().with_tls_config(tls.clone()).https_only();
+        let connector = if http2 { connector.enable_http2().build() } else { connector.enable_http1().build() };
+        let client = Client::builder(TokioExecutor::new()).http2_only(http2).build(connector);
+
+        let request = hyper::Request::builder()
+            .uri(format!("https://localhost:{}/hello", risu.port))
+            .header("x-target-host", &target)
+            .body(Empty::<Bytes>::new())
+            .unwrap();
+        let response = client.request(request).await.unwrap();
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.version(), if http2 { hyper::Version::HTTP_2 } else { hyper::Version::HTTP_11 });
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(&body[..], b"Hello, World!");
+    }
+
+    server.shutdown().await;
+    risu.shutdown().await;
+}
+
+#[tokio::test]
+async fn mixed_protocols()
+{
+    let hello = warp::any().map(|| "Hello, World!");
+    let (server, target) = TestServer::new_warp(warp::serve(hello).bind_ephemeral(LOCALHOST));
+    let risu = start_risu(
+        "target_allowlist:\n  \
+           hosts: [127.0.0.1]",
+    )
+    .await;
+
+    // HTTP/1.1 and HTTP/2 with prior knowledge on the same port, with the upstream protocol either matching the
+    // inbound one or forced per request
+    for (http2, target_protocol) in [(false, None), (true, None), (false, Some("http2")), (true, Some("http1"))] {
+        let client = Client::builder(TokioExecutor::new()).http2_only(http2).build_http();
+        let mut request = risu.get("/hello", &target);
+        if let Some(target_protocol) = target_protocol {
+            request = request.header("x-target-protocol", target_protocol);
+        }
+        let response = client.request(request.body(Empty::<Bytes>::new()).unwrap()).await.unwrap();
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.version(), if http2 { hyper::Version::HTTP_2 } else { hyper::Version::HTTP_11 });
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(&body[..], b"Hello, World!");
+    }
+
+    // Same backend, but the name isn't in the allowlist
+    let named = target.replace("127.0.0.1", "localhost");
+    assert_eq!(risu.send(risu.get("/hello", &named)).await.status, 403);
+
+    server.shutdown().await;
+    risu.shutdown().await;
+}
+
+#[tokio::test]
+async fn vary()
+{
+    // Backend counts its calls, so that we can tell cached responses apart
+    let calls = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+    let routes = warp::path!("any").map({
+        let calls = calls.clone();
+        move || {
+            let call = calls.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            warp::reply::with_header(format!("any-{}", call), "vary", "*")
+        }
+    })
+    .or(warp::header::optional::<String>("accept-language").map({
+        let calls = calls.clone();
+        move |language: Option<String>| {
+            let call = calls.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            let body = format!("{}-{}", language.unwrap_or_default(), call);
+            warp::reply::with_header(body, "vary", "Accept-Language")
+        }
+    }));
+    let (server, target) = TestServer::new_warp(warp::serve(routes).bind_ephemeral(LOCALHOST));
+    let risu = start_risu(
+        "target_allowlist:\n  \
+           hosts: [127.0.0.1]",
+    )
+    .await;
+
+    let get = |path: &'static str, language: &'static str| {
+        let (risu, target) = (&risu, &target);
+        async move {
+            let reply = risu.send(risu.get(path, target).header("accept-language", language)).await;
+            assert_eq!(reply.status, 200);
+            reply.body
+        }
+    };
+
+    // Each language gets its own variant, which ends up being cached
+    let mut last = vec![];
+    for _ in 0..6 {
+        last = vec![];
+        for language in ["fr", "en"] {
+            let body = get("/hello", language).await;
+            assert!(body.starts_with(&format!("{}-", language)), "{} got {}", language, body);
+            last.push(body);
+        }
+    }
+    assert_eq!(get("/hello", "fr").await, last[0]);
+    assert_eq!(get("/hello", "en").await, last[1]);
+
+    // Vary: * is never cached
+    let mut bodies = vec![];
+    for _ in 0..4 {
+        bodies.push(get("/any", "fr").await);
+    }
+    bodies.dedup();
+    assert_eq!(bodies.len(), 4);
+
+    server.shutdown().await;
+    risu.shutdown().await;
+}
+
+#[tokio::test]
+async fn stale_while_revalidate()
+{
+    let calls = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+    let routes = warp::any().map({
+        let calls = calls.clone();
+        move || {
+            let call = calls.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            warp::reply::with_header(call.to_string(), "cache-control", "max-age=1, stale-while-revalidate=60")
+        }
+    });
+    let (server, target) = TestServer::new_warp(warp::serve(routes).bind_ephemeral(LOCALHOST));
+    let clock = Arc::new(ManualClock::new());
+    let risu = start_risu_with_clock(
+        "target_allowlist:\n  \
+           hosts: [127.0.0.1]",
+        clock.clone(),
+    )
+    .await;
+
+    let get = || async {
+        let reply = risu.send(risu.get("/hello", &target)).await;
+        assert_eq!(reply.status, 200);
+        reply.body
+    };
+
+    // The second response enters the cache
+    assert_eq!(get().await, "0");
+    assert_eq!(get().await, "1");
+    assert_eq!(get().await, "1");
+
+    // Once expired, the stale response is served while it's refreshed
+    clock.advance(Duration::from_millis(1500));
+    assert_eq!(get().await, "1");
+    eventually("2".to_string(), get).await;
+    assert_eq!(calls.load(std::sync::atomic::Ordering::SeqCst), 3);
+
+    server.shutdown().await;
+    risu.shutdown().await;
+}
+
+#[tokio::test]
+async fn stale_if_error()
+{
+    let calls = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+    let down = Arc::new(std::sync::atomic::AtomicBool::new(false));
+    let routes = warp::any().map({
+        let calls = calls.clone();
+        let down = down.clone();
+        move || {
+            let call = calls.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            let status = match down.load(std::sync::atomic::Ordering::SeqCst) {
+                true => warp::http::StatusCode::SERVICE_UNAVAILABLE,
+                false => warp::http::StatusCode::OK,
+            };
+            let reply = warp::reply::with_header(call.to_string(), "cache-control", "max-age=1, stale-if-error=60");
+            warp::reply::with_status(reply, status)
+        }
+    });
+    let (server, target) = TestServer::new_warp(warp::serve(routes).bind_ephemeral(LOCALHOST));
+    let clock = Arc::new(ManualClock::new());
+    let risu = start_risu_with_clock(
+        "target_allowlist:\n  \
+           hosts: [127.0.0.1]",
+        clock.clone(),
+    )
+    .await;
+
+    let get = |path: &'static str| {
+        let (risu, target) = (&risu, &target);
+        async move {
+            let reply = risu.send(risu.get(path, target)).await;
+            let stale = reply.headers.get("x-risu-stale").map(|v| v.to_str().unwrap().to_string());
+            (reply.status, reply.body, stale)
+        }
+    };
+
+    // The second response enters the cache
+    assert_eq!(get("/hello").await.1, "0");
+    assert_eq!(get("/hello").await.1, "1");
+
+    // Once expired, the stale response is served instead of upstream errors
+    clock.advance(Duration::from_millis(1500));
+    down.store(true, std::sync::atomic::Ordering::SeqCst);
+    assert_eq!(get("/hello").await, (hyper::StatusCode::OK, "1".to_string(), Some("if-error".to_string())));
+
+    // Without a stale response, the error goes through
+    assert_eq!(get("/other").await, (hyper::StatusCode::SERVICE_UNAVAILABLE, "3".to_string(), None));
+
+    // Upstream is back, and the entry is refreshed
+    down.store(false, std::sync::atomic::Ordering::SeqCst);
+    assert_eq!(get("/hello").await, (hyper::StatusCode::OK, "4".to_string(), None));
+    assert_eq!(get("/hello").await, (hyper::StatusCode::OK, "4".to_string(), None));
+
+    server.shutdown().await;
+    risu.shutdown().await;
+}
+
+#[tokio::test]
+async fn refresh_ahead()
+{
+    let calls = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+    let routes = warp::any().map({
+        let calls = calls.clone();
+        move || {
+            let call = calls.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            warp::reply::with_header(call.to_string(), "cache-control", "max-age=2")
+        }
+    });
+    let (server, target) = TestServer::new_warp(warp::serve(routes).bind_ephemeral(LOCALHOST));
+    let clock = Arc::new(ManualClock::new());
+    let risu = start_risu_with_clock(
+        "cache_refresh_ahead:\n  \
+           ttl_percent: 50\n  \
+           min_hits: 2\n\
+         target_allowlist:\n  \
+           hosts: [127.0.0.1]",
+        clock.clone(),
+    )
+    .await;
+
+    let get = || async {
+        let reply = risu.send(risu.get("/hello", &target)).await;
+        assert_eq!(reply.status, 200);
+        reply.body
+    };
+
+    // The second response enters the cache
+    assert_eq!(get().await, "0");
+    assert_eq!(get().await, "1");
+    assert_eq!(get().await, "1");
+
+    // Second hit in the last half of the time to live, the entry is refreshed before it expires
+    clock.advance(Duration::from_millis(1300));
+    assert_eq!(get().await, "1");
+    eventually("2".to_string(), get).await;
+    assert_eq!(calls.load(std::sync::atomic::Ordering::SeqCst), 3);
+
+    server.shutdown().await;
+    risu.shutdown().await;
+}
+
+#[tokio::test]
+async fn purge()
+{
+    let calls = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+    let routes = warp::any().map({
+        let calls = calls.clone();
+        move || calls.fetch_add(1, std::sync::atomic::Ordering::SeqCst).to_string()
+    });
+    let (server, target) = TestServer::new_warp(warp::serve(routes).bind_ephemeral(LOCALHOST));
+    let risu = start_risu(
+        "target_allowlist:\n  \
+           hosts: [127.0.0.1]",
+    )
+    .await;
+
+    let get = || async { risu.send(risu.get("/hello", &target)).await.body };
+    // Purged requests are keyed by their target too
+    let admin = |method: &'static str, path: String| {
+        let (risu, target) = (&risu, &target);
+        async move {
+            let reply = risu.send(risu.admin(method, &path).header("x-target-host", target)).await;
+            (reply.status.as_u16(), reply.body)
+        }
+    };
+
+    // The second response enters the cache
+    assert_eq!(get().await, "0");
+    assert_eq!(get().await, "1");
+    assert_eq!(get().await, "1");
+
+    // Purged by request, the next response is cached right away
+    let (status, key) = admin("PURGE", "/hello".to_string()).await;
+    assert_eq!(status, 200);
+    assert_eq!(admin("PURGE", "/hello".to_string()).await, (404, key.clone()));
+    assert_eq!(get().await, "2");
+    assert_eq!(get().await, "2");
+
+    // Deleted by key
+    assert_eq!(admin("DELETE", format!("/keys/{}", key)).await, (200, key.clone()));
+    assert_eq!(get().await, "3");
+    assert_eq!(admin("DELETE", "/keys/nothex".to_string()).await.0, 400);
+
+    // Flushed, the key goes through probation again
+    assert_eq!(admin("DELETE", "/keys".to_string()).await, (200, "Removed 1 entries".to_string()));
+    assert_eq!(get().await, "4");
+    assert_eq!(get().await, "5");
+    assert_eq!(get().await, "5");
+
+    server.shutdown().await;
+    risu.shutdown().await;
+}
+
+#[tokio::test]
+async fn tags()
+{
+    let calls = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+    let routes = warp::path!("users" / u32).map({
+        let calls = calls.clone();
+        move |user: u32| {
+            let call = calls.fetch_add(1, std::sync::atomic::Ordering::SeqCst).to_string();
+            warp::reply::with_header(call, "surrogate-key", format!("user-{} users", user))
+        }
+    });
+    let (server, target) = TestServer::new_warp(warp::serve(routes).bind_ephemeral(LOCALHOST));
+    let risu = start_risu(
+        "target_allowlist:\n  \
+           hosts: [127.0.0.1]",
+    )
+    .await;
+
+    let get = |user: u32| {
+        let (risu, target) = (&risu, &target);
+        async move { risu.send(risu.get(&format!("/users/{}", user), target)).await.body }
+    };
+    let purge = |tag: &'static str| {
+        let risu = &risu;
+        async move { risu.send(risu.admin("DELETE", &format!("/tags/{}", tag))).await.body }
+    };
+
+    // The second responses enter the cache
+    assert_eq!(get(1).await, "0");
+    assert_eq!(get(1).await, "1");
+    assert_eq!(get(2).await, "2");
+    assert_eq!(get(2).await, "3");
+    assert_eq!(get(1).await, "1");
+    assert_eq!(get(2).await, "3");
+
+    // Only the response of the first user carries its tag
+    assert_eq!(purge("user-1").await, "Removed 1 entries");
+    assert_eq!(purge("user-1").await, "Removed 0 entries");
+    assert_eq!(get(2).await, "3");
+    assert_eq!(get(1).await, "4");
+    assert_eq!(get(1).await, "4");
+
+    // Both carry the shared tag
+    assert_eq!(purge("users").await, "Removed 2 entries");
+    assert_eq!(get(1).await, "5");
+    assert_eq!(get(2).await, "6");
+
+    server.shutdown().await;
+    risu.shutdown().await;
+}
+
+#[tokio::test]
+async fn tiny_lfu()
+{
+    let calls = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+    let routes = warp::path!("items" / u32).map({
+        let calls = calls.clone();
+        move |_: u32| calls.fetch_add(1, std::sync::atomic::Ordering::SeqCst).to_string()
+    });
+    let (server, target) = TestServer::new_warp(warp::serve(routes).bind_ephemeral(LOCALHOST));
+    let risu = start_risu(
+        "in_memory_shards: 1\n\
+         cache_resident_size: 4\n\
+         cache_policy: tiny_lfu\n\
+         target_allowlist:\n  \
+           hosts: [127.0.0.1]",
+    )
+    .await;
+
+    let get = |item: u32| {
+        let (risu, target) = (&risu, &target);
+        async move { risu.send(risu.get(&format!("/items/{}", item), target)).await.body }
+    };
+
+    // The first response enters the cache right away
+    for _ in 0..5 {
+        assert_eq!(get(1).await, "0");
+    }
+
+    // A scan of items requested once doesn't evict the hot one
+    for item in 2..12 {
+        get(item).await;
+    }
+    assert_eq!(calls.load(std::sync::atomic::Ordering::SeqCst), 11);
+    assert_eq!(get(1).await, "0");
+
+    server.shutdown().await;
+    risu.shutdown().await;
+}
+
+#[tokio::test]
+async fn snapshot()
+{
+    let calls = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+    let routes = warp::any().map({
+        let calls = calls.clone();
+        move || calls.fetch_add(1, std::sync::atomic::Ordering::SeqCst).to_string()
+    });
+    let (server, target) = TestServer::new_warp(warp::serve(routes).bind_ephemeral(LOCALHOST));
+    let path = std::env::temp_dir().join(format!("risu-{}.snapshot", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let config = format!(
+        "cache_snapshot:\n  \
+           path: {}\n  \
+           interval_seconds: 1\n\
+         target_allowlist:\n  \
+           hosts: [127.0.0.1]",
+        path.display()
+    );
+
+    // The second response enters the cache, then gets saved along with its age
+    let clock = Arc::new(ManualClock::new());
+    let risu = start_risu_with_clock(&config, clock.clone()).await;
+    assert_eq!(risu.send(risu.get("/hello", &target)).await.body, "0");
+    assert_eq!(risu.send(risu.get("/hello", &target)).await.body, "1");
+    clock.advance(Duration::from_secs(5));
+    eventually(true, || async { path.exists() }).await;
+    risu.shutdown().await;
+
+    // Served from the snapshot after a restart, as old as it was
+    let risu = start_risu(&config).await;
+    let reply = risu.send(risu.get("/hello", &target)).await;
+    assert_eq!(reply.body, "1");
+    assert_eq!(reply.headers["age"], "5");
+    assert_eq!(calls.load(std::sync::atomic::Ordering::SeqCst), 2);
+
+    server.shutdown().await;
+    risu.shutdown().await;
+    let _ = std::fs::remove_file(&path);
+}
+
+#[tokio::test]
+async fn disk()
+{
+    let calls = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+    let routes = warp::any().map({
+        let calls = calls.clone();
+        move || calls.fetch_add(1, std::sync::atomic::Ordering::SeqCst).to_string()
+    });
+    let (server, target) = TestServer::new_warp(warp::serve(routes).bind_ephemeral(LOCALHOST));
+    let directory = std::env::temp_dir().join(format!("risu-disk-{}", std::process::id()));
+    // A single entry fits in memory
+    let risu = start_risu(&format!(
+        "in_memory_shards: 1\n\
+         cache_resident_size: 1\n\
+         cache_disk:\n  \
+           directory: {}\n  \
+           min_hits: 0\n  \
+           min_ttl_seconds: 0\n\
+         target_allowlist:\n  \
+           hosts: [127.0.0.1]",
+        directory.display()
+    ))
+    .await;
+
+    let get = |path: &'static str| async { risu.send(risu.get(path, &target)).await.body };
+    // Evicted entries are written to disk in the background
+    let written = |count: usize| {
+        let risu = &risu;
+        let written = format!("cache_disk_writes{{outcome=\"written\"}} {}", count);
+        async move { eventually(true, || async { risu.metrics().await.contains(&written) }).await }
+    };
+
+    // The second responses enter the cache, the first one being evicted to disk by the other one
+    assert_eq!(get("/a").await, "0");
+    assert_eq!(get("/a").await, "1");
+    assert_eq!(get("/b").await, "2");
+    assert_eq!(get("/b").await, "3");
+    written(1).await;
+
+    // Both are served from disk in turn, each promoted back to memory evicting the other one
+    assert_eq!(get("/a").await, "1");
+    written(2).await;
+    assert_eq!(get("/b").await, "3");
+    written(3).await;
+    assert_eq!(calls.load(std::sync::atomic::Ordering::SeqCst), 4);
+    assert!(risu.metrics().await.contains("cache_tier_lookups{outcome=\"hit\",tier=\"l2\"} 2"));
+
+    server.shutdown().await;
+    risu.shutdown().await;
+    let _ = std::fs::remove_dir_all(&directory);
+}
+
 // #[tokio::test]
 // async fn https_external()
 // {